@@ -0,0 +1,9 @@
+#!/bin/sh
+# Replace the bundled files with the latest community CHIP-8 database, rebuild afterwards.
+set -e
+
+cd "$(dirname "$0")"
+BASE=https://raw.githubusercontent.com/chip-8/chip-8-database/master/database
+
+curl -fsSL "$BASE/programs.json" -o programs.json
+curl -fsSL "$BASE/sha1-hashes.json" -o sha1-hashes.json
@@ -0,0 +1,371 @@
+// Runs a rom without a browser, driven by commands on stdin, one per line. Handy for trying
+// roms from a terminal or a script:
+//
+//   cargo run --bin chip8 -- path/to/rom.ch8
+//
+// Commands:
+//   run [frames]          run 60 Hz frames, 1 if not given
+//   step                  run a single instruction
+//   key <0-F> down|up     press or let go of a key of the hex keypad
+//   show                  print the screen as of the last frame, # is a lit pixel
+//   reset                 start the rom over, like `Chip8::reset`
+//   power-cycle [random]  turn it off and on, with "random" the RAM gets random garbage
+//   record start [scale] [frame skip] [seconds]
+//                         record the screen to an animated GIF, see `GifRecorderOptions`
+//   record stop <file>    write what was recorded to a GIF file
+//   search start          start a memory search for finding cheats, see `Chip8CheatFinder`
+//   search <filter> [hex value]
+//                         keep the addresses that are "equal" to the value, or "changed",
+//                         "unchanged", "increased" or "decreased" since the last search
+//   search results [count]
+//                         the addresses left with their values, 20 if no count is given
+//   help
+//   quit
+use std::cell::RefCell;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+
+use chip8_rust_wasm::chip8::cheat_finder::{Chip8CheatFinder, SearchFilter};
+use chip8_rust_wasm::chip8::config::{Rgb, ScalingMode};
+use chip8_rust_wasm::chip8::display::Chip8Display;
+use chip8_rust_wasm::chip8::recorder::GifRecorderOptions;
+use chip8_rust_wasm::chip8::Chip8;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+const HELP: &str = "run [frames], step, key <0-F> down|up, show, reset, power-cycle [random], \
+                    record start [scale] [frame skip] [seconds], record stop <file>, search start, \
+                    search <filter> [hex value], search results [count], help, quit";
+
+// Keeps the last frame for `show`, a palette index for every pixel
+struct TextDisplay {
+    screen: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Chip8Display for TextDisplay {
+    fn set_palette(&mut self, _palette: &[Rgb]) {}
+
+    fn set_scaling(&mut self, _scaling: ScalingMode) {}
+
+    // (palette index, intensity) pairs, bottom row first
+    fn draw(&mut self, texels: &[u8]) {
+        *self.screen.borrow_mut() = texels
+            .chunks(WIDTH * 2)
+            .rev()
+            .flat_map(|row| row.chunks(2).map(|texel| texel[0] & 0x0F))
+            .collect();
+    }
+
+    fn needs_redraw(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "text"
+    }
+}
+
+struct Runner {
+    machine: Chip8,
+    screen: Rc<RefCell<Vec<u8>>>,
+    // The memory search, if one was started
+    finder: Option<Chip8CheatFinder>,
+}
+
+impl Runner {
+    fn new(rom: &[u8]) -> Result<Runner, String> {
+        let screen = Rc::new(RefCell::new(vec![0; WIDTH * HEIGHT]));
+        let mut machine = Chip8::new(Box::new(TextDisplay {
+            screen: screen.clone(),
+        }));
+
+        for warning in machine.load_rom_bytes(rom).map_err(|err| err.to_string())? {
+            eprintln!("warning: {}", warning);
+        }
+
+        Ok(Runner {
+            machine,
+            screen,
+            finder: None,
+        })
+    }
+
+    // What the command printed, or what was wrong with it
+    fn run_command(&mut self, command: &str, args: &[&str]) -> Result<String, String> {
+        match (command, args) {
+            ("run", []) => self.run(1),
+            ("run", [frames]) => self.run(number(frames, "number of frames")?),
+            ("step", []) => {
+                self.machine.step();
+                Ok(String::new())
+            }
+            ("key", [key, state]) => {
+                let key = match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => key,
+                    _ => return Err(format!("{} is not a key, the keys are 0 to F", key)),
+                };
+                let pressed = match *state {
+                    "down" => true,
+                    "up" => false,
+                    _ => return Err(format!("{} is not down or up", state)),
+                };
+
+                self.machine.input().borrow_mut().set_key(key, pressed);
+                Ok(String::new())
+            }
+            ("show", []) => Ok(self.show()),
+            ("reset", []) => {
+                self.machine.reset().map_err(|err| err.to_string())?;
+                Ok(String::from("Reset"))
+            }
+            ("power-cycle", []) => {
+                self.machine
+                    .power_cycle(false)
+                    .map_err(|err| err.to_string())?;
+                Ok(String::from("Power cycled"))
+            }
+            ("power-cycle", ["random"]) => {
+                self.machine
+                    .power_cycle(true)
+                    .map_err(|err| err.to_string())?;
+                Ok(String::from("Power cycled with random RAM"))
+            }
+            ("record", ["start", options @ ..]) if options.len() <= 3 => {
+                let defaults = GifRecorderOptions::default();
+                let option = |index: usize| options.get(index).copied();
+                let options = GifRecorderOptions {
+                    scale: option(0).map_or(Ok(defaults.scale), |scale| number(scale, "scale"))?,
+                    frame_skip: option(1).map_or(Ok(defaults.frame_skip), |skip| {
+                        number(skip, "number of frames to skip")
+                    })?,
+                    max_seconds: option(2).map_or(Ok(defaults.max_seconds), |seconds| {
+                        number(seconds, "number of seconds")
+                    })?,
+                };
+
+                self.machine.start_recording(options)?;
+                Ok(String::from("Recording"))
+            }
+            ("record", ["stop", path]) => {
+                let gif = self.machine.stop_recording()?;
+                std::fs::write(path, &gif)
+                    .map_err(|err| format!("Could not write {}: {}", path, err))?;
+                Ok(format!("Wrote {} bytes to {}", gif.len(), path))
+            }
+            ("search", ["start"]) => {
+                let finder = Chip8CheatFinder::new(
+                    &self.machine.memory().borrow(),
+                    self.machine.config().memory_size(),
+                );
+                let count = finder.candidates().len();
+                self.finder = Some(finder);
+                Ok(format!("{} addresses", count))
+            }
+            ("search", ["results"]) => self.search_results(20),
+            ("search", ["results", count]) => self.search_results(number(count, "addresses")?),
+            ("search", [filter, value @ ..]) if value.len() <= 1 => {
+                let value = match value.first() {
+                    Some(value) => match u8::from_str_radix(value.trim_start_matches("0x"), 16) {
+                        Ok(value) => Some(value),
+                        Err(_) => return Err(format!("{} is not a byte in hex", value)),
+                    },
+                    None => None,
+                };
+                let filter = match (SearchFilter::from_name(filter, value), *filter) {
+                    (Some(filter), _) => filter,
+                    (None, "equal") => return Err(String::from("equal needs a hex value")),
+                    (None, _) => return Err(format!("Don't know the search {}", filter)),
+                };
+                let finder = self
+                    .finder
+                    .as_mut()
+                    .ok_or_else(|| String::from("Start a search first"))?;
+
+                let count = finder.narrow(&self.machine.memory().borrow(), filter);
+                Ok(format!("{} addresses left", count))
+            }
+            ("help", []) => Ok(String::from(HELP)),
+            _ => Err(format!(
+                "Don't know {}, try help",
+                [&[command], args].concat().join(" ")
+            )),
+        }
+    }
+
+    fn run(&mut self, frames: u32) -> Result<String, String> {
+        for _ in 0..frames {
+            self.machine.update();
+        }
+
+        Ok(String::new())
+    }
+
+    fn search_results(&self, count: usize) -> Result<String, String> {
+        let finder = self
+            .finder
+            .as_ref()
+            .ok_or_else(|| String::from("Start a search first"))?;
+
+        Ok(finder
+            .results(&self.machine.memory().borrow(), count)
+            .iter()
+            .map(|result| format!("{:04X}: {:02X}", result.address, result.value))
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    fn show(&self) -> String {
+        self.screen
+            .borrow()
+            .chunks(WIDTH)
+            .map(|row| {
+                row.iter()
+                    .map(|pixel| if *pixel == 0 { '.' } else { '#' })
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+fn number<T: std::str::FromStr>(text: &str, what: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("{} is not a valid {}", text, what))
+}
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: chip8 <rom>, then commands on stdin: {}", HELP);
+            std::process::exit(2);
+        }
+    };
+
+    let mut runner = match std::fs::read(&path)
+        .map_err(|err| format!("Could not read {}: {}", path, err))
+        .and_then(|rom| Runner::new(&rom))
+    {
+        Ok(runner) => runner,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.split_first() {
+            None => continue,
+            Some((&"quit", _)) => break,
+            Some((command, args)) => match runner.run_command(command, args) {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("error: {}", err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waits for key 5, then draws a 0 at (5, 5)
+    const WAIT_AND_DRAW: [u8; 14] = [
+        0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0xF0, 0x18, 0xA0, 0x50, 0xD0, 0x05, 0x12, 0x0C,
+    ];
+
+    fn command(runner: &mut Runner, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        runner.run_command(words[0], &words[1..])
+    }
+
+    fn lit(runner: &mut Runner) -> bool {
+        command(runner, "show").unwrap().contains('#')
+    }
+
+    #[test]
+    fn test_keys_reset_and_power_cycle() {
+        let mut runner = Runner::new(&WAIT_AND_DRAW).unwrap();
+
+        command(&mut runner, "run 2").unwrap();
+        assert!(!lit(&mut runner));
+
+        command(&mut runner, "key 5 down").unwrap();
+        command(&mut runner, "run").unwrap();
+        let screen = command(&mut runner, "show").unwrap();
+        assert_eq!(screen.lines().count(), HEIGHT);
+        assert_eq!(&screen.lines().nth(5).unwrap()[..10], ".....####.");
+
+        // Starts over and waits for the key again
+        command(&mut runner, "key 5 up").unwrap();
+        assert_eq!(command(&mut runner, "reset"), Ok(String::from("Reset")));
+        command(&mut runner, "run").unwrap();
+        assert!(!lit(&mut runner));
+
+        command(&mut runner, "power-cycle random").unwrap();
+        command(&mut runner, "key 5 down").unwrap();
+        command(&mut runner, "run").unwrap();
+        assert!(lit(&mut runner));
+    }
+
+    #[test]
+    fn test_bad_commands() {
+        let mut runner = Runner::new(&WAIT_AND_DRAW).unwrap();
+
+        assert!(command(&mut runner, "run lots").is_err());
+        assert!(command(&mut runner, "key G down").is_err());
+        assert!(command(&mut runner, "key 5 sideways").is_err());
+        assert!(command(&mut runner, "power-cycle twice").is_err());
+        assert!(command(&mut runner, "fly").is_err());
+        assert!(command(&mut runner, "record start big").is_err());
+        assert!(command(&mut runner, "record stop out.gif").is_err());
+        assert!(command(&mut runner, "search changed").is_err());
+        command(&mut runner, "search start").unwrap();
+        assert!(command(&mut runner, "search equal").is_err());
+        assert!(command(&mut runner, "search equal zz").is_err());
+        assert!(command(&mut runner, "search sideways").is_err());
+        assert!(Runner::new(&[]).is_err());
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut runner = Runner::new(&WAIT_AND_DRAW).unwrap();
+        let path = std::env::temp_dir().join("chip8_runner_test.gif");
+        let path = path.to_str().unwrap();
+
+        command(&mut runner, "record start 2 0 1").unwrap();
+        command(&mut runner, "key 5 down").unwrap();
+        command(&mut runner, "run 10").unwrap();
+        let output = command(&mut runner, &format!("record stop {}", path)).unwrap();
+
+        let gif = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+        assert_eq!(output, format!("Wrote {} bytes to {}", gif.len(), path));
+    }
+
+    #[test]
+    fn test_memory_search() {
+        // Counts V0 down and stores it in 0x300, over and over
+        let countdown = [0x60, 0x90, 0xA3, 0x00, 0xF0, 0x55, 0x70, 0xFF, 0x12, 0x04];
+        let mut runner = Runner::new(&countdown).unwrap();
+        let mut command = |line: &str| command(&mut runner, line).unwrap();
+
+        command("run");
+        assert_eq!(command("search start"), "4096 addresses");
+        command("run");
+        command("search decreased");
+        command("run");
+        assert_eq!(command("search decreased"), "1 addresses left");
+        assert!(command("search results").starts_with("0300: "));
+        assert_eq!(command("search unchanged"), "1 addresses left");
+    }
+}
@@ -0,0 +1,281 @@
+// Achievements: goals for a game that unlock when all their conditions hold in the same frame,
+// see `conditions` for what a condition can check. Definitions are JSON, one file per rom:
+//
+//   { "achievements": [ {
+//       "id": "level-5",
+//       "title": "Halfway there",
+//       "description": "Reach level 5",
+//       "conditions": [ { "left": "m:2A0", "cmp": ">=", "right": "5" } ]
+//   } ] }
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use super::conditions::{self, Condition, ConditionDefinition, MachineSnapshot, PreviousFrame};
+
+#[derive(Debug, Deserialize)]
+struct AchievementsFile {
+    achievements: Vec<AchievementDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AchievementDefinition {
+    id: String,
+    title: String,
+    #[serde(default)]
+    description: String,
+    conditions: Vec<ConditionDefinition>,
+}
+
+// What the page gets to show
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AchievementInfo {
+    id: String,
+    title: String,
+    description: String,
+    unlocked: bool,
+}
+
+#[wasm_bindgen]
+impl AchievementInfo {
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn description(&self) -> String {
+        self.description.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn unlocked(&self) -> bool {
+        self.unlocked
+    }
+}
+
+impl AchievementInfo {
+    pub fn id_str(&self) -> &str {
+        &self.id
+    }
+
+    pub fn set_unlocked(&mut self, unlocked: bool) {
+        self.unlocked = unlocked;
+    }
+}
+
+struct Achievement {
+    info: AchievementInfo,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Default)]
+pub struct Chip8Achievements {
+    achievements: Vec<Achievement>,
+    // The machine at the end of the last frame, for "prev" operands
+    previous: PreviousFrame,
+}
+
+impl Chip8Achievements {
+    pub fn new() -> Chip8Achievements {
+        Chip8Achievements {
+            achievements: Vec::new(),
+            previous: PreviousFrame::default(),
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Chip8Achievements, String> {
+        let file: AchievementsFile =
+            serde_json::from_str(json).map_err(|err| format!("Invalid achievements: {}", err))?;
+
+        let mut achievements: Vec<Achievement> = Vec::new();
+        for definition in file.achievements {
+            if achievements
+                .iter()
+                .any(|achievement| achievement.info.id == definition.id)
+            {
+                return Err(format!("There already is an achievement {}", definition.id));
+            }
+
+            if definition.conditions.is_empty() {
+                return Err(format!("Achievement {} has no conditions", definition.id));
+            }
+
+            let conditions = definition
+                .conditions
+                .iter()
+                .map(Condition::from_definition)
+                .collect::<Result<Vec<Condition>, String>>()
+                .map_err(|err| format!("Achievement {}: {}", definition.id, err))?;
+
+            achievements.push(Achievement {
+                info: AchievementInfo {
+                    id: definition.id,
+                    title: definition.title,
+                    description: definition.description,
+                    unlocked: false,
+                },
+                conditions,
+            });
+        }
+
+        let previous = PreviousFrame::new(
+            achievements
+                .iter()
+                .flat_map(|achievement| &achievement.conditions),
+        );
+
+        Ok(Chip8Achievements {
+            achievements,
+            previous,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.achievements.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<AchievementInfo> {
+        self.achievements
+            .iter()
+            .map(|achievement| achievement.info.clone())
+            .collect()
+    }
+
+    // For achievements unlocked in an earlier session, false if there is no such achievement
+    pub fn unlock(&mut self, id: &str) -> bool {
+        match self
+            .achievements
+            .iter_mut()
+            .find(|achievement| achievement.info.id == id)
+        {
+            Some(achievement) => {
+                achievement.info.unlocked = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Call at the end of every frame, returns the achievements unlocked in it
+    pub fn evaluate(&mut self, now: &MachineSnapshot) -> Vec<AchievementInfo> {
+        if !self.previous.is_recorded() {
+            self.previous.record(now);
+        }
+        let mut unlocked = Vec::new();
+
+        for achievement in &mut self.achievements {
+            if achievement.info.unlocked {
+                continue;
+            }
+
+            if conditions::update_all(&mut achievement.conditions, now, &self.previous) {
+                achievement.info.unlocked = true;
+                unlocked.push(achievement.info.clone());
+            }
+        }
+
+        self.previous.record(now);
+
+        unlocked
+    }
+
+    // The game starts over, hit counts start from 0. Unlocked achievements stay unlocked.
+    pub fn restart(&mut self) {
+        self.previous.forget();
+
+        for achievement in &mut self.achievements {
+            for condition in &mut achievement.conditions {
+                condition.reset();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::conditions::tests::TestMachine;
+
+    const ACHIEVEMENTS: &str = r#"{ "achievements": [
+        {
+            "id": "level-5",
+            "title": "Halfway there",
+            "description": "Reach level 5",
+            "conditions": [ { "left": "m:2A0", "cmp": ">=", "right": "5" } ]
+        },
+        {
+            "id": "no-miss",
+            "title": "Steady",
+            "conditions": [
+                { "left": "m:2A1", "cmp": "==", "right": "prev m:2A1", "for_frames": 3 },
+                { "left": "v:0", "cmp": "==", "right": "1", "hits": 2 }
+            ]
+        }
+    ] }"#;
+
+    fn frame(level: u8, lives: u8, v0: u8) -> TestMachine {
+        let mut machine = TestMachine::new();
+        machine.memory[0x2A0] = level;
+        machine.memory[0x2A1] = lives;
+        machine.registers[0] = v0;
+        machine
+    }
+
+    #[test]
+    fn test_unlocks_once() {
+        let mut achievements = Chip8Achievements::parse(ACHIEVEMENTS).unwrap();
+        assert_eq!(achievements.list().len(), 2);
+
+        assert!(achievements.evaluate(&frame(4, 3, 0).snapshot()).is_empty());
+        let unlocked = achievements.evaluate(&frame(5, 3, 0).snapshot());
+        assert_eq!(unlocked.len(), 1);
+        assert_eq!(unlocked[0].id(), "level-5");
+        assert!(unlocked[0].unlocked());
+
+        // Already unlocked
+        assert!(achievements.evaluate(&frame(6, 3, 0).snapshot()).is_empty());
+        assert!(achievements.list()[0].unlocked());
+    }
+
+    #[test]
+    fn test_all_conditions_in_the_same_frame() {
+        let mut achievements = Chip8Achievements::parse(ACHIEVEMENTS).unwrap();
+        achievements.unlock("level-5");
+
+        // Lives stay the same from the first frame on, V0 is 1 on two frames
+        assert!(achievements.evaluate(&frame(0, 3, 1).snapshot()).is_empty());
+        assert!(achievements.evaluate(&frame(0, 3, 1).snapshot()).is_empty());
+        let unlocked = achievements.evaluate(&frame(0, 3, 0).snapshot());
+        assert_eq!(unlocked.len(), 1);
+        assert_eq!(unlocked[0].id(), "no-miss");
+
+        // After a restart the hits count again
+        let mut achievements = Chip8Achievements::parse(ACHIEVEMENTS).unwrap();
+        achievements.evaluate(&frame(0, 3, 1).snapshot());
+        achievements.evaluate(&frame(0, 3, 1).snapshot());
+        achievements.restart();
+        assert!(achievements.evaluate(&frame(0, 3, 0).snapshot()).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        assert!(Chip8Achievements::parse("[]").is_err());
+        assert!(Chip8Achievements::parse(
+            r#"{ "achievements": [ { "id": "a", "title": "A", "conditions": [] } ] }"#
+        )
+        .is_err());
+        let error = Chip8Achievements::parse(
+            r#"{ "achievements": [ { "id": "a", "title": "A", "conditions": [
+                { "left": "m:2A0", "cmp": "=", "right": "5" } ] } ] }"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error, "Achievement a: = is not a comparison");
+    }
+}
@@ -1,3 +1,79 @@
-pub struct Chip8Audio {}
+// The chip8 buzzer, a square wave that plays while the sound timer runs.
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{
+    AudioContext, AudioNode, BaseAudioContext, GainNode, OscillatorNode, OscillatorType,
+};
 
-trait OutputAudio {}
+pub struct Chip8Audio {
+    context: BaseAudioContext,
+    gain: GainNode,
+    // Oscillators can only be started once, every beep gets a new one
+    oscillator: Option<OscillatorNode>,
+}
+
+impl Chip8Audio {
+    pub const FREQUENCY: f32 = 440.0;
+    // Square waves are loud
+    pub const DEFAULT_VOLUME: f32 = 0.1;
+
+    // Plays into `output`, e.g. a node of the page's own mixer, or to the speakers through an
+    // AudioContext of its own
+    pub fn new(output: Option<AudioNode>) -> Result<Chip8Audio, JsValue> {
+        let (context, output): (BaseAudioContext, AudioNode) = match output {
+            Some(output) => (output.context(), output),
+            None => {
+                let context = AudioContext::new()?;
+                let output = context.destination().into();
+                (context.into(), output)
+            }
+        };
+
+        let gain = context.create_gain()?;
+        gain.gain().set_value(Chip8Audio::DEFAULT_VOLUME);
+        gain.connect_with_audio_node(&output)?;
+
+        Ok(Chip8Audio {
+            context,
+            gain,
+            oscillator: None,
+        })
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        match (playing, &self.oscillator) {
+            (true, None) => match self.start_oscillator() {
+                Ok(oscillator) => self.oscillator = Some(oscillator),
+                Err(err) => web_sys::console::warn_2(&JsValue::from_str("Can't beep:"), &err),
+            },
+            (false, Some(oscillator)) => {
+                let _ = oscillator.stop();
+                let _ = oscillator.disconnect();
+                self.oscillator = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn start_oscillator(&self) -> Result<OscillatorNode, JsValue> {
+        let oscillator = self.context.create_oscillator()?;
+        oscillator.set_type(OscillatorType::Square);
+        oscillator.frequency().set_value(Chip8Audio::FREQUENCY);
+        oscillator.connect_with_audio_node(&self.gain)?;
+        oscillator.start()?;
+
+        Ok(oscillator)
+    }
+
+    // 0 mutes, 1 is full volume
+    pub fn set_volume(&self, volume: f32) {
+        self.gain.gain().set_value(volume.clamp(0.0, 1.0));
+    }
+
+    // Browsers keep new audio contexts suspended until the page is clicked or typed in, call
+    // this from such an event
+    pub fn resume(&self) {
+        if let Some(context) = self.context.dyn_ref::<AudioContext>() {
+            let _ = context.resume();
+        }
+    }
+}
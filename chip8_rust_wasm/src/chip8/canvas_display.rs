@@ -0,0 +1,119 @@
+// Fallback renderer for browsers without WebGL2, draws with a plain 2D canvas.
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+use super::config::{Chip8Config, Rgb, ScalingMode};
+use super::display::{
+    canvas_device_size, image_rect, scratch_canvas, texels_to_rgba, Chip8Display,
+};
+use super::frame_buffer::Chip8FrameBuffer;
+
+pub struct Chip8CanvasDisplay {
+    canvas: HtmlCanvasElement,
+    context: CanvasRenderingContext2d,
+    // 64x32 canvas the frame is put on before it is scaled up to the real one
+    frame_canvas: HtmlCanvasElement,
+    frame_context: CanvasRenderingContext2d,
+    palette: Vec<Rgb>,
+    scaling: ScalingMode,
+    stale: bool,
+}
+
+fn context_2d(canvas: &HtmlCanvasElement) -> Result<CanvasRenderingContext2d, String> {
+    canvas
+        .get_context("2d")
+        .map_err(|_| String::from("Failed finding 2D canvas context"))?
+        .ok_or_else(|| String::from("Failed to find a 2d canvas context!"))?
+        .dyn_into::<CanvasRenderingContext2d>()
+        .map_err(|_| String::from("Dynamic Cast Fail"))
+}
+
+impl Chip8CanvasDisplay {
+    // Fails if the canvas already has another kind of context
+    pub fn new(canvas: HtmlCanvasElement) -> Result<Chip8CanvasDisplay, String> {
+        let frame_canvas = scratch_canvas()?;
+        frame_canvas.set_width(Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as u32);
+        frame_canvas.set_height(Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as u32);
+
+        Ok(Chip8CanvasDisplay {
+            context: context_2d(&canvas)?,
+            frame_context: context_2d(&frame_canvas)?,
+            canvas,
+            frame_canvas,
+            palette: Chip8Config::new().palette,
+            scaling: ScalingMode::Fit,
+            stale: true,
+        })
+    }
+}
+
+impl Chip8Display for Chip8CanvasDisplay {
+    fn set_palette(&mut self, palette: &[Rgb]) {
+        self.palette = palette.to_vec();
+        self.stale = true;
+    }
+
+    fn set_scaling(&mut self, scaling: ScalingMode) {
+        if self.scaling != scaling {
+            self.scaling = scaling;
+            self.stale = true;
+        }
+    }
+
+    fn draw(&mut self, texels: &[u8]) {
+        self.stale = false;
+
+        // Match the canvas resolution to its size on the page, resizing also clears it
+        if let Some((width, height)) = canvas_device_size(&self.canvas) {
+            if (self.canvas.width(), self.canvas.height()) != (width, height) {
+                self.canvas.set_width(width);
+                self.canvas.set_height(height);
+            }
+        }
+
+        let rgba = texels_to_rgba(texels, &self.palette);
+        let image = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(&rgba),
+            Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as u32,
+            Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as u32,
+        )
+        .expect("Failed creating image data");
+        self.frame_context
+            .put_image_data(&image, 0.0, 0.0)
+            .expect("Failed putting image data");
+
+        let [r, g, b] = self.palette.first().copied().unwrap_or([0, 0, 0]);
+        self.context
+            .set_fill_style_str(&format!("rgb({}, {}, {})", r, g, b));
+        self.context.fill_rect(
+            0.0,
+            0.0,
+            self.canvas.width() as f64,
+            self.canvas.height() as f64,
+        );
+
+        let (x, y, width, height) =
+            image_rect(self.canvas.width(), self.canvas.height(), self.scaling);
+        // Keep the pixels sharp
+        self.context.set_image_smoothing_enabled(false);
+        self.context
+            .draw_image_with_html_canvas_element_and_dw_and_dh(
+                &self.frame_canvas,
+                x as f64,
+                y as f64,
+                width as f64,
+                height as f64,
+            )
+            .expect("Failed drawing the frame");
+    }
+
+    fn needs_redraw(&self) -> bool {
+        self.stale
+            || canvas_device_size(&self.canvas)
+                .is_some_and(|size| size != (self.canvas.width(), self.canvas.height()))
+    }
+
+    fn name(&self) -> &'static str {
+        "canvas 2d"
+    }
+}
@@ -0,0 +1,372 @@
+// Octo cartridges, GIF images with the Octo source and its settings hidden in the pixels.
+//
+// Every pair of pixels holds one byte of payload, the colour index of the first pixel is the high
+// nibble and the second one the low nibble (only the low 4 bits of the index count). The pixels of
+// all frames are read one after the other. The payload starts with its length as a 4 byte big
+// endian number, followed by that many bytes of UTF-8 JSON: `{ "options": {...}, "program": "..." }`.
+use serde::Deserialize;
+
+use super::{
+    config::{Chip8Config, Chip8Platform, Chip8Quirks, Rgb, SpriteEdges},
+    database::parse_color,
+    octo,
+    rom::RomLoadError,
+};
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(default)]
+    options: OctoOptions,
+    program: String,
+}
+
+// Settings as Octo stores them, missing quirks are off in Octo.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoOptions {
+    pub tickrate: Option<u32>,
+    pub background_color: Option<String>,
+    pub fill_color: Option<String>,
+    pub fill_color2: Option<String>,
+    pub blend_color: Option<String>,
+    #[serde(default)]
+    pub shift_quirks: bool,
+    #[serde(default)]
+    pub load_store_quirks: bool,
+    #[serde(default)]
+    pub jump_quirks: bool,
+    #[serde(default)]
+    pub logic_quirks: bool,
+    #[serde(default)]
+    pub v_blank_quirks: bool,
+    // Sprites wrap around the screen unless this is set
+    #[serde(default)]
+    pub clip_quirks: bool,
+    // How big the program may get, Octo's way of picking the target machine
+    pub max_size: Option<u32>,
+}
+
+impl OctoOptions {
+    // What Octo runs at when a cartridge doesn't say
+    pub const DEFAULT_TICK_RATE: u32 = 20;
+
+    // The machine behind Octo's "maxSize", None for sizes Octo doesn't offer
+    pub fn platform(&self) -> Option<Chip8Platform> {
+        match self.max_size? {
+            3584 => Some(Chip8Platform::ModernChip8),
+            3583 => Some(Chip8Platform::Superchip1),
+            65024 => Some(Chip8Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn config(&self) -> Chip8Config {
+        let mut config = Chip8Config::new();
+
+        config.platform = self.platform();
+        config.quirks = Chip8Quirks {
+            shift: self.shift_quirks,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: self.load_store_quirks,
+            jump: self.jump_quirks,
+            logic: self.logic_quirks,
+            display_wait: self.v_blank_quirks,
+            // Octo always draws DXY0 as a 16x16 sprite
+            large_sprites: true,
+            edges: if self.clip_quirks {
+                SpriteEdges::CLIP
+            } else {
+                SpriteEdges::WRAP
+            },
+        };
+
+        config.tick_rate = self.tickrate.unwrap_or(OctoOptions::DEFAULT_TICK_RATE);
+
+        // Background and fill are needed, the colours for the second plane are optional
+        let colors = [
+            &self.background_color,
+            &self.fill_color,
+            &self.fill_color2,
+            &self.blend_color,
+        ];
+        let palette: Vec<Rgb> = colors
+            .iter()
+            .map_while(|color| color.as_deref().and_then(parse_color))
+            .collect();
+
+        if palette.len() >= 2 {
+            config.palette = palette;
+        }
+
+        config
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OctoCartridge {
+    pub program: String,
+    pub options: OctoOptions,
+}
+
+impl OctoCartridge {
+    pub fn is_cartridge(bytes: &[u8]) -> bool {
+        bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+    }
+
+    pub fn decode(gif_bytes: &[u8]) -> Result<OctoCartridge, RomLoadError> {
+        let invalid = |reason: String| RomLoadError::InvalidCartridge(reason);
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options
+            .read_info(gif_bytes)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        let mut nibbles = Vec::new();
+        while let Some(frame) = decoder
+            .read_next_frame()
+            .map_err(|err| invalid(err.to_string()))?
+        {
+            nibbles.extend(frame.buffer.iter().map(|index| index & 0x0F));
+        }
+
+        let bytes: Vec<u8> = nibbles
+            .chunks_exact(2)
+            .map(|pair| (pair[0] << 4) | pair[1])
+            .collect();
+
+        if bytes.len() < 4 {
+            return Err(invalid(String::from("the image holds no data")));
+        }
+
+        let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let json = bytes.get(4..4 + length).ok_or_else(|| {
+            invalid(format!(
+                "the data is {} bytes, but {} were expected",
+                bytes.len() - 4,
+                length
+            ))
+        })?;
+
+        let payload: Payload =
+            serde_json::from_slice(json).map_err(|err| invalid(err.to_string()))?;
+
+        Ok(OctoCartridge {
+            program: payload.program,
+            options: payload.options,
+        })
+    }
+
+    // Assemble the program to a ROM
+    pub fn rom(&self) -> Result<Vec<u8>, RomLoadError> {
+        octo::assemble(&self.program).map_err(|err| RomLoadError::InvalidCartridge(err.to_string()))
+    }
+
+    pub fn config(&self) -> Chip8Config {
+        self.options.config()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Build a cartridge like Octo does, with a grayscale palette.
+    pub fn encode_cartridge(json: &str) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+
+        let mut pixels: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0F])
+            .collect();
+        let width = 32;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+
+        let palette: Vec<u8> = (0..16).flat_map(|i| [i * 17, i * 17, i * 17]).collect();
+        let mut gif_bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif_bytes, width as u16, 1, &palette).unwrap();
+
+            // Split over two frames, the reader has to join them
+            let half = pixels.len() / width / 2 * width;
+            for part in [&pixels[..half], &pixels[half..]] {
+                let frame = gif::Frame::from_indexed_pixels(
+                    width as u16,
+                    (part.len() / width) as u16,
+                    part.to_vec(),
+                    None,
+                );
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        gif_bytes
+    }
+
+    // Build a cartridge the way Octo's exporter lays it out: 128x64 frames with a 256 colour
+    // palette, the high nibble of every pixel is the label picture and the low one the data.
+    pub fn encode_octo_cartridge(json: &str) -> Vec<u8> {
+        const WIDTH: usize = 128;
+        const HEIGHT: usize = 64;
+
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+
+        let mut nibbles: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0F])
+            .collect();
+        nibbles.resize(nibbles.len().div_ceil(WIDTH * HEIGHT) * WIDTH * HEIGHT, 0);
+
+        // Colour index i shows as label colour i >> 4
+        let palette: Vec<u8> = (0..=255u8)
+            .flat_map(|i| [(i >> 4) * 17, (i >> 4) * 17, (i >> 4) * 17])
+            .collect();
+        let mut gif_bytes = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut gif_bytes, WIDTH as u16, HEIGHT as u16, &palette).unwrap();
+            encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+
+            for frame_nibbles in nibbles.chunks(WIDTH * HEIGHT) {
+                let pixels: Vec<u8> = frame_nibbles
+                    .iter()
+                    .enumerate()
+                    // Some stripes for a label
+                    .map(|(i, nibble)| (((i / WIDTH / 8) % 16) as u8) << 4 | nibble)
+                    .collect();
+                let mut frame =
+                    gif::Frame::from_indexed_pixels(WIDTH as u16, HEIGHT as u16, pixels, None);
+                frame.delay = 1;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        gif_bytes
+    }
+
+    // All the options Octo writes, for a program bigger than a CHIP-8 has room for
+    pub fn octo_xo_chip_cartridge() -> Vec<u8> {
+        let data = "0xAB ".repeat(5000);
+        let json = format!(
+            r##"{{
+                "options": {{
+                    "tickrate": 200, "fillColor": "#FFCC00", "fillColor2": "#FF6600",
+                    "blendColor": "#662200", "backgroundColor": "#996600",
+                    "buzzColor": "#FFAA00", "quietColor": "#000000",
+                    "shiftQuirks": false, "loadStoreQuirks": false, "vfOrderQuirks": false,
+                    "clipQuirks": false, "vBlankQuirks": false, "jumpQuirks": false,
+                    "logicQuirks": false, "screenRotation": 0, "maxSize": 65024,
+                    "touchInputMode": "none", "fontStyle": "octo", "displayScale": 4
+                }},
+                "program": ": main\n  loop again\n: data\n  {}"
+            }}"##,
+            data
+        );
+
+        encode_octo_cartridge(&json)
+    }
+
+    #[test]
+    fn test_decode_octo_layout() {
+        let cartridge = OctoCartridge::decode(&octo_xo_chip_cartridge()).unwrap();
+
+        assert_eq!(cartridge.options.max_size, Some(65024));
+        assert_eq!(cartridge.rom().unwrap().len(), 5004);
+
+        let config = cartridge.config();
+        assert_eq!(config.platform, Some(Chip8Platform::XoChip));
+        assert_eq!(config.tick_rate, 200);
+        assert!(config.max_rom_size() >= 5004);
+    }
+
+    #[test]
+    fn test_max_size_picks_the_platform() {
+        let platform = |max_size| {
+            OctoOptions {
+                max_size,
+                ..OctoOptions::default()
+            }
+            .config()
+            .platform
+        };
+
+        assert_eq!(platform(Some(3584)), Some(Chip8Platform::ModernChip8));
+        assert_eq!(platform(Some(3583)), Some(Chip8Platform::Superchip1));
+        assert_eq!(platform(Some(65024)), Some(Chip8Platform::XoChip));
+        assert_eq!(platform(Some(1234)), None);
+        assert_eq!(platform(None), None);
+    }
+
+    #[test]
+    fn test_decode_cartridge() {
+        let json = r##"{
+            "options": {
+                "tickrate": 7,
+                "fillColor": "#FFCC00",
+                "fillColor2": "#FF6600",
+                "blendColor": "#662200",
+                "backgroundColor": "#996600",
+                "shiftQuirks": true,
+                "loadStoreQuirks": false
+            },
+            "program": ": main\n  clear\n  v0 := 1\n  loop again"
+        }"##;
+
+        let cartridge = OctoCartridge::decode(&encode_cartridge(json)).unwrap();
+
+        assert!(cartridge.program.starts_with(": main"));
+        assert_eq!(
+            cartridge.rom().unwrap(),
+            [0x12, 0x02, 0x00, 0xE0, 0x60, 0x01, 0x12, 0x06]
+        );
+
+        let config = cartridge.config();
+        assert_eq!(config.tick_rate, 7);
+        assert_eq!(
+            config.palette,
+            vec![
+                [0x99, 0x66, 0x00],
+                [0xFF, 0xCC, 0x00],
+                [0xFF, 0x66, 0x00],
+                [0x66, 0x22, 0x00]
+            ]
+        );
+        assert!(config.quirks.shift);
+        assert!(!config.quirks.memory_leave_i_unchanged);
+        assert!(!config.quirks.logic);
+    }
+
+    #[test]
+    fn test_missing_options_use_defaults() {
+        let cartridge =
+            OctoCartridge::decode(&encode_cartridge(r#"{ "program": ": main ;" }"#)).unwrap();
+
+        let config = cartridge.config();
+        assert_eq!(config.tick_rate, OctoOptions::DEFAULT_TICK_RATE);
+        assert_eq!(config.palette, Chip8Config::new().palette);
+        assert!(!config.quirks.shift);
+    }
+
+    #[test]
+    fn test_invalid_cartridges() {
+        assert!(matches!(
+            OctoCartridge::decode(b"GIF89a nope"),
+            Err(RomLoadError::InvalidCartridge(_))
+        ));
+        assert!(matches!(
+            OctoCartridge::decode(&encode_cartridge("not json")),
+            Err(RomLoadError::InvalidCartridge(_))
+        ));
+
+        let cartridge =
+            OctoCartridge::decode(&encode_cartridge(r#"{ "program": "jump main" }"#)).unwrap();
+        assert_eq!(
+            cartridge.rom(),
+            Err(RomLoadError::InvalidCartridge(String::from(
+                "line 1: This program is missing a 'main' label"
+            )))
+        );
+    }
+}
@@ -0,0 +1,148 @@
+// Finds the addresses behind things like the lives counter, for writing cheats. Take a
+// snapshot of memory, play a bit, then keep the addresses that changed the way the value did
+// (lost a life: "decreased") and repeat until a few are left. Doesn't need a browser.
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use super::Chip8Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchFilter {
+    // The value is N now
+    Equal(u8),
+    // Compared to the last snapshot
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl SearchFilter {
+    // "equal" (needs `value`), "changed", "unchanged", "increased" or "decreased"
+    pub fn from_name(name: &str, value: Option<u8>) -> Option<SearchFilter> {
+        let filter = match name {
+            "equal" => SearchFilter::Equal(value?),
+            "changed" => SearchFilter::Changed,
+            "unchanged" => SearchFilter::Unchanged,
+            "increased" => SearchFilter::Increased,
+            "decreased" => SearchFilter::Decreased,
+            _ => return None,
+        };
+
+        Some(filter)
+    }
+
+    fn matches(&self, before: u8, now: u8) -> bool {
+        match self {
+            SearchFilter::Equal(value) => now == *value,
+            SearchFilter::Changed => now != before,
+            SearchFilter::Unchanged => now == before,
+            SearchFilter::Increased => now > before,
+            SearchFilter::Decreased => now < before,
+        }
+    }
+}
+
+// An address that is still in the running
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub address: u16,
+    pub value: u8,
+}
+
+pub struct Chip8CheatFinder {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Chip8CheatFinder {
+    // Every address below `memory_size` (the platform's, see `Chip8Config::memory_size`) is
+    // a candidate at first
+    pub fn new(memory: &Chip8Memory, memory_size: usize) -> Chip8CheatFinder {
+        Chip8CheatFinder {
+            snapshot: memory.data.to_vec(),
+            candidates: (0..memory_size).map(|address| address as u16).collect(),
+        }
+    }
+
+    // Keep the candidates that match, then snapshot memory for the next search. Returns how
+    // many are left.
+    pub fn narrow(&mut self, memory: &Chip8Memory, filter: SearchFilter) -> usize {
+        let snapshot = &mut self.snapshot;
+
+        self.candidates.retain(|address| {
+            let before = snapshot[*address as usize];
+            let now = memory.data[*address as usize];
+            snapshot[*address as usize] = now;
+
+            filter.matches(before, now)
+        });
+
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    // The first `limit` candidates with their values now
+    pub fn results(&self, memory: &Chip8Memory, limit: usize) -> Vec<SearchResult> {
+        self.candidates
+            .iter()
+            .take(limit)
+            .map(|address| SearchResult {
+                address: *address,
+                value: memory.data[*address as usize],
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finds_the_lives_counter() {
+        let mut memory = Chip8Memory::new();
+        memory.data[0x300] = 3;
+        memory.data[0x301] = 3;
+        let mut finder = Chip8CheatFinder::new(&memory, 0x1000);
+
+        assert_eq!(finder.candidates().len(), 0x1000);
+        assert_eq!(finder.narrow(&memory, SearchFilter::Equal(3)), 2);
+
+        // A life is lost, something else counts up
+        memory.data[0x300] = 2;
+        memory.data[0x301] = 4;
+        assert_eq!(finder.narrow(&memory, SearchFilter::Decreased), 1);
+        assert_eq!(
+            finder.results(&memory, 10),
+            [SearchResult {
+                address: 0x300,
+                value: 2
+            }]
+        );
+
+        // Compared to the last search, not the first
+        assert_eq!(finder.narrow(&memory, SearchFilter::Unchanged), 1);
+        memory.data[0x300] = 5;
+        assert_eq!(finder.narrow(&memory, SearchFilter::Increased), 1);
+        assert_eq!(finder.narrow(&memory, SearchFilter::Changed), 0);
+    }
+
+    #[test]
+    fn test_filter_names() {
+        assert_eq!(
+            SearchFilter::from_name("equal", Some(7)),
+            Some(SearchFilter::Equal(7))
+        );
+        assert_eq!(SearchFilter::from_name("equal", None), None);
+        assert_eq!(
+            SearchFilter::from_name("decreased", None),
+            Some(SearchFilter::Decreased)
+        );
+        assert_eq!(SearchFilter::from_name("smaller", None), None);
+    }
+}
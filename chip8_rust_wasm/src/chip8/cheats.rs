@@ -0,0 +1,360 @@
+// Cheats that poke memory, e.g. infinite lives. A cheat is a named list of address/value
+// patches, written once or every frame (freezing the value). A patch with a compare value is
+// only written while memory still holds that value.
+//
+// Cheat lists are plain text, so they can be shared and stored per game:
+//
+//   # Infinite lives for the first ship
+//   [Infinite lives] freeze on
+//   1F2 03
+//   [Start on level 5] once
+//   2A0 05 if 00
+//
+// A cheat starts with its name in brackets, "once" or "freeze" and "on" if it is turned on.
+// The lines after it are hex addresses and values, "if" adds the compare value.
+use std::fmt;
+
+use wasm_bindgen::JsValue;
+
+use super::Chip8Memory;
+
+#[derive(Debug, PartialEq)]
+pub struct CheatParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CheatParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cheats line {}: {}", self.line, self.message)
+    }
+}
+
+impl From<CheatParseError> for JsValue {
+    fn from(err: CheatParseError) -> JsValue {
+        js_sys::Error::new(&err.to_string()).into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatMode {
+    // Written on the first frame after turning it on or a reset
+    Once,
+    // Written every frame
+    Freeze,
+}
+
+impl CheatMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CheatMode::Once => "once",
+            CheatMode::Freeze => "freeze",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheatPatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub name: String,
+    pub mode: CheatMode,
+    pub patches: Vec<CheatPatch>,
+    pub enabled: bool,
+    // A once cheat that was written already
+    applied: bool,
+}
+
+impl Cheat {
+    fn apply(&mut self, memory: &mut Chip8Memory) {
+        if !self.enabled || self.applied {
+            return;
+        }
+
+        for patch in &self.patches {
+            let byte = &mut memory.data[patch.address as usize];
+            if patch.compare.map_or(true, |compare| *byte == compare) {
+                *byte = patch.value;
+            }
+        }
+
+        self.applied = self.mode == CheatMode::Once;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chip8Cheats {
+    cheats: Vec<Cheat>,
+}
+
+fn parse_hex(text: &str, max: u32, line: usize, what: &str) -> Result<u32, CheatParseError> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+
+    match u32::from_str_radix(digits, 16) {
+        Ok(number) if number <= max => Ok(number),
+        _ => Err(CheatParseError {
+            line,
+            message: format!("{} {} is not a hex number up to {:X}", what, text, max),
+        }),
+    }
+}
+
+impl Chip8Cheats {
+    pub fn new() -> Chip8Cheats {
+        Chip8Cheats { cheats: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Chip8Cheats, CheatParseError> {
+        let mut cheats: Vec<Cheat> = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let (name, options) = header.split_once(']').ok_or_else(|| CheatParseError {
+                    line: line_number,
+                    message: String::from("the cheat name has no closing ]"),
+                })?;
+                let name = name.trim();
+
+                if name.is_empty() {
+                    return Err(CheatParseError {
+                        line: line_number,
+                        message: String::from("the cheat has no name"),
+                    });
+                }
+
+                if cheats.iter().any(|cheat| cheat.name == name) {
+                    return Err(CheatParseError {
+                        line: line_number,
+                        message: format!("there already is a cheat called {}", name),
+                    });
+                }
+
+                let mut mode = None;
+                let mut enabled = false;
+                for option in options.split_whitespace() {
+                    match option {
+                        "once" => mode = Some(CheatMode::Once),
+                        "freeze" => mode = Some(CheatMode::Freeze),
+                        "on" => enabled = true,
+                        "off" => enabled = false,
+                        _ => {
+                            return Err(CheatParseError {
+                                line: line_number,
+                                message: format!("unknown cheat option {}", option),
+                            })
+                        }
+                    }
+                }
+
+                cheats.push(Cheat {
+                    name: String::from(name),
+                    mode: mode.ok_or_else(|| CheatParseError {
+                        line: line_number,
+                        message: format!("{} has to be \"once\" or \"freeze\"", name),
+                    })?,
+                    patches: Vec::new(),
+                    enabled,
+                    applied: false,
+                });
+                continue;
+            }
+
+            let cheat = cheats.last_mut().ok_or_else(|| CheatParseError {
+                line: line_number,
+                message: String::from("patches have to come after a [cheat name]"),
+            })?;
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let compare = match words.as_slice() {
+                [_, _] => None,
+                [_, _, "if", compare] => {
+                    Some(parse_hex(compare, 0xFF, line_number, "Compare")? as u8)
+                }
+                _ => return Err(CheatParseError {
+                    line: line_number,
+                    message: String::from(
+                        "patches are an address and a value, and maybe \"if\" and a compare value",
+                    ),
+                }),
+            };
+
+            let max_address = Chip8Memory::MEMORY_SIZE as u32 - 1;
+            cheat.patches.push(CheatPatch {
+                address: parse_hex(words[0], max_address, line_number, "Address")? as u16,
+                value: parse_hex(words[1], 0xFF, line_number, "Value")? as u8,
+                compare,
+            });
+        }
+
+        Ok(Chip8Cheats { cheats })
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    // False if there is no such cheat. Turning a once cheat on writes it again.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.cheats.iter_mut().find(|cheat| cheat.name == name) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                cheat.applied = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Call at the start of every frame
+    pub fn apply(&mut self, memory: &mut Chip8Memory) {
+        for cheat in &mut self.cheats {
+            cheat.apply(memory);
+        }
+    }
+
+    // The program starts over in fresh memory, once cheats are written again
+    pub fn restart(&mut self) {
+        for cheat in &mut self.cheats {
+            cheat.applied = false;
+        }
+    }
+}
+
+// The text format, with the cheats that are on marked "on"
+impl fmt::Display for Chip8Cheats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cheat in &self.cheats {
+            write!(f, "[{}] {}", cheat.name, cheat.mode.name())?;
+            if cheat.enabled {
+                write!(f, " on")?;
+            }
+            writeln!(f)?;
+
+            for patch in &cheat.patches {
+                write!(f, "{:03X} {:02X}", patch.address, patch.value)?;
+                if let Some(compare) = patch.compare {
+                    write!(f, " if {:02X}", compare)?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHEATS: &str = "\
+# Comment
+[Infinite lives] freeze on
+0x1F2 03   # lives
+
+[Start on level 5] once
+2A0 05 if 00
+2A1 ff
+";
+
+    #[test]
+    fn test_parse_and_write() {
+        let cheats = Chip8Cheats::parse(CHEATS).unwrap();
+
+        assert_eq!(cheats.cheats().len(), 2);
+        assert_eq!(cheats.cheats()[0].name, "Infinite lives");
+        assert_eq!(cheats.cheats()[0].mode, CheatMode::Freeze);
+        assert!(cheats.cheats()[0].enabled);
+        assert!(!cheats.cheats()[1].enabled);
+        assert_eq!(
+            cheats.cheats()[1].patches,
+            [
+                CheatPatch {
+                    address: 0x2A0,
+                    value: 0x05,
+                    compare: Some(0x00)
+                },
+                CheatPatch {
+                    address: 0x2A1,
+                    value: 0xFF,
+                    compare: None
+                },
+            ]
+        );
+
+        let text = cheats.to_string();
+        assert_eq!(
+            text,
+            "[Infinite lives] freeze on\n1F2 03\n[Start on level 5] once\n2A0 05 if 00\n2A1 FF\n"
+        );
+        assert_eq!(Chip8Cheats::parse(&text).unwrap(), cheats);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| Chip8Cheats::parse(text).unwrap_err().line;
+
+        assert_eq!(error("200 01"), 1);
+        assert_eq!(error("[Lives]\n200 01"), 1);
+        assert_eq!(error("[Lives] freeze\n10000 01"), 2);
+        assert_eq!(error("[Lives] freeze\n200 100"), 2);
+        assert_eq!(error("[Lives] freeze\n200 01 02"), 2);
+        assert_eq!(error("[Lives] freeze\n\n[Lives] once"), 3);
+        assert_eq!(error("[Lives freeze"), 1);
+    }
+
+    #[test]
+    fn test_once_freeze_and_compare() {
+        let mut cheats = Chip8Cheats::parse(CHEATS).unwrap();
+        let mut memory = Chip8Memory::new();
+        memory.data[0x2A0] = 0x01;
+
+        cheats.apply(&mut memory);
+        assert_eq!(memory.data[0x1F2], 0x03);
+        // Off
+        assert_eq!(memory.data[0x2A1], 0x00);
+
+        // Frozen
+        memory.data[0x1F2] = 0x02;
+        cheats.apply(&mut memory);
+        assert_eq!(memory.data[0x1F2], 0x03);
+
+        // The compare value doesn't match, the other patch is written anyway
+        assert!(cheats.set_enabled("Start on level 5", true));
+        cheats.apply(&mut memory);
+        assert_eq!(memory.data[0x2A0], 0x01);
+        assert_eq!(memory.data[0x2A1], 0xFF);
+
+        // Only once
+        memory.data[0x2A1] = 0x00;
+        cheats.apply(&mut memory);
+        assert_eq!(memory.data[0x2A1], 0x00);
+
+        // Again after a reset, now the compare value matches
+        memory.data[0x2A0] = 0x00;
+        cheats.restart();
+        cheats.apply(&mut memory);
+        assert_eq!(memory.data[0x2A0], 0x05);
+        assert_eq!(memory.data[0x2A1], 0xFF);
+
+        assert!(!cheats.set_enabled("Nope", true));
+    }
+}
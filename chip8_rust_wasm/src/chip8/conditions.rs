@@ -0,0 +1,465 @@
+// Conditions over the machine, checked once per frame: memory bytes, registers and timers
+// compared to numbers, to each other or to what they were a frame before. Achievements and
+// speedrun splits are made of these.
+//
+// In JSON a condition looks like
+//   { "left": "m:2A0", "cmp": "<", "right": "prev m:2A0", "hits": 3 }
+// Operands are "m:<hex address>", "v:<hex register>", "i", "dt" (delay timer), "st" (sound
+// timer) or a number (decimal, or hex with 0x), "prev" in front is the value a frame before.
+// The comparisons are ==, !=, <, <=, > and >=. With "hits": N a condition holds from the Nth
+// frame it was true on, with "for_frames": N once it was true N frames in a row.
+use serde::{Deserialize, Serialize};
+
+use super::Chip8Memory;
+
+// The machine at the end of a frame, memory is borrowed from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MachineSnapshot<'a> {
+    pub memory: &'a [u8],
+    pub registers: [u8; 16],
+    pub index: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+// What "prev" operands read: the registers and timers of the last frame, but only the memory
+// bytes the conditions ask for, copying all of memory every frame would be a waste.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PreviousFrame {
+    // Sorted
+    addresses: Vec<u16>,
+    // The byte at every address
+    memory: Vec<u8>,
+    registers: [u8; 16],
+    index: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    recorded: bool,
+}
+
+impl PreviousFrame {
+    // Keeps the bytes the "prev" operands of these conditions read
+    pub fn new<'a>(conditions: impl IntoIterator<Item = &'a Condition>) -> PreviousFrame {
+        let mut addresses: Vec<u16> = conditions
+            .into_iter()
+            .flat_map(|condition| [condition.left, condition.right])
+            .filter_map(|operand| match operand.source {
+                Source::Memory(address) if operand.previous => Some(address),
+                _ => None,
+            })
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        PreviousFrame {
+            memory: vec![0; addresses.len()],
+            addresses,
+            ..PreviousFrame::default()
+        }
+    }
+
+    // Remember the end of this frame for the next one
+    pub fn record(&mut self, now: &MachineSnapshot) {
+        for (address, byte) in self.addresses.iter().zip(&mut self.memory) {
+            *byte = now.memory[*address as usize];
+        }
+        self.registers = now.registers;
+        self.index = now.index;
+        self.delay_timer = now.delay_timer;
+        self.sound_timer = now.sound_timer;
+        self.recorded = true;
+    }
+
+    // There was no frame before, e.g. after a restart
+    pub fn is_recorded(&self) -> bool {
+        self.recorded
+    }
+
+    pub fn forget(&mut self) {
+        self.recorded = false;
+    }
+
+    fn memory(&self, address: u16) -> u8 {
+        self.addresses
+            .binary_search(&address)
+            .map_or(0, |position| self.memory[position])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Memory(u16),
+    Register(u8),
+    Index,
+    DelayTimer,
+    SoundTimer,
+    Number(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Operand {
+    source: Source,
+    // The value a frame before
+    previous: bool,
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+impl Operand {
+    pub fn parse(text: &str) -> Result<Operand, String> {
+        let text = text.trim().to_ascii_lowercase();
+        let (previous, name) = match text.strip_prefix("prev ") {
+            Some(name) => (true, name.trim()),
+            None => (false, text.as_str()),
+        };
+
+        let source = match name {
+            "i" => Source::Index,
+            "dt" => Source::DelayTimer,
+            "st" => Source::SoundTimer,
+            _ => {
+                if let Some(address) = name.strip_prefix("m:") {
+                    match u16::from_str_radix(address, 16) {
+                        Ok(address) if (address as usize) < Chip8Memory::MEMORY_SIZE => {
+                            Source::Memory(address)
+                        }
+                        _ => return Err(format!("{} is not a memory address", address)),
+                    }
+                } else if let Some(register) = name.strip_prefix("v:") {
+                    match u8::from_str_radix(register, 16) {
+                        Ok(register) if register < 16 => Source::Register(register),
+                        _ => return Err(format!("{} is not a register", register)),
+                    }
+                } else {
+                    Source::Number(
+                        parse_number(name)
+                            .ok_or_else(|| format!("{} is not something to compare", name))?,
+                    )
+                }
+            }
+        };
+
+        if previous && matches!(source, Source::Number(_)) {
+            return Err(format!("{} is a number, it has no previous value", name));
+        }
+
+        Ok(Operand { source, previous })
+    }
+
+    fn value(&self, now: &MachineSnapshot, before: &PreviousFrame) -> u32 {
+        if self.previous {
+            return match self.source {
+                Source::Memory(address) => before.memory(address) as u32,
+                Source::Register(register) => before.registers[register as usize] as u32,
+                Source::Index => before.index as u32,
+                Source::DelayTimer => before.delay_timer as u32,
+                Source::SoundTimer => before.sound_timer as u32,
+                Source::Number(number) => number,
+            };
+        }
+
+        match self.source {
+            Source::Memory(address) => now.memory[address as usize] as u32,
+            Source::Register(register) => now.registers[register as usize] as u32,
+            Source::Index => now.index as u32,
+            Source::DelayTimer => now.delay_timer as u32,
+            Source::SoundTimer => now.sound_timer as u32,
+            Source::Number(number) => number,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn parse(text: &str) -> Result<Comparison, String> {
+        let comparison = match text.trim() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessOrEqual,
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterOrEqual,
+            other => return Err(format!("{} is not a comparison", other)),
+        };
+
+        Ok(comparison)
+    }
+
+    fn compare(&self, left: u32, right: u32) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+// A condition as it is written in JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConditionDefinition {
+    pub left: String,
+    pub cmp: String,
+    pub right: String,
+    #[serde(default)]
+    pub hits: u32,
+    #[serde(default)]
+    pub for_frames: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+    hits: u32,
+    for_frames: u32,
+    // Frames it was true on, and how many of those in a row up to now
+    hit_count: u32,
+    streak: u32,
+}
+
+impl Condition {
+    pub fn from_definition(definition: &ConditionDefinition) -> Result<Condition, String> {
+        Ok(Condition {
+            left: Operand::parse(&definition.left)?,
+            comparison: Comparison::parse(&definition.cmp)?,
+            right: Operand::parse(&definition.right)?,
+            hits: definition.hits,
+            for_frames: definition.for_frames,
+            hit_count: 0,
+            streak: 0,
+        })
+    }
+
+    // Call once per frame, whether it holds now. `before` has to be made for this condition.
+    pub fn update(&mut self, now: &MachineSnapshot, before: &PreviousFrame) -> bool {
+        let holds = self
+            .comparison
+            .compare(self.left.value(now, before), self.right.value(now, before));
+
+        if holds {
+            self.hit_count = self.hit_count.saturating_add(1);
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 0;
+        }
+
+        match (self.hits, self.for_frames) {
+            (0, 0) => holds,
+            (hits, 0) => self.hit_count >= hits,
+            (0, frames) => self.streak >= frames,
+            (hits, frames) => self.hit_count >= hits && self.streak >= frames,
+        }
+    }
+
+    // Forget the hits, e.g. when the game starts over
+    pub fn reset(&mut self) {
+        self.hit_count = 0;
+        self.streak = 0;
+    }
+}
+
+// Every condition is updated, so all hit counts keep counting. True if they all hold.
+pub fn update_all(
+    conditions: &mut [Condition],
+    now: &MachineSnapshot,
+    before: &PreviousFrame,
+) -> bool {
+    let mut all = true;
+    for condition in conditions {
+        all &= condition.update(now, before);
+    }
+
+    all
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // A machine that owns its memory, for making snapshots of
+    #[derive(Clone)]
+    pub struct TestMachine {
+        pub memory: Vec<u8>,
+        pub registers: [u8; 16],
+        pub index: u16,
+        pub delay_timer: u8,
+    }
+
+    impl Default for TestMachine {
+        fn default() -> Self {
+            TestMachine::new()
+        }
+    }
+
+    impl TestMachine {
+        pub fn new() -> TestMachine {
+            TestMachine {
+                memory: vec![0; Chip8Memory::MEMORY_SIZE],
+                registers: [0; 16],
+                index: 0,
+                delay_timer: 0,
+            }
+        }
+
+        pub fn snapshot(&self) -> MachineSnapshot<'_> {
+            MachineSnapshot {
+                memory: &self.memory,
+                registers: self.registers,
+                index: self.index,
+                delay_timer: self.delay_timer,
+                sound_timer: 0,
+            }
+        }
+    }
+
+    fn condition(left: &str, cmp: &str, right: &str, hits: u32, for_frames: u32) -> Condition {
+        Condition::from_definition(&ConditionDefinition {
+            left: String::from(left),
+            cmp: String::from(cmp),
+            right: String::from(right),
+            hits,
+            for_frames,
+        })
+        .unwrap()
+    }
+
+    // The frame before, kept for `condition`
+    fn previous(condition: &Condition, machine: &TestMachine) -> PreviousFrame {
+        let mut before = PreviousFrame::new([condition]);
+        before.record(&machine.snapshot());
+        before
+    }
+
+    #[test]
+    fn test_operands() {
+        let mut now = TestMachine::new();
+        now.memory[0x2A0] = 7;
+        now.registers[0xA] = 3;
+        now.index = 0x300;
+        now.delay_timer = 9;
+        let before = TestMachine::new();
+
+        let value = |text: &str| {
+            let operand = Operand::parse(text).unwrap();
+            let mut previous = PreviousFrame {
+                addresses: vec![0x2A0],
+                memory: vec![0],
+                ..PreviousFrame::default()
+            };
+            previous.record(&before.snapshot());
+            operand.value(&now.snapshot(), &previous)
+        };
+        assert_eq!(value("m:2a0"), 7);
+        assert_eq!(value("prev m:2A0"), 0);
+        assert_eq!(value("v:A"), 3);
+        assert_eq!(value("prev v:A"), 0);
+        assert_eq!(value("I"), 0x300);
+        assert_eq!(value("dt"), 9);
+        assert_eq!(value("st"), 0);
+        assert_eq!(value("0x10"), 16);
+        assert_eq!(value("10"), 10);
+
+        assert!(Operand::parse("m:10000").is_err());
+        assert!(Operand::parse("v:10").is_err());
+        assert!(Operand::parse("prev 5").is_err());
+        assert!(Operand::parse("lives").is_err());
+        assert!(Comparison::parse("=>").is_err());
+    }
+
+    #[test]
+    fn test_only_prev_bytes_are_kept() {
+        let conditions = [
+            condition("m:300", "<", "prev m:300", 0, 0),
+            condition("prev m:10", "==", "m:20", 0, 0),
+            condition("m:300", "!=", "prev m:300", 0, 0),
+        ];
+        let mut machine = TestMachine::new();
+        machine.memory[0x10] = 5;
+        machine.memory[0x300] = 3;
+
+        let mut before = PreviousFrame::new(&conditions);
+        assert!(!before.is_recorded());
+        before.record(&machine.snapshot());
+
+        assert!(before.is_recorded());
+        assert_eq!(before.addresses, [0x10, 0x300]);
+        assert_eq!(before.memory, [5, 3]);
+        assert_eq!(before.memory(0x20), 0);
+
+        before.forget();
+        assert!(!before.is_recorded());
+    }
+
+    #[test]
+    fn test_deltas_hits_and_frames() {
+        let mut before = TestMachine::new();
+        let mut now = TestMachine::new();
+
+        // Lives went down
+        let mut lost_life = condition("m:300", "<", "prev m:300", 0, 0);
+        before.memory[0x300] = 3;
+        now.memory[0x300] = 2;
+        assert!(lost_life.update(&now.snapshot(), &previous(&lost_life, &before)));
+        assert!(!lost_life.update(&now.snapshot(), &previous(&lost_life, &now)));
+
+        // Scored 3 times, the hits stay
+        let mut scored_3_times = condition("v:1", "==", "1", 3, 0);
+        let before = previous(&scored_3_times, &before);
+        now.registers[1] = 1;
+        assert!(!scored_3_times.update(&now.snapshot(), &before));
+        assert!(!scored_3_times.update(&now.snapshot(), &before));
+        now.registers[1] = 0;
+        assert!(!scored_3_times.update(&now.snapshot(), &before));
+        now.registers[1] = 1;
+        assert!(scored_3_times.update(&now.snapshot(), &before));
+        now.registers[1] = 0;
+        assert!(scored_3_times.update(&now.snapshot(), &before));
+
+        // A second without losing the timer
+        let mut held = condition("dt", ">", "0", 0, 60);
+        now.delay_timer = 1;
+        for _ in 0..59 {
+            assert!(!held.update(&now.snapshot(), &before));
+        }
+        assert!(held.update(&now.snapshot(), &before));
+        now.delay_timer = 0;
+        assert!(!held.update(&now.snapshot(), &before));
+
+        held.reset();
+        now.delay_timer = 1;
+        assert!(!held.update(&now.snapshot(), &before));
+    }
+
+    #[test]
+    fn test_update_all_counts_every_hit() {
+        let now = TestMachine::new();
+        let before = PreviousFrame::default();
+        let mut conditions = [
+            condition("1", "==", "2", 0, 0),
+            condition("1", "==", "1", 2, 0),
+        ];
+
+        assert!(!update_all(&mut conditions, &now.snapshot(), &before));
+        conditions[0] = condition("1", "==", "1", 0, 0);
+        // The second one counted its hit while the first didn't hold
+        assert!(update_all(&mut conditions, &now.snapshot(), &before));
+    }
+}
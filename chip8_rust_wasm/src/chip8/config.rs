@@ -0,0 +1,337 @@
+// Machine configuration, everything that differs between the interpreters ROMs were written for.
+use super::keyboard_input::Chip8KeyMap;
+use super::persistence::PersistenceMode;
+use super::Chip8Memory;
+
+pub type Rgb = [u8; 3];
+
+// Interpreters from the CHIP-8 database, named like the database does.
+// Everything runs on the 64x32 screen, only the quirks differ. SUPER-CHIP and XO-CHIP are here so
+// their ROMs are recognised, their extra instructions and screen modes aren't emulated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip8Platform {
+    OriginalChip8,
+    HybridVip,
+    ModernChip8,
+    Chip48,
+    Superchip1,
+    Superchip,
+    XoChip,
+}
+
+impl Chip8Platform {
+    pub fn from_id(id: &str) -> Option<Chip8Platform> {
+        let platform = match id {
+            "originalChip8" => Chip8Platform::OriginalChip8,
+            "hybridVIP" => Chip8Platform::HybridVip,
+            "modernChip8" => Chip8Platform::ModernChip8,
+            "chip48" => Chip8Platform::Chip48,
+            "superchip1" => Chip8Platform::Superchip1,
+            "superchip" => Chip8Platform::Superchip,
+            "xochip" => Chip8Platform::XoChip,
+            _ => return None,
+        };
+
+        Some(platform)
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Chip8Platform::OriginalChip8 => "originalChip8",
+            Chip8Platform::HybridVip => "hybridVIP",
+            Chip8Platform::ModernChip8 => "modernChip8",
+            Chip8Platform::Chip48 => "chip48",
+            Chip8Platform::Superchip1 => "superchip1",
+            Chip8Platform::Superchip => "superchip",
+            Chip8Platform::XoChip => "xochip",
+        }
+    }
+
+    // Whether its ROMs can run here, see above
+    pub fn is_supported(&self) -> bool {
+        !matches!(
+            self,
+            Chip8Platform::Superchip1 | Chip8Platform::Superchip | Chip8Platform::XoChip
+        )
+    }
+
+    // Quirks of the interpreter, as listed in the database's platforms.json
+    pub fn quirks(&self) -> Chip8Quirks {
+        match self {
+            Chip8Platform::OriginalChip8 | Chip8Platform::HybridVip => Chip8Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                jump: false,
+                logic: true,
+                display_wait: true,
+                large_sprites: false,
+                edges: SpriteEdges::CLIP,
+            },
+            Chip8Platform::ModernChip8 => Chip8Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                jump: false,
+                logic: false,
+                display_wait: false,
+                large_sprites: false,
+                edges: SpriteEdges::CLIP,
+            },
+            Chip8Platform::XoChip => Chip8Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                jump: false,
+                logic: false,
+                display_wait: false,
+                large_sprites: true,
+                edges: SpriteEdges::WRAP,
+            },
+            Chip8Platform::Chip48 => Chip8Quirks {
+                shift: true,
+                memory_increment_by_x: true,
+                memory_leave_i_unchanged: false,
+                jump: true,
+                logic: false,
+                display_wait: false,
+                large_sprites: false,
+                edges: SpriteEdges::CLIP,
+            },
+            Chip8Platform::Superchip1 => Chip8Quirks {
+                shift: true,
+                memory_increment_by_x: true,
+                memory_leave_i_unchanged: false,
+                jump: true,
+                logic: false,
+                display_wait: false,
+                large_sprites: true,
+                edges: SpriteEdges::CLIP,
+            },
+            Chip8Platform::Superchip => Chip8Quirks {
+                shift: true,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: true,
+                jump: true,
+                logic: false,
+                display_wait: false,
+                large_sprites: true,
+                edges: SpriteEdges::CLIP,
+            },
+        }
+    }
+}
+
+// Instruction behaviour that differs between interpreters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chip8Quirks {
+    // 8XY6/8XYE shift VX in place instead of shifting VY into VX
+    pub shift: bool,
+    // FX55/FX65 add X to I instead of X + 1
+    pub memory_increment_by_x: bool,
+    // FX55/FX65 leave I alone
+    pub memory_leave_i_unchanged: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic: bool,
+    // DXYN waits for the next 60 Hz frame like the COSMAC VIP, at most 60 sprites a second
+    pub display_wait: bool,
+    // DXY0 draws a 16x16 sprite from 32 bytes at I instead of nothing
+    pub large_sprites: bool,
+    // What happens to sprites that cross the edge of the screen
+    pub edges: SpriteEdges,
+}
+
+impl Default for Chip8Quirks {
+    // How this emulator has always behaved
+    fn default() -> Self {
+        Chip8Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            jump: false,
+            logic: false,
+            display_wait: false,
+            large_sprites: false,
+            edges: SpriteEdges::CLIP,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EdgeMode {
+    // Cut off at the edge
+    #[default]
+    Clip,
+    // Continue on the other side of the screen
+    Wrap,
+}
+
+// Edge behaviour of sprites per axis, the start position always wraps around.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SpriteEdges {
+    pub horizontal: EdgeMode,
+    pub vertical: EdgeMode,
+}
+
+impl SpriteEdges {
+    pub const CLIP: SpriteEdges = SpriteEdges {
+        horizontal: EdgeMode::Clip,
+        vertical: EdgeMode::Clip,
+    };
+    pub const WRAP: SpriteEdges = SpriteEdges {
+        horizontal: EdgeMode::Wrap,
+        vertical: EdgeMode::Wrap,
+    };
+}
+
+// Named colour schemes to pick from the page.
+// Background first, then the colours of the planes (fill, second fill, both planes).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PalettePreset {
+    Classic,
+    Amber,
+    Lcd,
+    HighContrast,
+}
+
+impl PalettePreset {
+    pub const ALL: [PalettePreset; 4] = [
+        PalettePreset::Classic,
+        PalettePreset::Amber,
+        PalettePreset::Lcd,
+        PalettePreset::HighContrast,
+    ];
+
+    pub fn from_name(name: &str) -> Option<PalettePreset> {
+        PalettePreset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PalettePreset::Classic => "classic",
+            PalettePreset::Amber => "amber",
+            PalettePreset::Lcd => "lcd",
+            PalettePreset::HighContrast => "high-contrast",
+        }
+    }
+
+    pub fn colors(&self) -> Vec<Rgb> {
+        match self {
+            // Black on green, how this emulator has always looked
+            PalettePreset::Classic => vec![[0x00, 0xFF, 0x00], [0x00, 0x00, 0x00]],
+            PalettePreset::Amber => vec![
+                [0x1A, 0x0E, 0x00],
+                [0xFF, 0xB0, 0x00],
+                [0xB3, 0x5C, 0x00],
+                [0x66, 0x33, 0x00],
+            ],
+            PalettePreset::Lcd => vec![
+                [0x9B, 0xBC, 0x0F],
+                [0x0F, 0x38, 0x0F],
+                [0x30, 0x62, 0x30],
+                [0x8B, 0xAC, 0x0F],
+            ],
+            PalettePreset::HighContrast => vec![
+                [0x00, 0x00, 0x00],
+                [0xFF, 0xFF, 0xFF],
+                [0xFF, 0xFF, 0x00],
+                [0x00, 0xFF, 0xFF],
+            ],
+        }
+    }
+}
+
+// How the 64x32 image is scaled up to the canvas, both keep the aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScalingMode {
+    // As large as fits
+    #[default]
+    Fit,
+    // As large as fits with a whole number of screen pixels per chip8 pixel
+    Integer,
+}
+
+impl ScalingMode {
+    pub fn from_name(name: &str) -> Option<ScalingMode> {
+        match name {
+            "fit" => Some(ScalingMode::Fit),
+            "integer" => Some(ScalingMode::Integer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chip8Config {
+    pub platform: Option<Chip8Platform>,
+    pub quirks: Chip8Quirks,
+    // Instructions executed per 60 Hz frame
+    pub tick_rate: u32,
+    pub key_map: Chip8KeyMap,
+    // Background first, then the foreground, at most `MAX_COLORS`
+    pub palette: Vec<Rgb>,
+    pub persistence: PersistenceMode,
+    pub scaling: ScalingMode,
+}
+
+impl Chip8Config {
+    pub const DEFAULT_TICK_RATE: u32 = 15;
+    // One colour for every combination of 4 planes
+    pub const MAX_COLORS: usize = 16;
+
+    pub fn new() -> Chip8Config {
+        Chip8Config {
+            platform: None,
+            quirks: Chip8Quirks::default(),
+            tick_rate: Chip8Config::DEFAULT_TICK_RATE,
+            key_map: Chip8KeyMap::default(),
+            palette: PalettePreset::Classic.colors(),
+            persistence: PersistenceMode::Off,
+            scaling: ScalingMode::Fit,
+        }
+    }
+
+    // XO-CHIP programs can use the whole 64 KB address space, the others have 4 KB
+    pub fn memory_size(&self) -> usize {
+        match self.platform {
+            Some(Chip8Platform::XoChip) => Chip8Memory::MEMORY_SIZE,
+            _ => 0x1000,
+        }
+    }
+
+    // Everything from the start address to the end of memory
+    pub fn max_rom_size(&self) -> usize {
+        self.memory_size() - Chip8Memory::START_ADRESS as usize
+    }
+}
+
+impl Default for Chip8Config {
+    fn default() -> Self {
+        Chip8Config::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette_presets() {
+        for preset in PalettePreset::ALL {
+            assert_eq!(PalettePreset::from_name(preset.name()), Some(preset));
+
+            let colors = preset.colors();
+            assert!(colors.len() >= 2 && colors.len() <= Chip8Config::MAX_COLORS);
+            // Pixels have to be visible on the background
+            assert_ne!(colors[0], colors[1]);
+        }
+
+        assert_eq!(PalettePreset::from_name("sepia"), None);
+        assert_eq!(Chip8Config::new().palette, PalettePreset::Classic.colors());
+    }
+}
@@ -1,9 +1,11 @@
-use std::{cell::RefCell, rc::Rc, u16, u8, usize};
+use std::{cell::RefCell, rc::Rc};
 
-use wasm_bindgen::JsValue;
-use web_sys::console;
-
-use super::{display::Chip8WebGLDisplay, keyboard_input::Chip8Input, Chip8, Chip8Memory};
+use super::{
+    config::{Chip8Quirks, EdgeMode},
+    frame_buffer::Chip8FrameBuffer,
+    keyboard_input::Chip8Input,
+    Chip8Memory,
+};
 
 pub struct Chip8CPU {
     index_registers: [u8; 16],
@@ -14,8 +16,12 @@ pub struct Chip8CPU {
     opcode: u16,
     delay_timer: u8,
     sound_timer: u8,
+    quirks: Chip8Quirks,
+    // A sprite was drawn with the display wait quirk, nothing runs until the next frame
+    waiting_for_vblank: bool,
     chip8_memory: Rc<RefCell<Chip8Memory>>,
-    chip8_display: Rc<RefCell<Chip8WebGLDisplay>>,
+    chip8_frame_buffer: Rc<RefCell<Chip8FrameBuffer>>,
+    chip8_input: Rc<RefCell<Chip8Input>>,
     func_table: [fn(&mut Chip8CPU); 0xF + 1],
     table0: [fn(&mut Chip8CPU); 0xE + 1],
     table8: [fn(&mut Chip8CPU); 0xE + 1],
@@ -25,7 +31,11 @@ pub struct Chip8CPU {
 }
 
 impl Chip8CPU {
-    pub fn new(mem: Rc<RefCell<Chip8Memory>>, disp: Rc<RefCell<Chip8WebGLDisplay>>) -> Chip8CPU {
+    pub fn new(
+        mem: Rc<RefCell<Chip8Memory>>,
+        frame_buffer: Rc<RefCell<Chip8FrameBuffer>>,
+        input: Rc<RefCell<Chip8Input>>,
+    ) -> Chip8CPU {
         let mut cpu = Chip8CPU {
             index_registers: [0; 16],
             stack: [0; 16],
@@ -35,8 +45,11 @@ impl Chip8CPU {
             opcode: 0x00,
             delay_timer: 0,
             sound_timer: 0,
+            quirks: Chip8Quirks::default(),
+            waiting_for_vblank: false,
             chip8_memory: mem,
-            chip8_display: disp,
+            chip8_frame_buffer: frame_buffer,
+            chip8_input: input,
             func_table: [Chip8CPU::no_op; 0xF + 1],
             table0: [Chip8CPU::no_op; 0xE + 1],
             table8: [Chip8CPU::no_op; 0xE + 1],
@@ -74,7 +87,6 @@ impl Chip8CPU {
         cpu.table8[0x7] = Chip8CPU::op_8xy7;
         cpu.table8[0xE] = Chip8CPU::op_8xye;
 
-        // TODO: implement input functions
         cpu.table_e[0x1] = Chip8CPU::op_exa1;
         cpu.table_e[0xE] = Chip8CPU::op_ex9e;
 
@@ -91,6 +103,63 @@ impl Chip8CPU {
         cpu
     }
 
+    // Back to how the machine starts, the quirks stay
+    pub fn reset(&mut self) {
+        self.index_registers = [0; 16];
+        self.stack = [0; 16];
+        self.index = 0;
+        self.sp = 0x00;
+        self.pc = Chip8Memory::START_ADRESS as u16;
+        self.opcode = 0x00;
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.waiting_for_vblank = false;
+    }
+
+    // Registers, stack, timers and the display wait, without the quirks
+    pub const STATE_SIZE: usize = 16 + 16 * 2 + 2 + 1 + 2 + 1 + 1 + 1;
+
+    pub fn write_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&self.index_registers);
+        for address in &self.stack {
+            state.extend_from_slice(&address.to_be_bytes());
+        }
+        state.extend_from_slice(&self.index.to_be_bytes());
+        state.push(self.sp);
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.push(self.delay_timer);
+        state.push(self.sound_timer);
+        state.push(self.waiting_for_vblank as u8);
+    }
+
+    // `state` is `STATE_SIZE` bytes written by `write_state`
+    pub fn read_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let word = |at: usize| u16::from_be_bytes([state[at], state[at + 1]]);
+        let sp = state[50];
+        let pc = word(51);
+
+        if sp as usize > self.stack.len() || pc as usize + 1 >= Chip8Memory::MEMORY_SIZE {
+            return Err(String::from("The CPU in the save state is broken"));
+        }
+
+        self.index_registers.copy_from_slice(&state[..16]);
+        for (index, address) in self.stack.iter_mut().enumerate() {
+            *address = word(16 + index * 2);
+        }
+        self.index = word(48);
+        self.sp = sp;
+        self.pc = pc;
+        self.delay_timer = state[53];
+        self.sound_timer = state[54];
+        self.waiting_for_vblank = state[55] != 0;
+
+        Ok(())
+    }
+
+    pub fn set_quirks(&mut self, quirks: Chip8Quirks) {
+        self.quirks = quirks;
+    }
+
     fn table_0(&mut self) {
         self.table0[(self.opcode & 0x000F) as usize](self);
     }
@@ -108,9 +177,13 @@ impl Chip8CPU {
     }
 
     pub fn cycle(&mut self) {
+        if self.waiting_for_vblank {
+            return;
+        }
+
         // Opcodes are stored in memory as 2 u8's so to get a u16 opcode use bitmask
         {
-            let memory = self.chip8_memory.borrow_mut();
+            let memory = self.chip8_memory.borrow();
 
             self.opcode = ((memory.data[self.pc as usize] as u16) << 8)
                 | memory.data[self.pc as usize + 1] as u16;
@@ -123,7 +196,10 @@ impl Chip8CPU {
 
         // Function pointer shit
         self.func_table[((self.opcode & 0xF000) >> 12) as usize](self);
+    }
 
+    // Called at 60 Hz, independent of how many instructions run per frame
+    pub fn tick_timers(&mut self) {
         // Decrease delay timer
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
@@ -135,12 +211,38 @@ impl Chip8CPU {
         }
     }
 
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    // V0 to VF
+    pub fn registers(&self) -> [u8; 16] {
+        self.index_registers
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    // Called at every 60 Hz frame boundary, lets a CPU stalled by DXYN continue
+    pub fn vblank(&mut self) {
+        self.waiting_for_vblank = false;
+    }
+
+    // The rest of this frame's cycles would do nothing
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
     fn no_op(&mut self) {}
 
     // Clear display
     fn op_00e0(&mut self) {
-        self.chip8_display.borrow_mut().clear();
-        // display.clear();
+        self.chip8_frame_buffer.borrow_mut().clear();
     }
 
     // Return
@@ -231,6 +333,10 @@ impl Chip8CPU {
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.index_registers[x as usize] |= self.index_registers[y as usize];
+
+        if self.quirks.logic {
+            self.index_registers[0xF_usize] = 0;
+        }
     }
 
     // Set Vx to Vx Bitwise-AND Vy
@@ -239,6 +345,10 @@ impl Chip8CPU {
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.index_registers[x as usize] &= self.index_registers[y as usize];
+
+        if self.quirks.logic {
+            self.index_registers[0xF_usize] = 0;
+        }
     }
 
     // Set Vx to Vx Bitwise-XOR Vy
@@ -247,6 +357,10 @@ impl Chip8CPU {
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.index_registers[x as usize] ^= self.index_registers[y as usize];
+
+        if self.quirks.logic {
+            self.index_registers[0xF_usize] = 0;
+        }
     }
 
     // Adds Vy to Vx. VF is set to 1 when there's a carry, and to 0 when there is not.
@@ -254,16 +368,12 @@ impl Chip8CPU {
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
-        let result =
-            self.index_registers[x as usize] as u16 + self.index_registers[y as usize] as u16;
+        let (result, carry) =
+            self.index_registers[x as usize].overflowing_add(self.index_registers[y as usize]);
 
-        if result > 255 {
-            self.index_registers[0xF_usize] = 1;
-        } else {
-            self.index_registers[0xF_usize] = 0;
-        }
-
-        self.index_registers[x as usize] = (result & 0xFF) as u8;
+        // VF is written last so the flag wins when VF itself is the destination
+        self.index_registers[x as usize] = result;
+        self.index_registers[0xF_usize] = carry as u8;
     }
 
     // VY is subtracted from VX. VF is set to 0 when there's a borrow, and 1 when there is not.
@@ -271,23 +381,22 @@ impl Chip8CPU {
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
-        if y > x {
-            self.index_registers[0xF_usize] = 0;
-        } else {
-            self.index_registers[0xF_usize] = 1;
-        }
+        let vx = self.index_registers[x as usize];
+        let vy = self.index_registers[y as usize];
 
         // Allow integer wrapping
-        self.index_registers[x as usize] =
-            self.index_registers[x as usize].wrapping_sub(self.index_registers[y as usize]);
+        self.index_registers[x as usize] = vx.wrapping_sub(vy);
+        self.index_registers[0xF_usize] = (vx >= vy) as u8;
     }
 
     // Stores the least significant bit of VX in VF and then shifts VX to the right by 1.
+    // Without the shift quirk VY is shifted into VX instead.
     fn op_8xy6(&mut self) {
+        let value = self.shift_source();
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        self.index_registers[0xF_usize] = self.index_registers[x as usize] & 0x1;
-        self.index_registers[x as usize] >>= 1;
+        self.index_registers[x as usize] = value >> 1;
+        self.index_registers[0xF_usize] = value & 0x1;
     }
 
     // Sets VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 when there is not.
@@ -298,21 +407,29 @@ impl Chip8CPU {
         let vy = self.index_registers[y as usize];
         let vx = self.index_registers[x as usize];
 
-        if vx > vy {
-            self.index_registers[0xF_usize] = 0;
-        } else {
-            self.index_registers[0xF_usize] = 1;
-        }
-
         self.index_registers[x as usize] = vy.wrapping_sub(vx);
+        self.index_registers[0xF_usize] = (vy >= vx) as u8;
     }
 
     // Stores the most significant bit of VX in VF and then shifts VX to the left by 1.
+    // Without the shift quirk VY is shifted into VX instead.
     fn op_8xye(&mut self) {
+        let value = self.shift_source();
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        self.index_registers[0xF_usize] = (self.index_registers[x as usize] & 0x80) >> 7;
-        self.index_registers[x as usize] <<= 1;
+        self.index_registers[x as usize] = value << 1;
+        self.index_registers[0xF_usize] = (value & 0x80) >> 7;
+    }
+
+    fn shift_source(&self) -> u8 {
+        let x = ((self.opcode & 0x0F00) >> 8) as u8;
+        let y = ((self.opcode & 0x00F0) >> 4) as u8;
+
+        if self.quirks.shift {
+            self.index_registers[x as usize]
+        } else {
+            self.index_registers[y as usize]
+        }
     }
 
     // Skips the next instruction if VX does not equal VY. (Usually the next instruction is a jump to skip a code block);
@@ -332,9 +449,16 @@ impl Chip8CPU {
     }
 
     // Jumps to the address NNN plus V0.
+    // With the jump quirk it is XNN plus VX.
     fn op_bnnn(&mut self) {
         let nnn: u16 = self.opcode & 0x0FFF;
-        self.pc = self.index_registers[0] as u16 + nnn;
+        let register = if self.quirks.jump {
+            ((self.opcode & 0x0F00) >> 8) as usize
+        } else {
+            0
+        };
+
+        self.pc = self.index_registers[register] as u16 + nnn;
     }
 
     // Sets VX to the result of a bitwise and operation on a random number (Typically: 0 to 255) and NN.
@@ -345,46 +469,59 @@ impl Chip8CPU {
         self.index_registers[x as usize] = Chip8CPU::random_byte() & nn;
     }
 
-    // Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels.
-    // Each row of 8 pixels is read as bit-coded starting from memory location I;
+    // Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels,
+    // with the large sprites quirk N = 0 draws a 16x16 sprite with 2 bytes per row.
+    // Each row of pixels is read as bit-coded starting from memory location I;
     // I value does not change after the execution of this instruction.
     // As described above, VF is set to 1 if any screen pixels are flipped from set to unset when the sprite is drawn,
     // and to 0 if that does not happen
     fn op_dxyn(&mut self) {
-        let display = &mut self.chip8_display.borrow_mut();
-        let memory = self.chip8_memory.borrow_mut();
+        let frame_buffer = &mut self.chip8_frame_buffer.borrow_mut();
+        let memory = self.chip8_memory.borrow();
 
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
 
-        let x_pos = self.index_registers[x as usize] % Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH;
-        let y_pos = self.index_registers[y as usize] % Chip8WebGLDisplay::CHIP8_DISPLAY_HEIGHT;
+        let x_pos = self.index_registers[x as usize] % Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH;
+        let y_pos = self.index_registers[y as usize] % Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT;
 
-        let width = 8_u8;
-        let height = (self.opcode & 0xF) as u8;
+        let (width, height) = match (self.opcode & 0xF) as u8 {
+            0 if self.quirks.large_sprites => (16_u8, 16_u8),
+            n => (8, n),
+        };
+        let bytes_per_row = width as usize / 8;
+        let wrap_x = self.quirks.edges.horizontal == EdgeMode::Wrap;
 
         self.index_registers[0xF] = 0;
 
         // Start at I (index) loop to I + y_index
         for i_y in 0..height {
-            let pixels = memory.data[(self.index + i_y as u16) as usize];
-
-            for i_x in 0..width {
-                let mut pixel_state = (pixels >> (width - 1 - i_x)) & 0x1;
-
-                if pixel_state == 0 {
-                    pixel_state = 0x00;
-                } else {
-                    pixel_state = 0xFF;
-                }
-
-                let flipped = display.xor_pixel(i_x + x_pos, i_y + y_pos, pixel_state);
-
-                if flipped {
-                    self.index_registers[0xF] = 1;
-                }
+            let y = match self.quirks.edges.vertical {
+                EdgeMode::Clip if y_pos + i_y >= Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT => break,
+                EdgeMode::Clip => y_pos + i_y,
+                EdgeMode::Wrap => (y_pos + i_y) % Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT,
+            };
+
+            let address = self.index as usize + i_y as usize * bytes_per_row;
+            let pixels = (0..bytes_per_row).fold(0_u16, |row, byte| {
+                (row << 8) | memory.data[(address + byte) % Chip8Memory::MEMORY_SIZE] as u16
+            });
+
+            let flipped = frame_buffer.xor_sprite_row(
+                x_pos,
+                y,
+                pixels,
+                width,
+                Chip8FrameBuffer::PIXEL_ON,
+                wrap_x,
+            );
+
+            if flipped {
+                self.index_registers[0xF] = 1;
             }
         }
+
+        self.waiting_for_vblank = self.quirks.display_wait;
     }
 
     // Skips the next instruction if the key stored in VX is pressed.
@@ -392,10 +529,11 @@ impl Chip8CPU {
     fn op_ex9e(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        // TODO: Implement input
-        let input = 0;
-
-        if self.index_registers[x as usize] == input {
+        if self
+            .chip8_input
+            .borrow()
+            .is_pressed(self.index_registers[x as usize])
+        {
             self.pc += 2;
         }
     }
@@ -405,10 +543,11 @@ impl Chip8CPU {
     fn op_exa1(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        // TODO: Implement input
-        let input = 0;
-
-        if self.index_registers[x as usize] != input {
+        if !self
+            .chip8_input
+            .borrow()
+            .is_pressed(self.index_registers[x as usize])
+        {
             self.pc += 2;
         }
     }
@@ -424,15 +563,11 @@ impl Chip8CPU {
     fn op_fx0a(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        // TODO: Implement input
-        let input = 0;
-
-        if input == 0 {
-            self.pc -= 2;
-            return;
+        // Run this instruction again until a key is pressed
+        match self.chip8_input.borrow().first_pressed() {
+            Some(key) => self.index_registers[x as usize] = key,
+            None => self.pc -= 2,
         }
-
-        self.index_registers[x as usize] = input;
     }
 
     // Sets the delay timer to VX.
@@ -478,26 +613,47 @@ impl Chip8CPU {
     }
 
     // Stores V0 to VX (including VX) in memory starting at address I.
-    // The offset from I is increased by 1 for each value written, but I itself is left unmodified.
+    // The offset from I is increased by 1 for each value written, what happens to I depends on the memory quirks.
     fn op_fx55(&mut self) {
-        let memory = &mut self.chip8_memory.borrow_mut();
-
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        for i in 0..x {
-            memory.data[(self.index + i as u16) as usize] = self.index_registers[i as usize];
+        {
+            let memory = &mut self.chip8_memory.borrow_mut();
+
+            for i in 0..=x {
+                memory.data[(self.index + i as u16) as usize] = self.index_registers[i as usize];
+            }
         }
+
+        self.increment_index_after_memory_op(x);
     }
 
     // Fills V0 to VX (including VX) with values from memory starting at address I.
-    // The offset from I is increased by 1 for each value written, but I itself is left unmodified.
+    // The offset from I is increased by 1 for each value written, what happens to I depends on the memory quirks.
     fn op_fx65(&mut self) {
-        let memory = &mut self.chip8_memory.borrow_mut();
-
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        for i in 0..x {
-            self.index_registers[i as usize] = memory.data[(self.index + i as u16) as usize];
+        {
+            let memory = self.chip8_memory.borrow();
+
+            for i in 0..=x {
+                self.index_registers[i as usize] = memory.data[(self.index + i as u16) as usize];
+            }
+        }
+
+        self.increment_index_after_memory_op(x);
+    }
+
+    // The original interpreter leaves I pointing after the last register.
+    fn increment_index_after_memory_op(&mut self, x: u8) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+
+        if self.quirks.memory_increment_by_x {
+            self.index += x as u16;
+        } else {
+            self.index += x as u16 + 1;
         }
     }
 
@@ -505,18 +661,722 @@ impl Chip8CPU {
         rand::random::<u8>()
     }
 
-    pub fn unit_tests(&mut self) {
-        // self.
+    // Whether the opcode is one of the instructions above.
+    // 0NNN (call machine code routine) is not supported, so it does not count.
+    pub fn is_valid_opcode(opcode: u16) -> bool {
+        match opcode & 0xF000 {
+            0x0000 => opcode == 0x00E0 || opcode == 0x00EE,
+            0x5000 | 0x9000 => opcode & 0x000F == 0,
+            0x8000 => matches!(opcode & 0x000F, 0x0..=0x7 | 0xE),
+            0xE000 => matches!(opcode & 0x00FF, 0x9E | 0xA1),
+            0xF000 => matches!(
+                opcode & 0x00FF,
+                0x07 | 0x0A | 0x15 | 0x18 | 0x1E | 0x29 | 0x33 | 0x55 | 0x65
+            ),
+            _ => true,
+        }
     }
 }
 
-// #[test]
-// fn test_new_cpu() {
-//     let cpu = Chip8CPU::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::config::SpriteEdges;
+
+    fn new_cpu() -> Chip8CPU {
+        Chip8CPU::new(
+            Rc::new(RefCell::new(Chip8Memory::new())),
+            Rc::new(RefCell::new(Chip8FrameBuffer::new())),
+            Rc::new(RefCell::new(Chip8Input::new())),
+        )
+    }
+
+    fn new_cpu_with_quirks(quirks: Chip8Quirks) -> Chip8CPU {
+        let mut cpu = new_cpu();
+        cpu.set_quirks(quirks);
+
+        cpu
+    }
+
+    // Write the opcode at the program counter and run a single cycle
+    fn execute(cpu: &mut Chip8CPU, opcode: u16) {
+        {
+            let memory = &mut cpu.chip8_memory.borrow_mut();
+            memory.data[cpu.pc as usize] = (opcode >> 8) as u8;
+            memory.data[cpu.pc as usize + 1] = opcode as u8;
+        }
+
+        cpu.cycle();
+    }
+
+    fn pixel(cpu: &Chip8CPU, x: u8, y: u8) -> bool {
+        cpu.chip8_frame_buffer.borrow().pixel(x, y) == Chip8FrameBuffer::PIXEL_ON
+    }
+
+    const START: u16 = Chip8Memory::START_ADRESS as u16;
+
+    #[test]
+    fn test_new_cpu() {
+        let cpu = new_cpu();
+
+        assert_eq!(cpu.index_registers, [0; 16]);
+        assert_eq!(cpu.pc, START);
+        assert_eq!(cpu.sp, 0x00);
+        assert_eq!(cpu.index, 0x0000);
+    }
+
+    #[test]
+    fn test_00e0_clears_screen() {
+        let mut cpu = new_cpu();
+        cpu.chip8_frame_buffer
+            .borrow_mut()
+            .xor_pixel(3, 4, Chip8FrameBuffer::PIXEL_ON);
+
+        execute(&mut cpu, 0x00E0);
+
+        assert!(cpu
+            .chip8_frame_buffer
+            .borrow()
+            .video_buffer()
+            .iter()
+            .all(|px| *px == 0));
+    }
+
+    #[test]
+    fn test_1nnn_jumps() {
+        let mut cpu = new_cpu();
 
-//     assert_eq!(cpu.index_registers[5], 0 as u8);
-//     assert_eq!(cpu.index_registers[10], 0 as u8);
+        execute(&mut cpu, 0x1ABC);
 
-//     assert_eq!(cpu.pc, Chip8Memory::START_ADRESS as u16);
-//     assert_eq!(cpu.sp, 0x0000);
-// }
+        assert_eq!(cpu.pc, 0x0ABC);
+    }
+
+    #[test]
+    fn test_2nnn_and_00ee_call_and_return() {
+        let mut cpu = new_cpu();
+
+        execute(&mut cpu, 0x2400);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(cpu.sp, 1);
+        assert_eq!(cpu.stack[0], START + 2);
+
+        execute(&mut cpu, 0x2600);
+        assert_eq!(cpu.sp, 2);
+        assert_eq!(cpu.stack[1], 0x0402);
+
+        execute(&mut cpu, 0x00EE);
+        assert_eq!(cpu.pc, 0x0402);
+        assert_eq!(cpu.sp, 1);
+
+        execute(&mut cpu, 0x00EE);
+        assert_eq!(cpu.pc, START + 2);
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn test_skip_instructions() {
+        // (opcode, vx, vy, skipped)
+        let cases = [
+            (0x3142, 0x42, 0x00, true),
+            (0x3142, 0x41, 0x00, false),
+            (0x4142, 0x42, 0x00, false),
+            (0x4142, 0x41, 0x00, true),
+            (0x5120, 0x07, 0x07, true),
+            (0x5120, 0x07, 0x08, false),
+            (0x9120, 0x07, 0x07, false),
+            (0x9120, 0x07, 0x08, true),
+        ];
+
+        for (opcode, vx, vy, skipped) in cases {
+            let mut cpu = new_cpu();
+            cpu.index_registers[1] = vx;
+            cpu.index_registers[2] = vy;
+
+            execute(&mut cpu, opcode);
+
+            let expected = if skipped { START + 4 } else { START + 2 };
+            assert_eq!(cpu.pc, expected, "opcode {:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_6xnn_and_7xnn() {
+        let mut cpu = new_cpu();
+
+        execute(&mut cpu, 0x6AFE);
+        assert_eq!(cpu.index_registers[0xA], 0xFE);
+
+        execute(&mut cpu, 0x7A01);
+        assert_eq!(cpu.index_registers[0xA], 0xFF);
+
+        // Wraps around and leaves VF alone
+        execute(&mut cpu, 0x7A02);
+        assert_eq!(cpu.index_registers[0xA], 0x01);
+        assert_eq!(cpu.index_registers[0xF], 0);
+    }
+
+    #[test]
+    fn test_8xy0_to_8xy3() {
+        // (opcode, vx, vy, result)
+        let cases = [
+            (0x8120, 0b1100, 0b1010, 0b1010),
+            (0x8121, 0b1100, 0b1010, 0b1110),
+            (0x8122, 0b1100, 0b1010, 0b1000),
+            (0x8123, 0b1100, 0b1010, 0b0110),
+        ];
+
+        for (opcode, vx, vy, result) in cases {
+            let mut cpu = new_cpu();
+            cpu.index_registers[1] = vx;
+            cpu.index_registers[2] = vy;
+
+            execute(&mut cpu, opcode);
+
+            assert_eq!(cpu.index_registers[1], result, "opcode {:04X}", opcode);
+            assert_eq!(cpu.index_registers[2], vy, "opcode {:04X}", opcode);
+        }
+    }
+
+    // Runs an 8XY_ opcode on V1 and V2, returns (V1, VF)
+    fn run_8xy(n: u16, vx: u8, vy: u8) -> (u8, u8) {
+        let mut cpu = new_cpu();
+        cpu.index_registers[1] = vx;
+        cpu.index_registers[2] = vy;
+
+        execute(&mut cpu, 0x8120 | n);
+
+        (cpu.index_registers[1], cpu.index_registers[0xF])
+    }
+
+    #[test]
+    fn test_8xy4_carry() {
+        // (vx, vy, result, carry)
+        let cases = [
+            (0x01, 0x02, 0x03, 0),
+            (0xFE, 0x01, 0xFF, 0),
+            (0xFF, 0x01, 0x00, 1),
+            (0x80, 0x80, 0x00, 1),
+            (0xFF, 0xFF, 0xFE, 1),
+        ];
+
+        for (vx, vy, result, carry) in cases {
+            assert_eq!(run_8xy(0x4, vx, vy), (result, carry), "{} + {}", vx, vy);
+        }
+    }
+
+    #[test]
+    fn test_8xy5_borrow() {
+        // (vx, vy, result, no borrow)
+        let cases = [
+            (0x05, 0x03, 0x02, 1),
+            (0x03, 0x05, 0xFE, 0),
+            (0x05, 0x05, 0x00, 1),
+            (0x00, 0x01, 0xFF, 0),
+            (0xFF, 0x00, 0xFF, 1),
+        ];
+
+        for (vx, vy, result, no_borrow) in cases {
+            assert_eq!(run_8xy(0x5, vx, vy), (result, no_borrow), "{} - {}", vx, vy);
+        }
+
+        // The flag depends on the register values, not on the register numbers
+        let mut cpu = new_cpu();
+        cpu.index_registers[3] = 10;
+        cpu.index_registers[1] = 3;
+        execute(&mut cpu, 0x8315);
+        assert_eq!(cpu.index_registers[3], 7);
+        assert_eq!(cpu.index_registers[0xF], 1);
+    }
+
+    #[test]
+    fn test_8xy7_borrow() {
+        // (vx, vy, result, no borrow)
+        let cases = [
+            (0x03, 0x05, 0x02, 1),
+            (0x05, 0x03, 0xFE, 0),
+            (0x05, 0x05, 0x00, 1),
+            (0x01, 0x00, 0xFF, 0),
+        ];
+
+        for (vx, vy, result, no_borrow) in cases {
+            assert_eq!(run_8xy(0x7, vx, vy), (result, no_borrow), "{} - {}", vy, vx);
+        }
+    }
+
+    #[test]
+    fn test_8xy6_and_8xye_shifts() {
+        // (n, vx, result, shifted out bit)
+        let cases = [
+            (0x6, 0b0000_0011, 0b0000_0001, 1),
+            (0x6, 0b0000_0010, 0b0000_0001, 0),
+            (0x6, 0b1000_0000, 0b0100_0000, 0),
+            (0xE, 0b1000_0001, 0b0000_0010, 1),
+            (0xE, 0b0100_0000, 0b1000_0000, 0),
+            (0xE, 0b1111_1111, 0b1111_1110, 1),
+        ];
+
+        for (n, vx, result, flag) in cases {
+            assert_eq!(
+                run_8xy(n, vx, 0),
+                (result, flag),
+                "8XY{:X} on {:08b}",
+                n,
+                vx
+            );
+        }
+    }
+
+    #[test]
+    fn test_8xy_flag_wins_when_vf_is_destination() {
+        // (opcode, vf, vy, expected vf)
+        let cases = [
+            (0x8F14, 0xFF, 0x01, 1),
+            (0x8F14, 0x01, 0x01, 0),
+            (0x8F15, 0x01, 0x02, 0),
+            (0x8F15, 0x02, 0x01, 1),
+            (0x8F16, 0x03, 0x00, 1),
+            (0x8F17, 0x02, 0x01, 0),
+            (0x8F1E, 0x80, 0x00, 1),
+        ];
+
+        for (opcode, vf, vy, expected) in cases {
+            let mut cpu = new_cpu();
+            cpu.index_registers[0xF] = vf;
+            cpu.index_registers[1] = vy;
+
+            execute(&mut cpu, opcode);
+
+            assert_eq!(cpu.index_registers[0xF], expected, "opcode {:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_annn_and_bnnn() {
+        let mut cpu = new_cpu();
+
+        execute(&mut cpu, 0xA123);
+        assert_eq!(cpu.index, 0x0123);
+
+        cpu.index_registers[0] = 0x10;
+        execute(&mut cpu, 0xB300);
+        assert_eq!(cpu.pc, 0x0310);
+    }
+
+    #[test]
+    fn test_cxnn_masks_random_byte() {
+        let mut cpu = new_cpu();
+
+        for _ in 0..32 {
+            execute(&mut cpu, 0xC10F);
+            assert_eq!(cpu.index_registers[1] & 0xF0, 0);
+        }
+
+        execute(&mut cpu, 0xC100);
+        assert_eq!(cpu.index_registers[1], 0);
+    }
+
+    #[test]
+    fn test_dxyn_draws_and_detects_collision() {
+        let mut cpu = new_cpu();
+
+        // Font sprite for 0 at (2, 3)
+        cpu.index = Chip8Memory::FONTSET_START_ADRESS as u16;
+        cpu.index_registers[1] = 2;
+        cpu.index_registers[2] = 3;
+        execute(&mut cpu, 0xD125);
+
+        assert_eq!(cpu.index_registers[0xF], 0);
+        assert_eq!(cpu.index, Chip8Memory::FONTSET_START_ADRESS as u16);
+        // 0xF0 top row, 0x90 second row
+        assert!((2..6).all(|x| pixel(&cpu, x, 3)));
+        assert!(!pixel(&cpu, 6, 3));
+        assert!(pixel(&cpu, 2, 4) && !pixel(&cpu, 3, 4) && pixel(&cpu, 5, 4));
+
+        // Drawing it again erases it and sets VF
+        execute(&mut cpu, 0xD125);
+        assert_eq!(cpu.index_registers[0xF], 1);
+        assert!(cpu
+            .chip8_frame_buffer
+            .borrow()
+            .video_buffer()
+            .iter()
+            .all(|px| *px == 0));
+    }
+
+    #[test]
+    fn test_dxyn_wraps_start_and_clips_edges() {
+        let mut cpu = new_cpu();
+        cpu.chip8_memory.borrow_mut().data[0x300] = 0xFF;
+        cpu.index = 0x300;
+
+        // Start position wraps around the screen
+        cpu.index_registers[1] = 64 + 1;
+        cpu.index_registers[2] = 32 + 2;
+        execute(&mut cpu, 0xD121);
+        assert!((1..9).all(|x| pixel(&cpu, x, 2)));
+
+        // Pixels past the right edge are clipped, not wrapped
+        execute(&mut cpu, 0x00E0);
+        cpu.index_registers[1] = 60;
+        cpu.index_registers[2] = 0;
+        execute(&mut cpu, 0xD121);
+        assert!((60..64).all(|x| pixel(&cpu, x, 0)));
+        assert!(!pixel(&cpu, 0, 0));
+        assert_eq!(cpu.index_registers[0xF], 0);
+    }
+
+    #[test]
+    fn test_dxyn_display_wait() {
+        let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+            display_wait: true,
+            ..Chip8Quirks::default()
+        });
+        cpu.chip8_memory.borrow_mut().data[0x300] = 0x80;
+        cpu.index = 0x300;
+
+        execute(&mut cpu, 0xD011);
+        assert!(pixel(&cpu, 0, 0));
+        assert!(cpu.is_waiting_for_vblank());
+
+        // Nothing runs until the frame ends
+        execute(&mut cpu, 0x6042);
+        assert_eq!(cpu.index_registers[0], 0);
+        assert_eq!(cpu.pc, START + 2);
+
+        cpu.vblank();
+        execute(&mut cpu, 0x6042);
+        assert_eq!(cpu.index_registers[0], 0x42);
+
+        // Without the quirk drawing doesn't stall
+        let mut cpu = new_cpu();
+        execute(&mut cpu, 0xD011);
+        assert!(!cpu.is_waiting_for_vblank());
+    }
+
+    #[test]
+    fn test_dxyn_wraps_edges() {
+        let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+            edges: SpriteEdges {
+                horizontal: EdgeMode::Wrap,
+                vertical: EdgeMode::Clip,
+            },
+            ..Chip8Quirks::default()
+        });
+        cpu.chip8_memory.borrow_mut().data[0x300..0x302].copy_from_slice(&[0xFF, 0xFF]);
+        cpu.index = 0x300;
+        cpu.index_registers[1] = 60;
+        cpu.index_registers[2] = 31;
+
+        execute(&mut cpu, 0xD122);
+        assert!((60..64).chain(0..4).all(|x| pixel(&cpu, x, 31)));
+        assert!(!pixel(&cpu, 4, 31));
+        // Only the horizontal edge wraps
+        assert!(!pixel(&cpu, 60, 0));
+
+        cpu.set_quirks(Chip8Quirks {
+            edges: SpriteEdges::WRAP,
+            ..Chip8Quirks::default()
+        });
+        execute(&mut cpu, 0xD122);
+        assert!(pixel(&cpu, 0, 0));
+        assert!(!pixel(&cpu, 0, 31));
+        assert_eq!(cpu.index_registers[0xF], 1);
+    }
+
+    #[test]
+    fn test_dxy0_large_sprites() {
+        let mut cpu = new_cpu();
+        {
+            let memory = &mut cpu.chip8_memory.borrow_mut();
+            for byte in 0..32 {
+                memory.data[0x300 + byte] = 0xFF;
+            }
+        }
+        cpu.index = 0x300;
+        cpu.index_registers[1] = 56;
+        cpu.index_registers[2] = 24;
+
+        // Without the quirk nothing is drawn
+        execute(&mut cpu, 0xD120);
+        assert!(!pixel(&cpu, 56, 24));
+
+        cpu.set_quirks(Chip8Quirks {
+            large_sprites: true,
+            ..Chip8Quirks::default()
+        });
+        execute(&mut cpu, 0xD120);
+        assert!((56..64).all(|x| pixel(&cpu, x, 24) && pixel(&cpu, x, 31)));
+        // Clipped on both edges
+        assert!(!pixel(&cpu, 0, 24));
+        assert!(!pixel(&cpu, 56, 0));
+
+        cpu.set_quirks(Chip8Quirks {
+            large_sprites: true,
+            edges: SpriteEdges::WRAP,
+            ..Chip8Quirks::default()
+        });
+        execute(&mut cpu, 0x00E0);
+        execute(&mut cpu, 0xD120);
+        assert!((56..64)
+            .chain(0..8)
+            .all(|x| pixel(&cpu, x, 24) && pixel(&cpu, x, 7)));
+        assert!(!pixel(&cpu, 8, 0));
+        assert!(!pixel(&cpu, 0, 8));
+    }
+
+    #[test]
+    fn test_ex9e_and_exa1_keys() {
+        // (opcode, key in vx, pressed key, skipped)
+        let cases = [
+            (0xE19E, 0x5, None, false),
+            (0xE19E, 0x5, Some(0x5), true),
+            (0xE19E, 0x5, Some(0x6), false),
+            (0xE19E, 0x0, None, false),
+            (0xE1A1, 0x5, None, true),
+            (0xE1A1, 0x5, Some(0x5), false),
+            (0xE1A1, 0x5, Some(0x6), true),
+            (0xE1A1, 0x0, None, true),
+        ];
+
+        for (opcode, vx, pressed, skipped) in cases {
+            let mut cpu = new_cpu();
+            cpu.index_registers[1] = vx;
+            if let Some(key) = pressed {
+                cpu.chip8_input.borrow_mut().set_key(key, true);
+            }
+
+            execute(&mut cpu, opcode);
+
+            let expected = if skipped { START + 4 } else { START + 2 };
+            assert_eq!(cpu.pc, expected, "opcode {:04X} {:?}", opcode, pressed);
+        }
+    }
+
+    #[test]
+    fn test_fx0a_waits_for_key() {
+        let mut cpu = new_cpu();
+
+        execute(&mut cpu, 0xF30A);
+        assert_eq!(cpu.pc, START);
+
+        execute(&mut cpu, 0xF30A);
+        assert_eq!(cpu.pc, START);
+
+        cpu.chip8_input.borrow_mut().set_key(0x0, true);
+        execute(&mut cpu, 0xF30A);
+        assert_eq!(cpu.pc, START + 2);
+        assert_eq!(cpu.index_registers[3], 0x0);
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut cpu = new_cpu();
+        cpu.index_registers[1] = 3;
+
+        execute(&mut cpu, 0xF115);
+        execute(&mut cpu, 0xF118);
+        assert_eq!(cpu.delay_timer, 3);
+        assert_eq!(cpu.sound_timer, 3);
+
+        // Instructions don't tick the timers, frames do
+        cpu.tick_timers();
+        assert_eq!(cpu.delay_timer, 2);
+        assert_eq!(cpu.sound_timer, 2);
+
+        execute(&mut cpu, 0xF207);
+        assert_eq!(cpu.index_registers[2], 2);
+
+        // And stop at zero
+        for _ in 0..4 {
+            cpu.tick_timers();
+        }
+        assert_eq!(cpu.delay_timer, 0);
+        assert_eq!(cpu.sound_timer, 0);
+    }
+
+    #[test]
+    fn test_fx1e_and_fx29() {
+        let mut cpu = new_cpu();
+        cpu.index = 0x100;
+        cpu.index_registers[1] = 0x20;
+
+        execute(&mut cpu, 0xF11E);
+        assert_eq!(cpu.index, 0x120);
+        assert_eq!(cpu.index_registers[0xF], 0);
+
+        cpu.index_registers[1] = 0xA;
+        execute(&mut cpu, 0xF129);
+        assert_eq!(
+            cpu.index,
+            Chip8Memory::FONTSET_START_ADRESS as u16 + 0xA * 5
+        );
+    }
+
+    #[test]
+    fn test_fx33_bcd() {
+        let cases = [
+            (0, [0, 0, 0]),
+            (7, [0, 0, 7]),
+            (42, [0, 4, 2]),
+            (100, [1, 0, 0]),
+            (255, [2, 5, 5]),
+        ];
+
+        for (value, digits) in cases {
+            let mut cpu = new_cpu();
+            cpu.index = 0x300;
+            cpu.index_registers[4] = value;
+
+            execute(&mut cpu, 0xF433);
+
+            assert_eq!(
+                cpu.chip8_memory.borrow().data[0x300..0x303],
+                digits,
+                "BCD of {}",
+                value
+            );
+            assert_eq!(cpu.index, 0x300);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_opcode() {
+        let valid = [
+            0x00E0, 0x00EE, 0x1200, 0x2FFF, 0x3000, 0x4123, 0x5120, 0x6000, 0x7FFF, 0x812E, 0x9120,
+            0xA000, 0xB000, 0xC0FF, 0xD125, 0xE19E, 0xE1A1, 0xF107, 0xF165,
+        ];
+        let invalid = [
+            0x0000, 0x0123, 0x00E1, 0x5121, 0x8128, 0x812F, 0x9121, 0xE19F, 0xF100, 0xF1FF,
+        ];
+
+        for opcode in valid {
+            assert!(Chip8CPU::is_valid_opcode(opcode), "{:04X}", opcode);
+        }
+        for opcode in invalid {
+            assert!(!Chip8CPU::is_valid_opcode(opcode), "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_fx55_and_fx65_include_vx() {
+        for x in [0x0_u8, 0x3, 0xF] {
+            let mut cpu = new_cpu();
+            cpu.index = 0x300;
+            for i in 0..16 {
+                cpu.index_registers[i] = 0xA0 + i as u8;
+            }
+
+            execute(&mut cpu, 0xF055 | (x as u16) << 8);
+
+            {
+                let memory = cpu.chip8_memory.borrow();
+                for i in 0..16 {
+                    let expected = if i <= x as usize { 0xA0 + i as u8 } else { 0 };
+                    assert_eq!(memory.data[0x300 + i], expected, "FX55 x={:X} i={:X}", x, i);
+                }
+            }
+            assert_eq!(cpu.index, 0x300);
+
+            cpu.index_registers = [0; 16];
+            execute(&mut cpu, 0xF065 | (x as u16) << 8);
+
+            for i in 0..16 {
+                let expected = if i <= x as usize { 0xA0 + i as u8 } else { 0 };
+                assert_eq!(cpu.index_registers[i], expected, "FX65 x={:X} i={:X}", x, i);
+            }
+            assert_eq!(cpu.index, 0x300);
+        }
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        // (shift quirk, opcode, result, flag)
+        let cases = [
+            (true, 0x8126, 0b0000_0001, 1),
+            (false, 0x8126, 0b0100_0100, 0),
+            (true, 0x812E, 0b0000_0110, 0),
+            (false, 0x812E, 0b0001_0000, 1),
+        ];
+
+        for (shift, opcode, result, flag) in cases {
+            let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+                shift,
+                ..Chip8Quirks::default()
+            });
+            cpu.index_registers[1] = 0b0000_0011;
+            cpu.index_registers[2] = 0b1000_1000;
+
+            execute(&mut cpu, opcode);
+
+            assert_eq!(
+                cpu.index_registers[1], result,
+                "{:04X} shift={}",
+                opcode, shift
+            );
+            assert_eq!(
+                cpu.index_registers[0xF], flag,
+                "{:04X} shift={}",
+                opcode, shift
+            );
+        }
+    }
+
+    #[test]
+    fn test_logic_quirk_resets_vf() {
+        for opcode in [0x8121, 0x8122, 0x8123] {
+            for logic in [false, true] {
+                let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+                    logic,
+                    ..Chip8Quirks::default()
+                });
+                cpu.index_registers[0xF] = 5;
+
+                execute(&mut cpu, opcode);
+
+                let expected = if logic { 0 } else { 5 };
+                assert_eq!(cpu.index_registers[0xF], expected, "{:04X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        for (jump, expected) in [(false, 0x0310), (true, 0x0320)] {
+            let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+                jump,
+                ..Chip8Quirks::default()
+            });
+            cpu.index_registers[0] = 0x10;
+            cpu.index_registers[3] = 0x20;
+
+            execute(&mut cpu, 0xB300);
+
+            assert_eq!(cpu.pc, expected, "jump={}", jump);
+        }
+    }
+
+    #[test]
+    fn test_memory_quirks() {
+        // (increment by x, leave I unchanged, I after F255 and F265)
+        let cases = [
+            (false, true, 0x300),
+            (false, false, 0x303),
+            (true, false, 0x302),
+        ];
+
+        for (memory_increment_by_x, memory_leave_i_unchanged, index) in cases {
+            for opcode in [0xF255, 0xF265] {
+                let mut cpu = new_cpu_with_quirks(Chip8Quirks {
+                    memory_increment_by_x,
+                    memory_leave_i_unchanged,
+                    ..Chip8Quirks::default()
+                });
+                cpu.index = 0x300;
+
+                execute(&mut cpu, opcode);
+
+                assert_eq!(cpu.index, index, "{:04X}", opcode);
+            }
+        }
+    }
+}
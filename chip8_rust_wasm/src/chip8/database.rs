@@ -0,0 +1,355 @@
+// Identifies ROMs by their SHA-1 in a database using the format of the community CHIP-8 database,
+// https://github.com/chip-8/chip-8-database. The bundled copy lives in `database/`.
+use std::{collections::HashMap, sync::OnceLock};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use wasm_bindgen::prelude::*;
+
+use super::{
+    config::{Chip8Config, Chip8Platform, Rgb, SpriteEdges},
+    keyboard_input::Chip8KeyMap,
+};
+
+const BUNDLED_PROGRAMS: &str = include_str!("../../database/programs.json");
+const BUNDLED_HASHES: &str = include_str!("../../database/sha1-hashes.json");
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    description: Option<String>,
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    keys: Option<HashMap<String, u8>>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+    vblank: Option<bool>,
+    wrap: Option<bool>,
+}
+
+// What the database knows about a ROM, for showing in the UI.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    sha1: String,
+    title: String,
+    authors: Vec<String>,
+    description: Option<String>,
+    release: Option<String>,
+    platform: Option<Chip8Platform>,
+}
+
+#[wasm_bindgen]
+impl RomInfo {
+    #[wasm_bindgen(getter)]
+    pub fn sha1(&self) -> String {
+        self.sha1.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn authors(&self) -> js_sys::Array {
+        self.authors
+            .iter()
+            .map(|author| JsValue::from_str(author))
+            .collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn description(&self) -> Option<String> {
+        self.description.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn release(&self) -> Option<String> {
+        self.release.clone()
+    }
+
+    // Database id of the platform the settings are for, e.g. "originalChip8"
+    #[wasm_bindgen(getter)]
+    pub fn platform(&self) -> Option<String> {
+        self.platform.map(|platform| platform.id().to_string())
+    }
+}
+
+impl RomInfo {
+    pub fn author_list(&self) -> &[String] {
+        &self.authors
+    }
+}
+
+pub struct Chip8Database {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Program>,
+}
+
+impl Chip8Database {
+    pub fn from_json(programs_json: &str, hashes_json: &str) -> serde_json::Result<Chip8Database> {
+        Ok(Chip8Database {
+            programs: serde_json::from_str(programs_json)?,
+            hashes: serde_json::from_str(hashes_json)?,
+        })
+    }
+
+    // The database compiled into the emulator, parsed the first time it is used.
+    pub fn bundled() -> &'static Chip8Database {
+        static DATABASE: OnceLock<Chip8Database> = OnceLock::new();
+
+        DATABASE.get_or_init(|| {
+            Chip8Database::from_json(BUNDLED_PROGRAMS, BUNDLED_HASHES)
+                .expect("Bundled ROM database is not valid JSON")
+        })
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<(RomInfo, Chip8Config)> {
+        self.lookup_hash(&sha1_hex(rom))
+    }
+
+    pub fn lookup_hash(&self, sha1: &str) -> Option<(RomInfo, Chip8Config)> {
+        let program = self.programs.get(*self.hashes.get(sha1)?)?;
+        let entry = program.roms.get(sha1)?;
+
+        // First platform the emulator knows about, the database lists the preferred one first
+        let platform = entry
+            .platforms
+            .iter()
+            .find_map(|id| Chip8Platform::from_id(id));
+
+        let info = RomInfo {
+            sha1: sha1.to_string(),
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            description: program.description.clone(),
+            release: program.release.clone(),
+            platform,
+        };
+
+        Some((info, entry.config(platform)))
+    }
+}
+
+impl RomEntry {
+    fn config(&self, platform: Option<Chip8Platform>) -> Chip8Config {
+        let mut config = Chip8Config::new();
+        config.platform = platform;
+
+        if let Some(platform) = platform {
+            config.quirks = platform.quirks();
+
+            if let Some(overrides) = self.quirky_platforms.get(platform.id()) {
+                let quirks = &mut config.quirks;
+                quirks.shift = overrides.shift.unwrap_or(quirks.shift);
+                quirks.memory_increment_by_x = overrides
+                    .memory_increment_by_x
+                    .unwrap_or(quirks.memory_increment_by_x);
+                quirks.memory_leave_i_unchanged = overrides
+                    .memory_leave_i_unchanged
+                    .unwrap_or(quirks.memory_leave_i_unchanged);
+                quirks.jump = overrides.jump.unwrap_or(quirks.jump);
+                quirks.logic = overrides.logic.unwrap_or(quirks.logic);
+                quirks.display_wait = overrides.vblank.unwrap_or(quirks.display_wait);
+                quirks.edges = match overrides.wrap {
+                    Some(true) => SpriteEdges::WRAP,
+                    Some(false) => SpriteEdges::CLIP,
+                    None => quirks.edges,
+                };
+            }
+        }
+
+        if let Some(tick_rate) = self.tickrate {
+            config.tick_rate = tick_rate;
+        }
+
+        if let Some(keys) = &self.keys {
+            let key = |name: &str| keys.get(name).map(|key| key & 0x0F);
+
+            config.key_map = Chip8KeyMap {
+                up: key("up"),
+                down: key("down"),
+                left: key("left"),
+                right: key("right"),
+                a: key("a"),
+                b: key("b"),
+            };
+        }
+
+        if let Some(colors) = &self.colors {
+            let palette: Option<Vec<Rgb>> =
+                colors.pixels.iter().map(|hex| parse_color(hex)).collect();
+
+            match palette {
+                Some(palette) if palette.len() >= 2 => config.palette = palette,
+                _ => {}
+            }
+        }
+
+        config
+    }
+}
+
+pub fn sha1_hex(rom: &[u8]) -> String {
+    Sha1::digest(rom)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// "#rrggbb" to rgb
+pub fn parse_color(hex: &str) -> Option<Rgb> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::config::Chip8Quirks;
+    use crate::chip8::rom;
+
+    const TETRIS: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/test_stuff/Tetris [Fran Dachille, 1991].ch8"
+    );
+    const TEST_ROM: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/test_stuff/chip8_test1.ch8"
+    );
+
+    #[test]
+    fn test_sha1_hex() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#ff8000"), Some([0xFF, 0x80, 0x00]));
+        assert_eq!(parse_color("0a0B0c"), Some([0x0A, 0x0B, 0x0C]));
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("#gg0000"), None);
+    }
+
+    #[test]
+    fn test_bundled_database_identifies_tetris() {
+        let bytes = rom::read_path(TETRIS).unwrap();
+
+        let (info, config) = Chip8Database::bundled().lookup(&bytes).unwrap();
+
+        assert_eq!(info.title, "Tetris");
+        assert_eq!(info.author_list(), ["Fran Dachille"]);
+        assert_eq!(info.platform, Some(Chip8Platform::OriginalChip8));
+        assert_eq!(config.tick_rate, 15);
+        assert_eq!(config.key_map.left, Some(5));
+        assert_eq!(config.key_map.up, None);
+        assert_eq!(config.palette, vec![[0, 0, 0], [0xFF, 0xFF, 0xFF]]);
+        // The original interpreter quirks, with the rom's override
+        assert_eq!(
+            config.quirks,
+            Chip8Quirks {
+                logic: false,
+                ..Chip8Platform::OriginalChip8.quirks()
+            }
+        );
+    }
+
+    #[test]
+    fn test_bundled_database_identifies_the_test_roms() {
+        for (path, title) in [(TETRIS, "Tetris"), (TEST_ROM, "CHIP-8 Test ROM")] {
+            let bytes = rom::read_path(path).unwrap();
+            let (info, _) = Chip8Database::bundled().lookup(&bytes).unwrap();
+
+            assert_eq!(info.title, title);
+            assert_eq!(info.sha1, sha1_hex(&bytes));
+        }
+    }
+
+    // Still holds after dropping in the community database with `database/update.sh`
+    #[test]
+    fn test_bundled_hashes_all_lead_to_a_rom() {
+        let database = Chip8Database::bundled();
+
+        assert!(!database.hashes.is_empty());
+        for hash in database.hashes.keys() {
+            assert!(
+                database.lookup_hash(hash).is_some(),
+                "{} is not in programs.json",
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_rom() {
+        assert!(Chip8Database::bundled().lookup(&[0x12, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_database_format() {
+        let programs = r##"[
+            {
+                "title": "Some Game",
+                "authors": ["Someone", "Someone Else"],
+                "origin": { "type": "gamejam" },
+                "roms": {
+                    "0123": {
+                        "file": "game.ch8",
+                        "platforms": ["superchip8x", "chip48", "originalChip8"],
+                        "fontStyle": "octo",
+                        "colors": { "pixels": ["#112233", "nope"], "buzzer": "#ffffff" }
+                    }
+                }
+            }
+        ]"##;
+        let hashes = r#"{ "0123": 0, "4567": 5 }"#;
+
+        let database = Chip8Database::from_json(programs, hashes).unwrap();
+        let (info, config) = database.lookup_hash("0123").unwrap();
+
+        assert_eq!(info.description, None);
+        assert_eq!(info.platform, Some(Chip8Platform::Chip48));
+        assert_eq!(config.quirks, Chip8Platform::Chip48.quirks());
+        assert_eq!(config.tick_rate, Chip8Config::DEFAULT_TICK_RATE);
+        // Palette with an invalid colour is ignored
+        assert_eq!(config.palette, Chip8Config::new().palette);
+        // Hash pointing past the programs
+        assert!(database.lookup_hash("4567").is_none());
+    }
+}
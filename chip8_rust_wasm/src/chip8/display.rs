@@ -1,46 +1,263 @@
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{
-    console, WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader, WebGlTexture,
+    HtmlCanvasElement, OffscreenCanvas, WebGl2RenderingContext, WebGlProgram, WebGlShader,
+    WebGlTexture, WebGlUniformLocation, WebglLoseContext,
 };
 
+use super::canvas_display::Chip8CanvasDisplay;
+use super::config::{Chip8Config, Rgb, ScalingMode};
+use super::frame_buffer::Chip8FrameBuffer;
+
+// What the emulator needs from a renderer, so it can fall back to another one.
+pub trait Chip8Display {
+    // Background first, then the colours for the pixel values 1, 2, 3...
+    // Values past the end of the palette get its last colour.
+    fn set_palette(&mut self, palette: &[Rgb]);
+    fn set_scaling(&mut self, scaling: ScalingMode);
+    // Draw (palette index, intensity) pairs in video buffer order to the screen
+    fn draw(&mut self, texels: &[u8]);
+    // The picture on the canvas is outdated even if the frame didn't change, e.g. after a
+    // palette change or a resize
+    fn needs_redraw(&self) -> bool;
+    fn name(&self) -> &'static str;
+}
+
+// Draws nothing, for running without a browser
+pub struct Chip8NullDisplay;
+
+impl Chip8Display for Chip8NullDisplay {
+    fn set_palette(&mut self, _palette: &[Rgb]) {}
+
+    fn set_scaling(&mut self, _scaling: ScalingMode) {}
+
+    fn draw(&mut self, _texels: &[u8]) {}
+
+    fn needs_redraw(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+}
+
+// WebGL2 if the browser can do it, a 2D canvas otherwise.
+pub fn create(target: &DisplayTarget) -> Result<Box<dyn Chip8Display>, String> {
+    match target {
+        DisplayTarget::Canvas(canvas) => {
+            // A canvas that handed out a webgl2 context won't give a 2d one anymore, so ask a
+            // scratch canvas first
+            match probe_webgl2() {
+                Ok(()) => Ok(Box::new(Chip8WebGLDisplay::new(target.clone())?)),
+                Err(err) => {
+                    web_sys::console::warn_1(&JsValue::from_str(&format!(
+                        "WebGL2 is not available ({}), drawing with a 2D canvas instead",
+                        err
+                    )));
+                    Ok(Box::new(Chip8CanvasDisplay::new(canvas.clone())?))
+                }
+            }
+        }
+        DisplayTarget::Offscreen(_) => match Chip8WebGLDisplay::new(target.clone()) {
+            Ok(display) => Ok(Box::new(display)),
+            Err(err) => Err(format!(
+                "Drawing to an OffscreenCanvas needs WebGL2: {}",
+                err
+            )),
+        },
+    }
+}
+
+// Whether the browser hands out WebGL2 contexts. The context is lost right away, browsers
+// only keep a few of them alive and the display needs its own.
+fn probe_webgl2() -> Result<(), String> {
+    let context = scratch_canvas()?
+        .get_context("webgl2")
+        .ok()
+        .flatten()
+        .ok_or_else(|| String::from("no webgl2 context"))?
+        .dyn_into::<WebGl2RenderingContext>()
+        .map_err(|_| String::from("Dynamic Cast Fail"))?;
+
+    if let Ok(Some(extension)) = context.get_extension("WEBGL_lose_context") {
+        extension
+            .unchecked_into::<WebglLoseContext>()
+            .lose_context();
+    }
+
+    Ok(())
+}
+
+// A canvas that isn't on the page
+pub fn scratch_canvas() -> Result<HtmlCanvasElement, String> {
+    web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or_else(|| String::from("No document to create a canvas in"))?
+        .create_element("canvas")
+        .map_err(|_| String::from("Failed creating a canvas"))?
+        .dyn_into::<HtmlCanvasElement>()
+        .map_err(|_| String::from("Dynamic Cast Fail"))
+}
+
+// What a display draws to, every emulator on a page has its own.
+#[derive(Clone)]
+pub enum DisplayTarget {
+    Canvas(HtmlCanvasElement),
+    // Transferred to a worker, or made without a page
+    Offscreen(OffscreenCanvas),
+}
+
+impl DisplayTarget {
+    // A canvas element or an OffscreenCanvas handed over from JS
+    pub fn from_js(value: JsValue) -> Result<DisplayTarget, String> {
+        match value.dyn_into::<HtmlCanvasElement>() {
+            Ok(canvas) => Ok(DisplayTarget::Canvas(canvas)),
+            Err(value) => value
+                .dyn_into::<OffscreenCanvas>()
+                .map(DisplayTarget::Offscreen)
+                .map_err(|_| String::from("Expected a canvas element or an OffscreenCanvas")),
+        }
+    }
+
+    fn context(&self, context_id: &str) -> Result<Option<js_sys::Object>, JsValue> {
+        match self {
+            DisplayTarget::Canvas(canvas) => canvas.get_context(context_id),
+            DisplayTarget::Offscreen(canvas) => canvas.get_context(context_id),
+        }
+    }
+
+    fn size(&self) -> (u32, u32) {
+        match self {
+            DisplayTarget::Canvas(canvas) => (canvas.width(), canvas.height()),
+            DisplayTarget::Offscreen(canvas) => (canvas.width(), canvas.height()),
+        }
+    }
+
+    fn set_size(&self, (width, height): (u32, u32)) {
+        match self {
+            DisplayTarget::Canvas(canvas) => {
+                canvas.set_width(width);
+                canvas.set_height(height);
+            }
+            DisplayTarget::Offscreen(canvas) => {
+                canvas.set_width(width);
+                canvas.set_height(height);
+            }
+        }
+    }
+
+    // The resolution it should have, None if it isn't on the page to measure.
+    // The page sizes an OffscreenCanvas itself.
+    fn device_size(&self) -> Option<(u32, u32)> {
+        match self {
+            DisplayTarget::Canvas(canvas) => canvas_device_size(canvas),
+            DisplayTarget::Offscreen(_) => None,
+        }
+    }
+}
+
+// Size of the canvas on the page in device pixels, None if it isn't laid out (e.g. hidden).
+pub fn canvas_device_size(canvas: &HtmlCanvasElement) -> Option<(u32, u32)> {
+    let pixel_ratio = web_sys::window().unwrap().device_pixel_ratio();
+
+    if canvas.client_width() <= 0 || canvas.client_height() <= 0 {
+        return None;
+    }
+
+    Some((
+        (canvas.client_width() as f64 * pixel_ratio).round() as u32,
+        (canvas.client_height() as f64 * pixel_ratio).round() as u32,
+    ))
+}
+
+// The palette with a colour for every possible pixel value
+pub fn full_palette(palette: &[Rgb]) -> [Rgb; Chip8Config::MAX_COLORS] {
+    let last = palette.last().copied().unwrap_or([0xFF, 0xFF, 0xFF]);
+    let mut colors = [last; Chip8Config::MAX_COLORS];
+
+    for (color, palette_color) in colors.iter_mut().zip(palette) {
+        *color = *palette_color;
+    }
+
+    colors
+}
+
+// RGBA pixels, top row first, for (palette index, intensity) pairs in video buffer order.
+// Does what the WebGL shader does.
+pub fn texels_to_rgba(texels: &[u8], palette: &[Rgb]) -> Vec<u8> {
+    let colors = full_palette(palette);
+    let width = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as usize;
+
+    // The video buffer is stored bottom row first
+    texels
+        .chunks(width * 2)
+        .rev()
+        .flat_map(|row| row.chunks(2))
+        .flat_map(|texel| {
+            let color = colors[(texel[0] & 0x0F) as usize];
+            let intensity = texel[1] as u32;
+            let mix = |i: usize| {
+                ((colors[0][i] as u32 * (255 - intensity) + color[i] as u32 * intensity + 127)
+                    / 255) as u8
+            };
+
+            [mix(0), mix(1), mix(2), 0xFF]
+        })
+        .collect()
+}
+
 pub struct Chip8WebGLDisplay {
+    canvas: DisplayTarget,
     gl: WebGl2RenderingContext,
     render_texture: Option<WebGlTexture>,
     // gl_video_buffer: WebGlBuffer,
-    pub video_buffer: [u8; 64 * 32],
     program: WebGlProgram,
+    palette_location: Option<WebGlUniformLocation>,
+    image_rect_location: Option<WebGlUniformLocation>,
+    background: Rgb,
+    scaling: ScalingMode,
+    // Canvas size in device pixels the image was last laid out for
+    canvas_size: (u32, u32),
+    stale: bool,
 }
 
-impl Chip8WebGLDisplay {
-    pub const CHIP8_DISPLAY_HEIGHT: u8 = 32;
-    pub const CHIP8_DISPLAY_WIDTH: u8 = 64;
-
-    // Initialize WebGL environment
-    pub fn new(canvas_name: &str) -> Chip8WebGLDisplay {
-        let document = web_sys::window().unwrap().document().unwrap();
-        let canvas = match document.get_element_by_id(canvas_name) {
-            Some(element) => element,
-            None => panic!("No canvas element found with the id provided for the chip8 display"),
-        };
-
-        let canvas = canvas.dyn_into::<web_sys::HtmlCanvasElement>();
-
-        let canvas = match canvas {
-            Ok(val) => val,
-            Err(err) => {
-                panic!(
-                    "Err: {:?}, could not find a canvas with the corresponding id: {}",
-                    &err, canvas_name
-                );
-            }
-        };
+// Where the image goes on a canvas of the given size, as (x, y, width, height) in pixels.
+// Keeps the 2:1 aspect ratio and centers the image, the rest of the canvas is letterbox.
+pub fn image_rect(
+    canvas_width: u32,
+    canvas_height: u32,
+    scaling: ScalingMode,
+) -> (u32, u32, u32, u32) {
+    let width = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as f64;
+    let height = Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as f64;
+
+    let scale = (canvas_width as f64 / width).min(canvas_height as f64 / height);
+    let scale = match scaling {
+        ScalingMode::Fit => scale,
+        // Every chip8 pixel gets the same number of screen pixels
+        ScalingMode::Integer => scale.floor().max(1.0),
+    };
+
+    let image_width = (width * scale).round() as u32;
+    let image_height = (height * scale).round() as u32;
+
+    (
+        canvas_width.saturating_sub(image_width) / 2,
+        canvas_height.saturating_sub(image_height) / 2,
+        image_width,
+        image_height,
+    )
+}
 
+impl Chip8WebGLDisplay {
+    // Initialize WebGL environment, fails if the browser has no WebGL2
+    pub fn new(canvas: DisplayTarget) -> Result<Chip8WebGLDisplay, String> {
         let gl = canvas
-            .get_context("webgl2")
-            .expect("Failed finding WebGL context")
-            .expect("Failed to find a webgl context!")
+            .context("webgl2")
+            .map_err(|_| String::from("Failed finding WebGL context"))?
+            .ok_or_else(|| String::from("Failed to find a webgl context!"))?
             .dyn_into::<WebGl2RenderingContext>()
-            .expect("Dynamic Cast Fail");
+            .map_err(|_| String::from("Dynamic Cast Fail"))?;
 
         let vert_shader = compile_shader(
             &gl,
@@ -48,13 +265,18 @@ impl Chip8WebGLDisplay {
             r##"#version 300 es
 
             in vec4 position;
-    
+            // Scale and offset in clip space that put the image where it goes on the canvas
+            uniform vec4 uImageRect;
+
+            out vec2 vTexCoord;
+
             void main() {
-                gl_Position = position;
+                vTexCoord = position.xy * 0.5 + 0.5;
+                gl_Position = vec4(position.xy * uImageRect.xy + uImageRect.zw, 0.0, 1.0);
             }
             "##,
         )
-        .expect("Chip8Display vertex shader compilation error");
+        .map_err(|err| format!("Chip8Display vertex shader compilation error: {}", err))?;
 
         let frag_shader = compile_shader(
             &gl,
@@ -62,42 +284,51 @@ impl Chip8WebGLDisplay {
             r##"#version 300 es
             precision highp float;
             
+            // Palette index and intensity of every pixel
             uniform sampler2D uSampler;
+            // Colour for every palette index the video buffer can hold
+            uniform vec3 uPalette[16];
 
+            in vec2 vTexCoord;
             out vec4 frag;
-            // in vec4 gl_FragCoord;
 
             void main() {
-                // 800 px (canvas) / 64 px (video buffer) = 12.5 (scaling)
-                float x = 1.0 / 64.0 * gl_FragCoord.x / 12.5;
-                float y = 1.0 / 32.0 * gl_FragCoord.y / 12.5;
-                vec2 some_pos = vec2(x, y);
-                float sample_pix = texture(uSampler, some_pos).x;
+                vec2 sample_pix = texture(uSampler, vTexCoord).xy;
+                int index = int(round(sample_pix.x * 255.0)) & 15;
 
-                frag = vec4(0.0, 0.0, 0.0, sample_pix);
+                // Fading pixels blend into the background
+                frag = vec4(mix(uPalette[0], uPalette[index], sample_pix.y), 1.0);
             }
             "##,
         )
-        .expect("Chip8Display fragment shader compilation error");
+        .map_err(|err| format!("Chip8Display fragment shader compilation error: {}", err))?;
 
         let program = link_shader_program(&gl, &vert_shader, &frag_shader)
-            .expect("Failed linking shaders to WebGL");
+            .map_err(|err| format!("Failed linking shaders to WebGL: {}", err))?;
         gl.use_program(Some(&program));
 
-        let video_buffer = [0; 64 * 32];
+        let palette_location = gl.get_uniform_location(&program, "uPalette");
+        let image_rect_location = gl.get_uniform_location(&program, "uImageRect");
 
         let mut display = Chip8WebGLDisplay {
             render_texture: None,
-            video_buffer: video_buffer,
-            gl: gl,
-            program: program,
+            canvas,
+            gl,
+            program,
+            palette_location,
+            image_rect_location,
+            background: [0, 0, 0],
+            scaling: ScalingMode::Fit,
+            canvas_size: (0, 0),
+            stale: true,
         };
 
+        display.set_palette(&Chip8Config::new().palette);
         display
             .init_buffers()
-            .expect("Failed initializing WebGL program buffers");
+            .map_err(|err| format!("Failed initializing WebGL program buffers: {:?}", err))?;
 
-        display
+        Ok(display)
     }
 
     // fn test(self) {}
@@ -112,8 +343,8 @@ impl Chip8WebGLDisplay {
         ];
 
         // ATTRIBUTES
-        let position_attribute_location = gl.get_attrib_location(&program, "position");
-        let u_sampler_attrib = gl.get_uniform_location(&program, "uSampler");
+        let position_attribute_location = gl.get_attrib_location(program, "position");
+        let u_sampler_attrib = gl.get_uniform_location(program, "uSampler");
 
         // gl.uniform1fv(floatUniformLoc, [v]);
 
@@ -149,8 +380,6 @@ impl Chip8WebGLDisplay {
 
         gl.bind_vertex_array(Some(&vao));
 
-        let vert_count = (vertices.len() / 3) as i32;
-
         // Some nums
         let colors = [
             1.0, 1.0, 1.0, 1.0, // white
@@ -175,27 +404,27 @@ impl Chip8WebGLDisplay {
         // Actually read binary pixel data from chip8 video buffer into a WebGL buffer
         let texture = gl.create_texture();
 
-        gl.active_texture(WebGl2RenderingContext::TEXTURE0 + 0);
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0);
         gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, texture.as_ref());
         gl.uniform1i(u_sampler_attrib.as_ref(), 0);
 
         {
             // define size and format of level 0
             let level = 0;
-            let internal_format = WebGl2RenderingContext::R8;
+            let internal_format = WebGl2RenderingContext::RG8;
             let border = 0;
-            let format = WebGl2RenderingContext::RED;
+            let format = WebGl2RenderingContext::RG;
             let gl_type = WebGl2RenderingContext::UNSIGNED_BYTE;
             gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
                 WebGl2RenderingContext::TEXTURE_2D,
                 level,
                 internal_format as i32,
-                Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as i32,
-                Chip8WebGLDisplay::CHIP8_DISPLAY_HEIGHT as i32,
+                Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as i32,
+                Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as i32,
                 border,
                 format,
                 gl_type,
-                Some(&self.video_buffer),
+                Some(&[0; 64 * 32 * 2]),
             )
             .expect("Failed making texture");
 
@@ -225,89 +454,78 @@ impl Chip8WebGLDisplay {
         self.render_texture = texture;
         // Probably should not immediatly draw on initialization, but for not it does.
         // draw(&gl, vert_count, &gl_video_buffer, &chip8_video_buffer);
-        self.draw();
+        self.draw(&[0; 64 * 32 * 2]);
 
         Ok(())
     }
 
-    // Clear video buffer
-    pub fn clear(&mut self) {
-        self.video_buffer = [0; 64 * 32];
-    }
+    // Match the canvas resolution to its size on the page, so it stays sharp on high DPI
+    // screens and after resizes, and place the image on it.
+    fn fit_to_canvas(&mut self) {
+        // Not laid out (e.g. hidden), keep the resolution it has
+        let (width, height) = self.canvas.device_size().unwrap_or(self.canvas.size());
 
-    pub fn set_pixel(&mut self, x: u32, y: u32, state: bool) {
-        if state {
-            self.video_buffer[(y * 64 + x) as usize] = 0xFF;
-        } else {
-            self.video_buffer[(y * 64 + x) as usize] = 0x00;
+        if self.canvas_size == (width, height) {
+            return;
         }
-    }
-
-    pub fn xor_pixel(&mut self, x: u8, y: u8, pixel_color: u8) -> bool {
-        
 
-        if x < Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH && y < Chip8WebGLDisplay::CHIP8_DISPLAY_HEIGHT
-        {
-            let x = x as u32;
-            let y = y as u32;
+        self.canvas.set_size((width, height));
+        self.gl.viewport(0, 0, width as i32, height as i32);
 
-            let y = Chip8WebGLDisplay::CHIP8_DISPLAY_HEIGHT as u32 - y - 1;
-            // let x = Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as u32 - x - 1;
+        let (x, y, image_width, image_height) = image_rect(width, height, self.scaling);
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        let rect = [
+            image_width as f32 / width,
+            image_height as f32 / height,
+            (2 * x + image_width) as f32 / width - 1.0,
+            (2 * y + image_height) as f32 / height - 1.0,
+        ];
 
+        self.gl.use_program(Some(&self.program));
+        self.gl
+            .uniform4fv_with_f32_array(self.image_rect_location.as_ref(), &rect);
 
-            let px_before = self.video_buffer[(y * Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as u32 + x) as usize];
-            self.video_buffer[(y * Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as u32 + x) as usize] ^= pixel_color;
+        self.canvas_size = self.canvas.size();
+    }
+}
 
-            let px_after = self.video_buffer[(y * Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as u32 + x) as usize];
+impl Chip8Display for Chip8WebGLDisplay {
+    fn set_palette(&mut self, palette: &[Rgb]) {
+        let colors: Vec<f32> = full_palette(palette)
+            .iter()
+            .flatten()
+            .map(|channel| *channel as f32 / 255.0)
+            .collect();
 
-            if px_before == 0xFF && px_after == 0x00 {
-                return true;
-            }
-        } else {
-            // panic!("Yikes out of bounds: x: {} y: {}", x, y);
-        }
+        self.gl.use_program(Some(&self.program));
+        self.gl
+            .uniform3fv_with_f32_array(self.palette_location.as_ref(), &colors);
 
-        false
+        self.background = palette.first().copied().unwrap_or([0, 0, 0]);
+        self.stale = true;
     }
 
-    pub fn set_checkerboard(&mut self, state: bool) {
-        for y in 0..32 {
-            for x in 0..64 {
-                if (x + y) % 2 == 0 {
-                    if state {
-                        self.video_buffer[(y * 64 + x) as usize] = 0xFF;
-                    } else {
-                        self.video_buffer[(y * 64 + x) as usize] = 0x00;
-                    }
-                } else {
-                    if state {
-                        self.video_buffer[(y * 64 + x) as usize] = 0x00;
-                    } else {
-                        self.video_buffer[(y * 64 + x) as usize] = 0xFF;
-                    }
-                }
-            }
+    fn set_scaling(&mut self, scaling: ScalingMode) {
+        if self.scaling != scaling {
+            self.scaling = scaling;
+            // Lay the image out again on the next draw
+            self.canvas_size = (0, 0);
+            self.stale = true;
         }
     }
 
-    // Random test function
-    pub fn set_some_pixels(&mut self) {
-        for x in 20..40 {
-            for y in 20..40 {
-                self.video_buffer[x * y] = 0xFF;
-            }
-        }
-    }
+    fn draw(&mut self, texels: &[u8]) {
+        self.fit_to_canvas();
+        self.stale = false;
 
-    // Draw video buffer to webgl buffer to screen
-    pub fn draw(&mut self) {
         let gl = &self.gl;
         let vert_count = 6;
 
-        gl.clear_color(0.0, 1.0, 0.0, 1.0);
+        let [r, g, b] = self.background;
+        gl.clear_color(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0);
         gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
 
-        gl.active_texture(WebGl2RenderingContext::TEXTURE0 + 0);
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0);
         gl.bind_texture(
             WebGl2RenderingContext::TEXTURE_2D,
             self.render_texture.as_ref(),
@@ -315,27 +533,37 @@ impl Chip8WebGLDisplay {
         // gl.uniform1i(u_sampler_attrib.as_ref(), 0);
 
         let level = 0;
-        let internal_format = WebGl2RenderingContext::R8;
-        let border = 0;
-        let format = WebGl2RenderingContext::RED;
+        let format = WebGl2RenderingContext::RG;
         let gl_type = WebGl2RenderingContext::UNSIGNED_BYTE;
 
         gl.tex_sub_image_2d_with_i32_and_i32_and_u32_and_type_and_opt_u8_array(
-            WebGl2RenderingContext::TEXTURE_2D,             // target: u32,
-            level,                                          // level: i32,
-            0,                                              // xoffset: i32,
-            0,                                              // yoffset: i32,
-            Chip8WebGLDisplay::CHIP8_DISPLAY_WIDTH as i32,  // width: i32,
-            Chip8WebGLDisplay::CHIP8_DISPLAY_HEIGHT as i32, // height: i32,
-            format,                                         // format: u32,
-            gl_type,                                        // type_: u32,
-            Some(&self.video_buffer),
+            WebGl2RenderingContext::TEXTURE_2D,            // target: u32,
+            level,                                         // level: i32,
+            0,                                             // xoffset: i32,
+            0,                                             // yoffset: i32,
+            Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as i32,  // width: i32,
+            Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as i32, // height: i32,
+            format,                                        // format: u32,
+            gl_type,                                       // type_: u32,
+            Some(texels),
         )
         .expect("Failed updating sub texture");
 
         gl.draw_arrays(WebGl2RenderingContext::TRIANGLES, 0, vert_count);
         // draw(&self.gl, 6);
     }
+
+    fn needs_redraw(&self) -> bool {
+        self.stale
+            || self
+                .canvas
+                .device_size()
+                .is_some_and(|size| size != self.canvas_size)
+    }
+
+    fn name(&self) -> &'static str {
+        "webgl2"
+    }
 }
 
 pub fn compile_shader(
@@ -387,3 +615,54 @@ pub fn link_shader_program(
             .unwrap_or_else(|| String::from("Unknown error creating program object")))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_rect_fits_and_letterboxes() {
+        // Exact fit
+        assert_eq!(image_rect(800, 400, ScalingMode::Fit), (0, 0, 800, 400));
+        // Too wide, bars left and right
+        assert_eq!(image_rect(1000, 400, ScalingMode::Fit), (100, 0, 800, 400));
+        // Too tall, bars above and below
+        assert_eq!(image_rect(640, 480, ScalingMode::Fit), (0, 80, 640, 320));
+        // Not a multiple of the chip8 resolution
+        assert_eq!(image_rect(100, 100, ScalingMode::Fit), (0, 25, 100, 50));
+    }
+
+    #[test]
+    fn test_texels_to_rgba() {
+        let width = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as usize;
+        let height = Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize;
+        let palette = [[0x00, 0x00, 0x00], [0xFF, 0x80, 0x00]];
+
+        let mut texels = vec![0; width * height * 2];
+        // Bottom left on, bottom right half faded, top right a value past the palette
+        texels[0..2].copy_from_slice(&[1, 0xFF]);
+        texels[(width - 1) * 2..width * 2].copy_from_slice(&[1, 0x80]);
+        let top_right = (width * height - 1) * 2;
+        texels[top_right..top_right + 2].copy_from_slice(&[3, 0xFF]);
+
+        let rgba = texels_to_rgba(&texels, &palette);
+        let pixel = |x: usize, y: usize| &rgba[(y * width + x) * 4..(y * width + x) * 4 + 4];
+
+        assert_eq!(rgba.len(), width * height * 4);
+        assert_eq!(pixel(0, height - 1), [0xFF, 0x80, 0x00, 0xFF]);
+        assert_eq!(pixel(width - 1, height - 1), [0x80, 0x40, 0x00, 0xFF]);
+        assert_eq!(pixel(width - 1, 0), [0xFF, 0x80, 0x00, 0xFF]);
+        assert_eq!(pixel(1, 1), [0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_image_rect_integer_scaling() {
+        assert_eq!(image_rect(100, 100, ScalingMode::Integer), (18, 34, 64, 32));
+        assert_eq!(
+            image_rect(1920, 1080, ScalingMode::Integer),
+            (0, 60, 1920, 960)
+        );
+        // Smaller than the chip8 screen, it can't get any smaller than 1:1
+        assert_eq!(image_rect(32, 16, ScalingMode::Integer), (0, 0, 64, 32));
+    }
+}
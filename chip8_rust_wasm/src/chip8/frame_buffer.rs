@@ -0,0 +1,269 @@
+// The chip8 screen, every row is a bit mask per plane so a sprite row is drawn with one shift
+// and XOR, and collisions are found with one AND. The byte per pixel picture the display wants
+// is only built when a frame is presented.
+// Kept separate from any renderer so the CPU can draw to it without a browser.
+pub struct Chip8FrameBuffer {
+    // Bit 127 - x of a row is the pixel at x, room for screens up to 128 pixels wide
+    planes:
+        [[u128; Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize]; Chip8FrameBuffer::PLANE_COUNT],
+    // Something was drawn or cleared since the last `take_changed`
+    changed: bool,
+}
+
+impl Chip8FrameBuffer {
+    pub const CHIP8_DISPLAY_HEIGHT: u8 = 32;
+    pub const CHIP8_DISPLAY_WIDTH: u8 = 64;
+    // Palette index of a pixel that is on in the first plane, plain CHIP-8 only draws to that one.
+    // A pixel's palette index has a bit for every plane it is on in.
+    pub const PIXEL_ON: u8 = 1;
+    pub const PLANE_COUNT: usize = 4;
+
+    pub fn new() -> Chip8FrameBuffer {
+        Chip8FrameBuffer {
+            planes: [[0; Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize];
+                Chip8FrameBuffer::PLANE_COUNT],
+            changed: true,
+        }
+    }
+
+    // Every row of every plane
+    pub const STATE_SIZE: usize =
+        Chip8FrameBuffer::PLANE_COUNT * Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize * 16;
+
+    pub fn write_state(&self, state: &mut Vec<u8>) {
+        for row in self.planes.iter().flatten() {
+            state.extend_from_slice(&row.to_be_bytes());
+        }
+    }
+
+    // `state` is `STATE_SIZE` bytes written by `write_state`
+    pub fn read_state(&mut self, state: &[u8]) {
+        for (row, bytes) in self.planes.iter_mut().flatten().zip(state.chunks(16)) {
+            let mut row_bytes = [0; 16];
+            row_bytes.copy_from_slice(bytes);
+            *row = u128::from_be_bytes(row_bytes);
+        }
+        self.changed = true;
+    }
+
+    // Clear video buffer
+    pub fn clear(&mut self) {
+        self.planes =
+            [[0; Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize]; Chip8FrameBuffer::PLANE_COUNT];
+        self.changed = true;
+    }
+
+    // Whether the screen changed since the last call. Pixels that got flipped and flipped back
+    // still count as a change, checking that would cost more than drawing once too often.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    // XOR a sprite row onto the planes in `planes`, returns true if a set pixel got unset.
+    // `row` holds `row_width` pixels (8, or 16 for large sprites), the highest bit is the left
+    // most pixel. Pixels past the right edge wrap to the left one with `wrap`, otherwise they
+    // are clipped like rows below the screen.
+    pub fn xor_sprite_row(
+        &mut self,
+        x: u8,
+        y: u8,
+        row: u16,
+        row_width: u8,
+        planes: u8,
+        wrap: bool,
+    ) -> bool {
+        if y >= Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT || x >= Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH
+        {
+            return false;
+        }
+
+        // Line the sprite up with the left edge, then move it over, bits past the screen
+        // width are masked off or moved back to the start of the row
+        let sprite = ((row as u128) << (128 - row_width as u32)) >> x;
+        let overflow = sprite & !Chip8FrameBuffer::screen_mask();
+        let mut sprite = sprite & Chip8FrameBuffer::screen_mask();
+        if wrap {
+            sprite |= overflow << Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH;
+        }
+
+        if sprite == 0 || planes == 0 {
+            return false;
+        }
+
+        self.changed = true;
+        let mut collision = false;
+
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                let screen_row = &mut rows[y as usize];
+                collision |= *screen_row & sprite != 0;
+                *screen_row ^= sprite;
+            }
+        }
+
+        collision
+    }
+
+    // XOR the planes in `planes` onto a pixel, returns true if one of them got unset.
+    // Pixels outside of the screen are ignored.
+    pub fn xor_pixel(&mut self, x: u8, y: u8, planes: u8) -> bool {
+        self.xor_sprite_row(x, y, 1, 1, planes, false)
+    }
+
+    // Palette index of a pixel, 0 outside of the screen
+    pub fn pixel(&self, x: u8, y: u8) -> u8 {
+        if x >= Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH || y >= Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT
+        {
+            return 0;
+        }
+
+        let bit = 1u128 << (127 - x as u32);
+
+        self.planes
+            .iter()
+            .enumerate()
+            .filter(|(_, rows)| rows[y as usize] & bit != 0)
+            .fold(0, |index, (plane, _)| index | (1 << plane))
+    }
+
+    // One byte per pixel holding its palette index, rows are stored bottom to top, this is the
+    // order WebGL expects texture data in.
+    pub fn video_buffer(&self) -> [u8; 64 * 32] {
+        let width = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as usize;
+        let height = Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize;
+        let mut buffer = [0; 64 * 32];
+
+        for (plane, rows) in self.planes.iter().enumerate() {
+            for (y, row) in rows.iter().enumerate() {
+                if *row == 0 {
+                    continue;
+                }
+
+                let line = &mut buffer[(height - y - 1) * width..(height - y) * width];
+                for (x, pixel) in line.iter_mut().enumerate() {
+                    if row & (1u128 << (127 - x)) != 0 {
+                        *pixel |= 1 << plane;
+                    }
+                }
+            }
+        }
+
+        buffer
+    }
+
+    // Bits of a row that are on the screen
+    fn screen_mask() -> u128 {
+        !(u128::MAX >> Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH)
+    }
+}
+
+impl Default for Chip8FrameBuffer {
+    fn default() -> Self {
+        Chip8FrameBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_changed() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+        // A new screen still has to be shown once
+        assert!(frame_buffer.take_changed());
+        assert!(!frame_buffer.take_changed());
+
+        // Blank sprite pixels don't change anything
+        frame_buffer.xor_pixel(1, 1, 0);
+        assert!(!frame_buffer.take_changed());
+
+        frame_buffer.xor_pixel(1, 1, Chip8FrameBuffer::PIXEL_ON);
+        assert!(frame_buffer.take_changed());
+        assert!(!frame_buffer.take_changed());
+
+        frame_buffer.clear();
+        assert!(frame_buffer.take_changed());
+    }
+
+    #[test]
+    fn test_xor_sprite_row_and_collision() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+
+        assert!(!frame_buffer.xor_sprite_row(
+            4,
+            2,
+            0b1100_0011,
+            8,
+            Chip8FrameBuffer::PIXEL_ON,
+            false
+        ));
+        assert_eq!(frame_buffer.pixel(4, 2), 1);
+        assert_eq!(frame_buffer.pixel(6, 2), 0);
+        assert_eq!(frame_buffer.pixel(11, 2), 1);
+
+        // Overlapping one set pixel
+        assert!(frame_buffer.xor_sprite_row(
+            11,
+            2,
+            0b1000_0000,
+            8,
+            Chip8FrameBuffer::PIXEL_ON,
+            false
+        ));
+        assert_eq!(frame_buffer.pixel(11, 2), 0);
+        // Not overlapping anything
+        assert!(!frame_buffer.xor_sprite_row(
+            6,
+            2,
+            0b1100_0000,
+            8,
+            Chip8FrameBuffer::PIXEL_ON,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_xor_sprite_row_clips_and_planes() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+
+        // Only the 4 left pixels fit on the screen
+        frame_buffer.xor_sprite_row(60, 0, 0xFF, 8, Chip8FrameBuffer::PIXEL_ON, false);
+        assert_eq!(frame_buffer.pixel(63, 0), 1);
+        assert_eq!(frame_buffer.pixel(0, 0), 0);
+        assert!(!frame_buffer.xor_sprite_row(0, 32, 0xFF, 8, Chip8FrameBuffer::PIXEL_ON, false));
+
+        // 16 wide sprite on both of the first two planes
+        frame_buffer.xor_sprite_row(0, 5, 0x8001, 16, 0b11, false);
+        assert_eq!(frame_buffer.pixel(0, 5), 3);
+        assert_eq!(frame_buffer.pixel(15, 5), 3);
+        assert_eq!(frame_buffer.pixel(16, 5), 0);
+    }
+
+    #[test]
+    fn test_xor_sprite_row_wraps() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+
+        frame_buffer.xor_sprite_row(60, 1, 0xFF, 8, Chip8FrameBuffer::PIXEL_ON, true);
+        assert!((60..64).all(|x| frame_buffer.pixel(x, 1) == 1));
+        assert!((0..4).all(|x| frame_buffer.pixel(x, 1) == 1));
+        assert_eq!(frame_buffer.pixel(4, 1), 0);
+
+        // Collisions are found on the wrapped part too
+        assert!(frame_buffer.xor_sprite_row(0, 1, 0x80, 8, Chip8FrameBuffer::PIXEL_ON, true));
+        assert!(frame_buffer.xor_sprite_row(56, 1, 0xFFFF, 16, Chip8FrameBuffer::PIXEL_ON, true));
+    }
+
+    #[test]
+    fn test_video_buffer_is_bottom_row_first() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+        frame_buffer.xor_pixel(1, 0, 0b01);
+        frame_buffer.xor_pixel(2, 31, 0b10);
+
+        let buffer = frame_buffer.video_buffer();
+
+        assert_eq!(buffer[31 * 64 + 1], 1);
+        assert_eq!(buffer[2], 2);
+        assert_eq!(buffer.iter().filter(|pixel| **pixel != 0).count(), 2);
+    }
+}
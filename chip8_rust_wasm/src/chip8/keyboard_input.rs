@@ -1,5 +1,106 @@
+// Game specific keys, the arrow keys, space (a) and enter (b) are mapped to these keypad keys.
+// Uses the names of the `keys` object in the CHIP-8 database.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chip8KeyMap {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+impl Chip8KeyMap {
+    pub fn key_from_code(&self, code: &str) -> Option<u8> {
+        match code {
+            "ArrowUp" => self.up,
+            "ArrowDown" => self.down,
+            "ArrowLeft" => self.left,
+            "ArrowRight" => self.right,
+            "Space" => self.a,
+            "Enter" => self.b,
+            _ => None,
+        }
+    }
+}
+
+// State of the 16 key hexadecimal keypad (keys 0x0 to 0xF)
 pub struct Chip8Input {
-    pub num: u32,
+    keys: [bool; 16],
+    key_map: Chip8KeyMap,
+}
+
+impl Chip8Input {
+    pub const KEY_COUNT: u8 = 16;
+
+    pub fn new() -> Chip8Input {
+        Chip8Input {
+            keys: [false; 16],
+            key_map: Chip8KeyMap::default(),
+        }
+    }
+
+    pub fn set_key_map(&mut self, key_map: Chip8KeyMap) {
+        self.key_map = key_map;
+    }
+
+    // Only the low nibble of the key is used, just like the interpreter does with VX.
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keys[(key & 0x0F) as usize] = pressed;
+    }
+
+    pub fn release_all(&mut self) {
+        self.keys = [false; 16];
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[(key & 0x0F) as usize]
+    }
+
+    // Lowest key that is currently held down, if any.
+    pub fn first_pressed(&self) -> Option<u8> {
+        (0..Chip8Input::KEY_COUNT).find(|key| self.keys[*key as usize])
+    }
+
+    // Maps a `KeyboardEvent.code` to a keypad key, game specific keys first, then the layout.
+    pub fn key_from_code(&self, code: &str) -> Option<u8> {
+        self.key_map
+            .key_from_code(code)
+            .or_else(|| Chip8Input::layout_key_from_code(code))
+    }
+
+    // The usual layout:
+    // 1 2 3 C      1 2 3 4
+    // 4 5 6 D  ->  Q W E R
+    // 7 8 9 E      A S D F
+    // A 0 B F      Z X C V
+    pub fn layout_key_from_code(code: &str) -> Option<u8> {
+        let key = match code {
+            "Digit1" => 0x1,
+            "Digit2" => 0x2,
+            "Digit3" => 0x3,
+            "Digit4" => 0xC,
+            "KeyQ" => 0x4,
+            "KeyW" => 0x5,
+            "KeyE" => 0x6,
+            "KeyR" => 0xD,
+            "KeyA" => 0x7,
+            "KeyS" => 0x8,
+            "KeyD" => 0x9,
+            "KeyF" => 0xE,
+            "KeyZ" => 0xA,
+            "KeyX" => 0x0,
+            "KeyC" => 0xB,
+            "KeyV" => 0xF,
+            _ => return None,
+        };
+
+        Some(key)
+    }
 }
 
-trait ReadKeyboardInput {}
+impl Default for Chip8Input {
+    fn default() -> Self {
+        Chip8Input::new()
+    }
+}
@@ -1,192 +1,573 @@
-mod audio;
+pub mod achievements;
+pub mod audio;
+mod canvas_display;
+pub mod cartridge;
+pub mod cheat_finder;
+pub mod cheats;
+pub mod conditions;
+pub mod config;
 mod cpu;
-mod display;
-mod keyboard_input;
-mod timer;
+pub mod database;
+pub mod display;
+mod frame_buffer;
+pub mod keyboard_input;
+pub mod octo;
+pub mod persistence;
+pub mod recorder;
+pub mod rom;
+pub mod screenshot;
+pub mod splits;
 
-use std::{cell::RefCell, path::Path, rc::Rc, u8, usize, vec};
+use std::{cell::RefCell, fmt, path::Path, rc::Rc};
 
-use wasm_bindgen::prelude::*;
-use wasm_bindgen::JsCast;
-use web_sys::{
-    console::{self},
-    Blob, Document, File as WasmFile, FileList, FileReader, HtmlCanvasElement, HtmlInputElement,
-};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{DataTransfer, File as WasmFile, FileList, HtmlInputElement};
 
-use self::display::Chip8WebGLDisplay;
+use self::achievements::{AchievementInfo, Chip8Achievements};
+use self::cartridge::OctoCartridge;
+use self::cheats::Chip8Cheats;
+use self::conditions::MachineSnapshot;
+use self::config::{Chip8Config, Rgb, ScalingMode};
+use self::database::{Chip8Database, RomInfo};
+use self::display::Chip8Display;
+use self::frame_buffer::Chip8FrameBuffer;
+use self::keyboard_input::Chip8Input;
+use self::persistence::{Chip8Persistence, PersistenceMode};
+use self::recorder::{Chip8GifRecorder, GifRecorderOptions};
+use self::rom::{RomLoadError, RomLoadWarning};
+use self::splits::{Chip8Splits, SplitEvent};
 
 const CHIP8_FILE_INPUT_DOC_ID: &str = "chip8-file-input";
 
-pub enum Chip8Error {
-    NoRomFound(String),
-    DisplayFailed(String),
-}
-
+#[derive(Debug, PartialEq)]
 pub enum Chip8FileIOError {
     NoFileSelected,
+    // Not running on a page
+    NoDocument,
+    NoFileInput(&'static str),
+    NotAFileInput,
+}
+
+impl fmt::Display for Chip8FileIOError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8FileIOError::NoFileSelected => write!(f, "No file is selected"),
+            Chip8FileIOError::NoDocument => write!(f, "There is no page to find a file input on"),
+            Chip8FileIOError::NoFileInput(id) => {
+                write!(f, "Could not find a file input with the id {}", id)
+            }
+            Chip8FileIOError::NotAFileInput => write!(f, "The element is not a file input"),
+        }
+    }
+}
+
+impl From<Chip8FileIOError> for JsValue {
+    fn from(err: Chip8FileIOError) -> JsValue {
+        js_sys::Error::new(&err.to_string()).into()
+    }
+}
+
+// File picked in a file input, the page's rom file input if none is given
+pub fn selected_rom_file(input: Option<HtmlInputElement>) -> Result<WasmFile, Chip8FileIOError> {
+    let file_input_element = match input {
+        Some(input) => input,
+        None => web_sys::window()
+            .and_then(|window| window.document())
+            .ok_or(Chip8FileIOError::NoDocument)?
+            .get_element_by_id(CHIP8_FILE_INPUT_DOC_ID)
+            .ok_or(Chip8FileIOError::NoFileInput(CHIP8_FILE_INPUT_DOC_ID))?
+            .dyn_into::<HtmlInputElement>()
+            .map_err(|_| Chip8FileIOError::NotAFileInput)?,
+    };
+
+    let file_list: FileList = file_input_element
+        .files()
+        .ok_or(Chip8FileIOError::NotAFileInput)?;
+
+    file_list.item(0).ok_or(Chip8FileIOError::NoFileSelected)
+}
+
+// ROMs for SUPER-CHIP or XO-CHIP still load, but the player should know why they misbehave
+fn platform_warning(config: &Chip8Config) -> Option<RomLoadWarning> {
+    config
+        .platform
+        .filter(|platform| !platform.is_supported())
+        .map(RomLoadWarning::UnsupportedPlatform)
 }
 
 // #[wasm_bindgen]
 pub struct Chip8 {
     chip8_cpu: cpu::Chip8CPU,
     chip8_memory: Rc<RefCell<Chip8Memory>>,
-    chip8_display: Rc<RefCell<Chip8WebGLDisplay>>,
-    delay_timer: timer::Chip8DelayTimer,
-    audio_timer: timer::Chip8AudioTimer,
-    chip8_input: keyboard_input::Chip8Input,
+    chip8_frame_buffer: Rc<RefCell<Chip8FrameBuffer>>,
+    chip8_display: Box<dyn Chip8Display>,
+    chip8_input: Rc<RefCell<Chip8Input>>,
+    persistence: Chip8Persistence,
+    recorder: Option<Chip8GifRecorder>,
+    cheats: Chip8Cheats,
+    achievements: Chip8Achievements,
+    // Unlocked since the last `take_unlocked_achievements`
+    unlocked_achievements: Vec<AchievementInfo>,
+    splits: Chip8Splits,
+    // Since the last `take_split_events`
+    split_events: Vec<SplitEvent>,
+    frame_changed: bool,
+    // Frames don't run, the screen is still kept up to date
+    paused: bool,
+    config: Chip8Config,
     rom: Option<WasmFile>,
+    rom_info: Option<RomInfo>,
+    // The program as it went into memory, for resets
+    program: Option<Vec<u8>>,
 }
 
 impl Chip8 {
-    pub fn new() -> Chip8 {
+    // Every machine draws to its own display, see `display::create`
+    pub fn new(display: Box<dyn Chip8Display>) -> Chip8 {
         let mem = Rc::new(RefCell::new(Chip8Memory::new()));
-        let disp = Rc::new(RefCell::new(Chip8WebGLDisplay::new("chip8_canvas")));
+        let frame_buffer = Rc::new(RefCell::new(Chip8FrameBuffer::new()));
+        let input = Rc::new(RefCell::new(Chip8Input::new()));
         // let ok = &*mem.borrow_mut();
-        // let ok = disp.clone();
 
         Chip8 {
             rom: None,
-            chip8_cpu: cpu::Chip8CPU::new(mem.clone(), disp.clone()),
-            chip8_memory: mem.clone(),
-            chip8_display: disp.clone(),
-            delay_timer: timer::Chip8DelayTimer {},
-            audio_timer: timer::Chip8AudioTimer {},
-            chip8_input: keyboard_input::Chip8Input { num: 10 },
+            rom_info: None,
+            program: None,
+            config: Chip8Config::new(),
+            chip8_cpu: cpu::Chip8CPU::new(mem.clone(), frame_buffer.clone(), input.clone()),
+            chip8_memory: mem,
+            chip8_frame_buffer: frame_buffer,
+            chip8_display: display,
+            chip8_input: input,
+            persistence: Chip8Persistence::new(),
+            recorder: None,
+            cheats: Chip8Cheats::new(),
+            achievements: Chip8Achievements::new(),
+            unlocked_achievements: Vec::new(),
+            splits: Chip8Splits::new(),
+            split_events: Vec::new(),
+            frame_changed: false,
+            paused: false,
         }
     }
 
-    pub fn set_rom(&mut self) -> Result<(), Chip8FileIOError> {
-        let document: Document = web_sys::window().unwrap().document().unwrap();
-        let file_input_element = document.get_element_by_id(&CHIP8_FILE_INPUT_DOC_ID).expect(
-            format!(
-                "Could not find file input by document id: {}",
-                CHIP8_FILE_INPUT_DOC_ID
-            )
-            .as_str(),
-        );
+    // Keypad shared with the CPU, key events from the page are written into this.
+    pub fn input(&self) -> Rc<RefCell<Chip8Input>> {
+        self.chip8_input.clone()
+    }
 
-        let file_input_element: HtmlInputElement = file_input_element
-            .dyn_into::<HtmlInputElement>()
-            .map_err(|_| ())
-            .unwrap();
+    // Memory shared with the CPU, e.g. for searching it with `Chip8CheatFinder`
+    pub fn memory(&self) -> Rc<RefCell<Chip8Memory>> {
+        self.chip8_memory.clone()
+    }
 
-        let file_list: FileList = file_input_element
-            .files()
-            .expect("Failed getting filelist.");
+    // Draw with another display from now on, it gets the current colours and scaling. Hands
+    // back the old one, e.g. to move it to a new machine instead of setting up the canvas again.
+    pub fn replace_display(&mut self, display: Box<dyn Chip8Display>) -> Box<dyn Chip8Display> {
+        let old = std::mem::replace(&mut self.chip8_display, display);
+        self.chip8_display.set_palette(&self.config.palette);
+        self.chip8_display.set_scaling(self.config.scaling);
 
-        let file: WasmFile = match file_list.item(0) {
-            Some(file) => file,
-            None => return Err(Chip8FileIOError::NoFileSelected),
-        };
+        old
+    }
+
+    // "webgl2", or "canvas 2d" when WebGL2 isn't available
+    pub fn renderer(&self) -> &'static str {
+        self.chip8_display.name()
+    }
+
+    pub fn config(&self) -> &Chip8Config {
+        &self.config
+    }
+
+    pub fn apply_config(&mut self, config: Chip8Config) {
+        self.chip8_cpu.set_quirks(config.quirks);
+        self.chip8_input
+            .borrow_mut()
+            .set_key_map(config.key_map.clone());
+        self.chip8_display.set_palette(&config.palette);
+        self.set_persistence(config.persistence);
+        self.chip8_display.set_scaling(config.scaling);
+        self.config = config;
+    }
 
-        self.rom = Some(file);
+    // Instructions per 60 Hz frame
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.config.tick_rate = tick_rate;
+    }
+
+    pub fn set_scaling(&mut self, scaling: ScalingMode) {
+        self.chip8_display.set_scaling(scaling);
+        self.config.scaling = scaling;
+    }
+
+    // Switch colours while running, e.g. to a preset picked on the page
+    pub fn set_palette(&mut self, palette: Vec<Rgb>) {
+        self.chip8_display.set_palette(&palette);
+        self.config.palette = palette;
+    }
+
+    pub fn set_persistence(&mut self, mode: PersistenceMode) {
+        if self.persistence.mode() != mode {
+            self.persistence.set_mode(mode);
+        }
+        self.config.persistence = mode;
+    }
+
+    // Database entry of the loaded rom, if it was recognised
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+
+    pub fn set_rom(&mut self) -> Result<(), Chip8FileIOError> {
+        self.rom = Some(selected_rom_file(None)?);
 
         Ok(())
     }
 
-    // This might be a bad / disgusting way to read a binary file to memory, but it works. Using JS File.
-    pub async fn load_rom(&mut self) {
+    // Reads the file picked with `set_rom` into memory.
+    pub async fn load_rom(&mut self) -> Result<Vec<RomLoadWarning>, RomLoadError> {
         let file = match &self.rom {
             Some(rom) => rom,
-            None => {
-                panic!("Set rom please!");
-            }
+            None => return Err(RomLoadError::ReadFailed(String::from("no file selected"))),
+        };
+
+        let bytes = rom::read_file(file).await?;
+
+        self.load_rom_bytes(&bytes)
+    }
+
+    // Every other way of loading a rom ends up here.
+    // Known roms get the settings from the database, others keep the current config.
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        if OctoCartridge::is_cartridge(rom) {
+            return self.load_cartridge(rom);
+        }
+
+        // The platform decides how much fits, so look the rom up first
+        let known = Chip8Database::bundled().lookup(rom);
+        let max_size = match &known {
+            Some((_, config)) => config.max_rom_size(),
+            None => self.config.max_rom_size(),
         };
 
-        // Read file as binary to typed array.
-        let array = match wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await {
-            Ok(val) => val,
-            Err(err) => {
-                panic!("Failed smth");
+        let mut warnings = self.chip8_memory.borrow_mut().load_rom(rom, max_size)?;
+        self.program = Some(rom.to_vec());
+
+        self.rom_info = match known {
+            Some((info, config)) => {
+                warnings.extend(platform_warning(&config));
+                self.apply_config(config);
+                Some(info)
             }
+            None => None,
         };
 
-        let new_array = js_sys::Uint8Array::new(&array);
+        Ok(warnings)
+    }
+
+    // Assemble the program of an Octo cartridge and run it with the cartridge's settings.
+    pub fn load_cartridge(
+        &mut self,
+        gif_bytes: &[u8],
+    ) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        let cartridge = OctoCartridge::decode(gif_bytes)?;
+        let rom = cartridge.rom()?;
 
-        // Get memory stuff
-        // let max = self.chip8_memory.data.len();
-        // let buf = &mut self.chip8_memory.data[Chip8Memory::START_ADRESS as usize..max - 1];
-        // let buf = &mut self.chip8_memory.borrow_mut().data[Chip8Memory::START_ADRESS as usize..max - 1];
+        let config = cartridge.config();
+        let mut warnings = self
+            .chip8_memory
+            .borrow_mut()
+            .load_rom(&rom, config.max_rom_size())?;
+        warnings.extend(platform_warning(&config));
 
-        let memory = &mut self.chip8_memory.borrow_mut();
-        let max = memory.data.len();
-        let buf = &mut memory.data[Chip8Memory::START_ADRESS as usize..max - 1];
+        self.rom_info = Chip8Database::bundled().lookup(&rom).map(|(info, _)| info);
+        self.program = Some(rom);
+        self.apply_config(config);
 
-        // new_array does not have consistent length, indexing new_array with buf.len() as max range could be out of bounds.
-        let iter_length;
-        if (new_array.length()) <= buf.len() as u32 {
-            iter_length = new_array.length();
-        } else {
-            iter_length = buf.len() as u32;
+        Ok(warnings)
+    }
+
+    pub async fn load_rom_url(&mut self, url: &str) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        let bytes = rom::fetch(url).await?;
+
+        self.load_rom_bytes(&bytes)
+    }
+
+    pub async fn load_rom_data_transfer(
+        &mut self,
+        data_transfer: &DataTransfer,
+    ) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        let file = rom::data_transfer_file(data_transfer)
+            .ok_or_else(|| RomLoadError::ReadFailed(String::from("no file was dropped")))?;
+        let bytes = rom::read_file(&file).await?;
+
+        self.load_rom_bytes(&bytes)
+    }
+
+    pub fn load_rom_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        let bytes = rom::read_path(path)?;
+
+        self.load_rom_bytes(&bytes)
+    }
+
+    // PNG of the screen as it is now, in the current palette
+    pub fn screenshot_png(&self, scale: u32) -> Result<Vec<u8>, String> {
+        screenshot::encode_png(
+            &self.chip8_frame_buffer.borrow(),
+            &self.config.palette,
+            scale,
+        )
+    }
+
+    // Record every frame from now on to an animated GIF, replaces a recording in progress
+    pub fn start_recording(&mut self, options: GifRecorderOptions) -> Result<(), String> {
+        self.recorder = Some(Chip8GifRecorder::new(&self.config.palette, options)?);
+
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // The GIF of everything recorded since `start_recording`
+    pub fn stop_recording(&mut self) -> Result<Vec<u8>, String> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Err(String::from("Not recording")),
+        }
+    }
+
+    // Start the loaded program over: CPU, timers, screen and keypad start fresh and the program
+    // is loaded into fresh memory. Settings like the quirks and colours stay. The machine is left
+    // alone if the program can't be loaded again.
+    pub fn reset(&mut self) -> Result<(), RomLoadError> {
+        self.restart(Chip8Memory::new())
+    }
+
+    // Like turning the machine off and on again, RAM the program doesn't fill is cleared or
+    // gets random garbage, like on real hardware. Programs that expect zeroed RAM show up with
+    // `random_ram`.
+    pub fn power_cycle(&mut self, random_ram: bool) -> Result<(), RomLoadError> {
+        let mut memory = Chip8Memory::new();
+        if random_ram {
+            memory.fill_random();
         }
 
-        // Feels like bad implementation, O(n), but this might be the only option, doesn't really matter because files are only a couple kb big.
-        // Read all binary data from array into chip8 memory
-        for index in 0..iter_length {
-            buf[index as usize] = new_array.get_index(index);
+        self.restart(memory)
+    }
+
+    fn restart(&mut self, mut memory: Chip8Memory) -> Result<(), RomLoadError> {
+        if let Some(program) = &self.program {
+            memory.load_program(program)?;
         }
 
-        // To read binary data from regular Rust std::fs file
-        // let max = self.memory.len();
-        // let buf = &mut self.memory[Chip8Memory::START_ADRESS as usize..max - 1];
-        // file.read(buf).unwrap();
+        *self.chip8_memory.borrow_mut() = memory;
+        self.chip8_cpu.reset();
+        self.chip8_frame_buffer.borrow_mut().clear();
+        self.chip8_input.borrow_mut().release_all();
+        self.cheats.restart();
+        self.achievements.restart();
+        self.split_events.extend(self.splits.restart());
+        // Forget the afterglow of the old screen
+        self.persistence.set_mode(self.persistence.mode());
+
+        Ok(())
+    }
+
+    pub fn cheats(&self) -> &Chip8Cheats {
+        &self.cheats
+    }
+
+    // Replaces the cheats, they are written from the next frame on
+    pub fn set_cheats(&mut self, cheats: Chip8Cheats) {
+        self.cheats = cheats;
     }
 
-    pub fn test(&mut self) {
-        let memory = self.chip8_memory.borrow_mut();
-        let display = &mut self.chip8_display.borrow_mut();
+    // False if there is no such cheat
+    pub fn set_cheat_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        self.cheats.set_enabled(name, enabled)
+    }
 
-        let width = 8u8;
-        let height = 8u8;
+    pub fn achievements(&self) -> &Chip8Achievements {
+        &self.achievements
+    }
 
-        let x_pos = 0u8;
-        let y_pos = 5u8;
+    // Replaces the achievements, they are checked from the end of the next frame on
+    pub fn set_achievements(&mut self, achievements: Chip8Achievements) {
+        self.achievements = achievements;
+        self.unlocked_achievements.clear();
+    }
 
-        for i_y in 0..height {
-            // let pixels = memory.data[(self.index + i_y as u16) as usize];
+    pub fn take_unlocked_achievements(&mut self) -> Vec<AchievementInfo> {
+        std::mem::take(&mut self.unlocked_achievements)
+    }
 
-            for i_x in 0..width {
-                // let mut pixel_state = (pixels >> i_x) & 0x1;
+    pub fn splits(&self) -> &Chip8Splits {
+        &self.splits
+    }
 
-                // if pixel_state == 0 {
-                //     pixel_state = 0x00;
-                // } else {
-                //     pixel_state = 0xFF;
-                // }
+    // Replaces the splits, the timer waits for the start conditions
+    pub fn set_splits(&mut self, splits: Chip8Splits) {
+        self.splits = splits;
+        self.split_events.clear();
+    }
 
-                // display.set_pixel((i_x + x_pos) as u32, (i_y + y_pos) as u32, true);
-                unsafe {
-                    console::log_1(&JsValue::from_str(format!("x: {} y: {}", i_x, i_y).as_str()));
-                }
-                let flipped = display.xor_pixel(i_x, i_y, 0xFF);
+    pub fn reset_split_timer(&mut self) {
+        self.split_events.extend(self.splits.reset_timer());
+    }
 
-                // if flipped {
-                //     self.index_registers[0xF] = 1;
-                // }
+    pub fn take_split_events(&mut self) -> Vec<SplitEvent> {
+        std::mem::take(&mut self.split_events)
+    }
+
+    const SAVE_STATE_MAGIC: &'static [u8] = b"C8S1";
+    // Save states always have this size, it is what libretro wants
+    pub const SAVE_STATE_SIZE: usize = Chip8::SAVE_STATE_MAGIC.len()
+        + Chip8Memory::MEMORY_SIZE
+        + cpu::Chip8CPU::STATE_SIZE
+        + Chip8FrameBuffer::STATE_SIZE;
+
+    // Memory, CPU and screen as they are now. Settings like the quirks aren't in it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(Chip8::SAVE_STATE_SIZE);
+        state.extend_from_slice(Chip8::SAVE_STATE_MAGIC);
+        state.extend_from_slice(&self.chip8_memory.borrow().data);
+        self.chip8_cpu.write_state(&mut state);
+        self.chip8_frame_buffer.borrow().write_state(&mut state);
+
+        state
+    }
+
+    // Go back to a `save_state`, the machine is left alone if it isn't one
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != Chip8::SAVE_STATE_SIZE || !state.starts_with(Chip8::SAVE_STATE_MAGIC) {
+            return Err(String::from("Not a save state of this emulator"));
+        }
+
+        let (memory, rest) =
+            state[Chip8::SAVE_STATE_MAGIC.len()..].split_at(Chip8Memory::MEMORY_SIZE);
+        let (cpu, frame_buffer) = rest.split_at(cpu::Chip8CPU::STATE_SIZE);
+
+        self.chip8_cpu.read_state(cpu)?;
+        self.chip8_memory.borrow_mut().data.copy_from_slice(memory);
+        self.chip8_frame_buffer
+            .borrow_mut()
+            .read_state(frame_buffer);
+        self.chip8_input.borrow_mut().release_all();
+        // Forget the afterglow of the old screen
+        self.persistence.set_mode(self.persistence.mode());
+
+        Ok(())
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Runs one 60 Hz frame, or only redraws if needed while paused
+    pub fn update(&mut self) {
+        if self.paused {
+            self.present();
+            return;
+        }
+
+        self.cheats.apply(&mut self.chip8_memory.borrow_mut());
+
+        for _ in 0..self.config.tick_rate {
+            // Drawing with the display wait quirk ends the frame early
+            if self.chip8_cpu.is_waiting_for_vblank() {
+                break;
             }
+
+            self.chip8_cpu.cycle();
+        }
+
+        self.chip8_cpu.tick_timers();
+        self.chip8_cpu.vblank();
+
+        if !self.achievements.is_empty() || !self.splits.is_empty() {
+            let memory = self.chip8_memory.borrow();
+            let now = MachineSnapshot {
+                memory: &memory.data,
+                registers: self.chip8_cpu.registers(),
+                index: self.chip8_cpu.index(),
+                delay_timer: self.chip8_cpu.delay_timer(),
+                sound_timer: self.chip8_cpu.sound_timer(),
+            };
+
+            if !self.splits.is_empty() {
+                self.split_events.extend(self.splits.evaluate(&now));
+            }
+            if !self.achievements.is_empty() {
+                let unlocked = self.achievements.evaluate(&now);
+                self.unlocked_achievements.extend(unlocked);
+            }
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_frame(&self.chip8_frame_buffer.borrow());
+        }
+
+        self.present();
+    }
+
+    // Runs a single instruction, for stepping through a paused program. A CPU that waits for
+    // the display ends its frame instead.
+    pub fn step(&mut self) {
+        if self.chip8_cpu.is_waiting_for_vblank() {
+            self.chip8_cpu.tick_timers();
+            self.chip8_cpu.vblank();
+        } else {
+            self.chip8_cpu.cycle();
         }
 
-        display.draw();
+        self.present();
+    }
+
+    fn present(&mut self) {
+        self.frame_changed = self.chip8_frame_buffer.borrow_mut().take_changed();
+
+        // Idle screens don't need the texture upload and draw
+        if self.frame_changed
+            || self.persistence.is_animating()
+            || self.chip8_display.needs_redraw()
+        {
+            let texels = self.persistence.present(&self.chip8_frame_buffer.borrow());
+            self.chip8_display.draw(texels);
+        }
     }
 
-    pub fn update(&mut self, some_val: f32) {
-        self.chip8_cpu.cycle();
+    // The sound timer is running, a paused machine is quiet
+    pub fn is_beeping(&self) -> bool {
+        !self.paused && self.chip8_cpu.sound_timer() > 0
+    }
 
-        let disp = &mut self.chip8_display.borrow_mut();
-        // disp.set_pixel(some_val as u32, 0, true);
-        disp.draw();
+    // Whether the last `update` or `step` drew or cleared anything
+    pub fn frame_changed(&self) -> bool {
+        self.frame_changed
     }
 }
 
 pub struct Chip8Memory {
-    data: [u8; 4096],
+    data: [u8; Chip8Memory::MEMORY_SIZE],
 }
 
 impl Chip8Memory {
-    pub const MEMORY_SIZE: usize = 4096;
+    // The whole address space, only XO-CHIP programs use more than the first 4 KB
+    pub const MEMORY_SIZE: usize = 0x10000;
     pub const FONTSET_START_ADRESS: u32 = 0x50;
     pub const FONTSET_SIZE: u32 = 80;
     pub const START_ADRESS: u32 = 0x200;
+    pub const MAX_ROM_SIZE: usize = Chip8Memory::MEMORY_SIZE - Chip8Memory::START_ADRESS as usize;
 
     const FONT_SET: [u8; Chip8Memory::FONTSET_SIZE as usize] = [
         0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -207,10 +588,10 @@ impl Chip8Memory {
         0xF0, 0x80, 0xF0, 0x80, 0x80, // F
     ];
 
-    // pub fn load_
-
     pub fn new() -> Chip8Memory {
-        let mut memory = Chip8Memory { data: [0; 4096] };
+        let mut memory = Chip8Memory {
+            data: [0; Chip8Memory::MEMORY_SIZE],
+        };
 
         for (index, byte) in Chip8Memory::FONT_SET.iter().enumerate() {
             memory.data[Chip8Memory::FONTSET_START_ADRESS as usize + index] = *byte;
@@ -218,4 +599,448 @@ impl Chip8Memory {
 
         memory
     }
+
+    // The bytes stay put as long as the memory does, e.g. for a libretro frontend poking them
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.data.as_mut_ptr()
+    }
+
+    // Copy a rom into memory at the start address, everything after it is cleared.
+    // `max_size` is what the platform has room for, see `Chip8Config::max_rom_size`.
+    pub fn load_rom(
+        &mut self,
+        rom: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+        let warnings = rom::validate(rom, max_size)?;
+
+        let buf = &mut self.data[Chip8Memory::START_ADRESS as usize..];
+        buf.fill(0);
+        buf[..rom.len()].copy_from_slice(rom);
+
+        Ok(warnings)
+    }
+
+    // Copy a program that was loaded before back to the start address, the rest of memory is
+    // left as it is.
+    fn load_program(&mut self, program: &[u8]) -> Result<(), RomLoadError> {
+        rom::validate(program, Chip8Memory::MAX_ROM_SIZE)?;
+
+        let start = Chip8Memory::START_ADRESS as usize;
+        self.data[start..start + program.len()].copy_from_slice(program);
+
+        Ok(())
+    }
+
+    // Random bytes everywhere but the font
+    fn fill_random(&mut self) {
+        let font_start = Chip8Memory::FONTSET_START_ADRESS as usize;
+        let font_end = font_start + Chip8Memory::FONTSET_SIZE as usize;
+
+        for (address, byte) in self.data.iter_mut().enumerate() {
+            if !(font_start..font_end).contains(&address) {
+                *byte = rand::random::<u8>();
+            }
+        }
+    }
+}
+
+impl Default for Chip8Memory {
+    fn default() -> Self {
+        Chip8Memory::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::config::Chip8Platform;
+    use crate::chip8::display::Chip8NullDisplay;
+
+    const TEST_ROM: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/test_stuff/chip8_test1.ch8"
+    );
+
+    const START: usize = Chip8Memory::START_ADRESS as usize;
+    const MAX_ROM_SIZE: usize = 3584;
+
+    #[test]
+    fn test_load_rom_copies_to_start_address() {
+        let mut memory = Chip8Memory::new();
+
+        let warnings = memory
+            .load_rom(&[0x12, 0x34, 0x56, 0x78], MAX_ROM_SIZE)
+            .unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(
+            memory.data[START..START + 5],
+            [0x12, 0x34, 0x56, 0x78, 0x00]
+        );
+        assert_eq!(memory.data[START - 1], 0x00);
+        // Font is left alone
+        assert_eq!(
+            memory.data[Chip8Memory::FONTSET_START_ADRESS as usize],
+            0xF0
+        );
+    }
+
+    #[test]
+    fn test_load_rom_from_path() {
+        let bytes = rom::read_path(TEST_ROM).unwrap();
+        let mut memory = Chip8Memory::new();
+
+        memory.load_rom(&bytes, MAX_ROM_SIZE).unwrap();
+
+        assert!(!bytes.is_empty());
+        assert_eq!(memory.data[START..START + bytes.len()], bytes[..]);
+    }
+
+    #[test]
+    fn test_read_missing_path_fails() {
+        assert!(matches!(
+            rom::read_path("this/rom/does/not/exist.ch8"),
+            Err(RomLoadError::ReadFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_rom_fills_all_of_memory() {
+        for platform in [None, Some(Chip8Platform::XoChip)] {
+            let config = Chip8Config {
+                platform,
+                ..Chip8Config::new()
+            };
+            let mut memory = Chip8Memory::new();
+            let mut rom = vec![0x00; config.max_rom_size()];
+            rom[0] = 0x12;
+            rom[config.max_rom_size() - 1] = 0xAB;
+
+            memory.load_rom(&rom, config.max_rom_size()).unwrap();
+
+            assert_eq!(memory.data[config.memory_size() - 1], 0xAB);
+        }
+    }
+
+    #[test]
+    fn test_rom_size_depends_on_platform() {
+        let rom = vec![0x12; 0x8000];
+        let mut machine = Chip8::new(Box::new(Chip8NullDisplay));
+
+        assert_eq!(
+            machine.load_rom_bytes(&rom),
+            Err(RomLoadError::TooLarge {
+                size: 0x8000,
+                max_size: MAX_ROM_SIZE,
+            })
+        );
+
+        let mut config = machine.config().clone();
+        config.platform = Some(Chip8Platform::XoChip);
+        machine.apply_config(config);
+
+        assert_eq!(machine.load_rom_bytes(&rom), Ok(vec![]));
+        machine.reset().unwrap();
+        assert_eq!(machine.memory().borrow().data[START + 0x7FFF], 0x12);
+    }
+
+    #[test]
+    fn test_xo_chip_cartridges_get_all_of_memory() {
+        let gif_bytes = cartridge::tests::octo_xo_chip_cartridge();
+        let mut machine = Chip8::new(Box::new(Chip8NullDisplay));
+
+        assert_eq!(
+            machine.load_cartridge(&gif_bytes),
+            Ok(vec![RomLoadWarning::UnsupportedPlatform(
+                Chip8Platform::XoChip
+            )])
+        );
+        assert_eq!(machine.config().platform, Some(Chip8Platform::XoChip));
+        machine.reset().unwrap();
+        assert_eq!(machine.memory().borrow().data[START + 5003], 0xAB);
+    }
+
+    #[test]
+    fn test_unsupported_platforms_warn() {
+        let warning = |platform| {
+            platform_warning(&Chip8Config {
+                platform,
+                ..Chip8Config::new()
+            })
+        };
+
+        assert_eq!(warning(None), None);
+        assert_eq!(warning(Some(Chip8Platform::OriginalChip8)), None);
+        assert_eq!(warning(Some(Chip8Platform::Chip48)), None);
+        assert_eq!(
+            warning(Some(Chip8Platform::Superchip)),
+            Some(RomLoadWarning::UnsupportedPlatform(
+                Chip8Platform::Superchip
+            ))
+        );
+    }
+
+    #[test]
+    fn test_load_rom_errors() {
+        let cases: [(Vec<u8>, RomLoadError); 4] = [
+            (vec![], RomLoadError::Empty),
+            (
+                vec![0x12; MAX_ROM_SIZE + 3],
+                RomLoadError::TooLarge {
+                    size: 3587,
+                    max_size: 3584,
+                },
+            ),
+            (
+                b"GIF89a\x40\x00".to_vec(),
+                RomLoadError::UnsupportedFormat(String::from("a GIF image (Octo cartridge?)")),
+            ),
+            (
+                b": main\n  v0 := 1\n".to_vec(),
+                RomLoadError::UnsupportedFormat(String::from(
+                    "Octo source code, compile it to a .ch8 first",
+                )),
+            ),
+        ];
+
+        for (rom, expected) in cases {
+            let mut memory = Chip8Memory::new();
+            memory.data[START] = 0x99;
+
+            assert_eq!(memory.load_rom(&rom, MAX_ROM_SIZE), Err(expected));
+            // Memory is untouched
+            assert_eq!(memory.data[START], 0x99);
+        }
+    }
+
+    #[test]
+    fn test_load_rom_warnings() {
+        let cases = [
+            (vec![0x00, 0xE0, 0x12], vec![RomLoadWarning::OddSize(3)]),
+            (
+                vec![0x00, 0x00, 0x12, 0x00],
+                vec![RomLoadWarning::InvalidFirstOpcode(0x0000)],
+            ),
+            (
+                vec![0xFF],
+                vec![
+                    RomLoadWarning::OddSize(1),
+                    RomLoadWarning::InvalidFirstOpcode(0xFF00),
+                ],
+            ),
+        ];
+
+        for (rom, expected) in cases {
+            let mut memory = Chip8Memory::new();
+
+            assert_eq!(memory.load_rom(&rom, MAX_ROM_SIZE), Ok(expected));
+            assert_eq!(memory.data[START], rom[0]);
+        }
+    }
+
+    fn machine_with(program: &[u8]) -> Chip8 {
+        let mut machine = Chip8::new(Box::new(Chip8NullDisplay));
+        machine.load_rom_bytes(program).unwrap();
+
+        machine
+    }
+
+    // 6042 (V0 = 0x42), A300 (I = 0x300), F055 (store V0 at I), D011 (draw), 1208 (loop)
+    const STORE_AND_DRAW: [u8; 10] = [0x60, 0x42, 0xA3, 0x00, 0xF0, 0x55, 0xD0, 0x11, 0x12, 0x08];
+
+    #[test]
+    fn test_reset_starts_the_program_over() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.set_tick_rate(5);
+        machine.chip8_input.borrow_mut().set_key(0x5, true);
+        machine.update();
+
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x42);
+        // 0x42 drawn at (V0, V1) = (2, 0)
+        assert!(machine.chip8_frame_buffer.borrow().pixel(3, 0) != 0);
+
+        machine.reset().unwrap();
+
+        let memory = machine.chip8_memory.borrow();
+        assert_eq!(memory.data[START..START + 10], STORE_AND_DRAW);
+        assert_eq!(memory.data[0x300], 0);
+        assert!(machine
+            .chip8_frame_buffer
+            .borrow()
+            .video_buffer()
+            .iter()
+            .all(|pixel| *pixel == 0));
+        assert_eq!(machine.chip8_input.borrow().first_pressed(), None);
+        // Settings stay
+        assert_eq!(machine.config().tick_rate, 5);
+    }
+
+    #[test]
+    fn test_failed_reset_leaves_the_machine_alone() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.update();
+        machine.program = Some(Vec::new());
+
+        assert_eq!(machine.reset(), Err(RomLoadError::Empty));
+        assert_eq!(machine.power_cycle(true), Err(RomLoadError::Empty));
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x42);
+        assert!(machine.chip8_frame_buffer.borrow().pixel(3, 0) != 0);
+    }
+
+    #[test]
+    fn test_paused_machine_does_not_run() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.set_paused(true);
+        machine.update();
+
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0);
+
+        // Stepping still works
+        machine.step();
+        machine.set_paused(false);
+        machine.update();
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x42);
+    }
+
+    #[test]
+    fn test_cheats_are_written_every_frame() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.set_cheats(Chip8Cheats::parse("[Answer] freeze on\n300 07\n").unwrap());
+
+        // The program stores 42 after the cheat wrote 07, the next frame freezes it again
+        machine.update();
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x42);
+        machine.update();
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x07);
+
+        assert!(machine.set_cheat_enabled("Answer", false));
+        machine.reset().unwrap();
+        machine.update();
+        assert_eq!(machine.chip8_memory.borrow().data[0x300], 0x42);
+    }
+
+    #[test]
+    fn test_achievements_are_checked_every_frame() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.set_achievements(
+            Chip8Achievements::parse(
+                r#"{ "achievements": [ { "id": "stored", "title": "Stored", "conditions": [
+                    { "left": "m:300", "cmp": "==", "right": "v:0" },
+                    { "left": "i", "cmp": "==", "right": "0x300" } ] } ] }"#,
+            )
+            .unwrap(),
+        );
+
+        machine.update();
+        let unlocked = machine.take_unlocked_achievements();
+        assert_eq!(unlocked.len(), 1);
+        assert_eq!(unlocked[0].id(), "stored");
+
+        machine.update();
+        assert!(machine.take_unlocked_achievements().is_empty());
+    }
+
+    #[test]
+    fn test_save_states() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.update();
+        let state = machine.save_state();
+        assert_eq!(state.len(), Chip8::SAVE_STATE_SIZE);
+        let cpu_state = |machine: &Chip8| {
+            let mut state = Vec::new();
+            machine.chip8_cpu.write_state(&mut state);
+            state
+        };
+        let memory = machine.memory().borrow().data.to_vec();
+        let cpu = cpu_state(&machine);
+        let screen = machine.chip8_frame_buffer.borrow().video_buffer();
+
+        machine.reset().unwrap();
+        assert_ne!(cpu_state(&machine), cpu);
+
+        machine.load_state(&state).unwrap();
+        assert_eq!(machine.memory().borrow().data.to_vec(), memory);
+        assert_eq!(cpu_state(&machine), cpu);
+        assert_eq!(machine.chip8_frame_buffer.borrow().video_buffer(), screen);
+        assert_eq!(machine.save_state(), state);
+
+        assert!(machine.load_state(&state[1..]).is_err());
+        let mut broken = state.clone();
+        broken[0] = b'X';
+        assert!(machine.load_state(&broken).is_err());
+    }
+
+    #[test]
+    fn test_split_timer_counts_frames() {
+        use super::splits::SplitEventKind;
+
+        let mut machine = machine_with(&STORE_AND_DRAW);
+        machine.set_splits(
+            Chip8Splits::parse(
+                r#"{ "start": [ { "left": "v:0", "cmp": "==", "right": "0x42" } ],
+                     "splits": [ { "name": "Stored", "conditions": [
+                        { "left": "m:300", "cmp": "==", "right": "0x42", "for_frames": 3 } ] } ] }"#,
+            )
+            .unwrap(),
+        );
+
+        let kinds = |machine: &mut Chip8| -> Vec<SplitEventKind> {
+            machine
+                .take_split_events()
+                .iter()
+                .map(|event| event.kind())
+                .collect()
+        };
+
+        machine.update();
+        assert_eq!(kinds(&mut machine), [SplitEventKind::Start]);
+
+        // Paused frames don't count
+        machine.set_paused(true);
+        machine.update();
+        machine.set_paused(false);
+        machine.update();
+        machine.update();
+        assert!(kinds(&mut machine).is_empty());
+        machine.update();
+        let events = machine.take_split_events();
+        assert_eq!(events[0].kind(), SplitEventKind::Finish);
+        assert_eq!(events[0].frames(), 3);
+
+        machine.reset().unwrap();
+        assert_eq!(kinds(&mut machine), [SplitEventKind::Reset]);
+    }
+
+    #[test]
+    fn test_power_cycle_with_random_ram() {
+        let mut machine = machine_with(&STORE_AND_DRAW);
+
+        machine.power_cycle(true).unwrap();
+
+        let memory = machine.chip8_memory.borrow();
+        assert_eq!(memory.data[START..START + 10], STORE_AND_DRAW);
+        assert_eq!(memory.data[0x50..0x55], Chip8Memory::FONT_SET[..5]);
+        // 2 KB of zeros by chance is not going to happen
+        assert!(memory.data[0x800..0x1000].iter().any(|byte| *byte != 0));
+        drop(memory);
+
+        machine.power_cycle(false).unwrap();
+        assert!(machine.chip8_memory.borrow().data[START + 10..]
+            .iter()
+            .all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_too_large_message_has_exact_sizes() {
+        let err = RomLoadError::TooLarge {
+            size: 3600,
+            max_size: 3584,
+        };
+
+        assert_eq!(
+            err.to_string(),
+            "The ROM is 3600 bytes, but at most 3584 bytes fit in memory (16 bytes too many)"
+        );
+    }
 }
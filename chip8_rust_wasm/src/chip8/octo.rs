@@ -0,0 +1,1176 @@
+// Assembler for Octo, the CHIP-8 assembly language Octo cartridges store their programs in.
+// https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+//
+// Covers the language as used by most programs: labels, constants, aliases, macros, :calc
+// expressions, structured control flow and the CHIP-8, SUPER-CHIP and XO-CHIP instructions.
+// :stringmode is not supported.
+use std::{collections::HashMap, collections::VecDeque, fmt};
+
+const START_ADDRESS: usize = 0x200;
+
+#[derive(Debug, PartialEq)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let mut chars = line.chars().peekable();
+
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '#' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let text: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                tokens.push(Token {
+                    text: format!("\"{}\"", text),
+                    line: index + 1,
+                });
+            } else {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(Token {
+                    text,
+                    line: index + 1,
+                });
+            }
+        }
+    }
+
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn register_index(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+
+    if digit.len() != 1 {
+        return None;
+    }
+
+    u8::from_str_radix(digit, 16).ok()
+}
+
+// Instruction fields that still need the address of a label defined further down
+#[derive(Clone, Copy)]
+enum Patch {
+    // Low 12 bits of the instruction at the address
+    Address12,
+    // The two bytes at the address
+    Address16,
+    // v0 := (nibble << 4) | high address bits ; v1 := low address bits
+    Unpack(u8),
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey,
+}
+
+impl Comparison {
+    fn negate(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::GreaterEqual => Comparison::Less,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+        }
+    }
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+    None,
+}
+
+struct Condition {
+    register: u8,
+    comparison: Comparison,
+    operand: Operand,
+}
+
+struct Loop {
+    start: usize,
+    whiles: Vec<usize>,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    macro_calls: usize,
+    patches: Vec<(usize, Patch, String, usize)>,
+    loops: Vec<Loop>,
+    branches: Vec<usize>,
+}
+
+// Assemble Octo source to a ROM that is loaded at 0x200.
+pub fn assemble(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source).into(),
+        line: 1,
+        rom: Vec::new(),
+        here: START_ADDRESS,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        macro_calls: 0,
+        patches: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+    };
+
+    assembler.run()?;
+
+    Ok(assembler.rom)
+}
+
+impl Assembler {
+    fn error<T>(&self, message: String) -> Result<T, OctoError> {
+        Err(OctoError {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn next(&mut self) -> Result<String, OctoError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error(String::from("Unexpected end of program")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), OctoError> {
+        let token = self.next()?;
+
+        if token != expected {
+            return self.error(format!("Expected '{}', got '{}'", expected, token));
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, address: usize, byte: u8) -> Result<(), OctoError> {
+        if address > 0xFFFF {
+            return self.error(String::from("Program does not fit in 64k of memory"));
+        }
+
+        let index = address - START_ADDRESS;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), OctoError> {
+        self.write(self.here, byte)?;
+        self.here += 1;
+
+        Ok(())
+    }
+
+    fn instruction(&mut self, opcode: u16) -> Result<(), OctoError> {
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), OctoError> {
+        if self.labels.contains_key(&name) {
+            return self.error(format!("The label '{}' has already been defined", name));
+        }
+
+        self.labels.insert(name, address);
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), OctoError> {
+        // The first instruction jumps to main
+        self.instruction(0x1000)?;
+        self.patches
+            .push((START_ADDRESS, Patch::Address12, String::from("main"), 1));
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(start) = self.loops.last().map(|l| l.start) {
+            return self.error(format!("The loop at {:03X} is missing 'again'", start));
+        }
+        if !self.branches.is_empty() {
+            return self.error(String::from("An 'if ... begin' is missing 'end'"));
+        }
+
+        for (address, patch, name, line) in std::mem::take(&mut self.patches) {
+            self.line = line;
+
+            let target = match self.labels.get(&name) {
+                Some(target) => *target,
+                None if name == "main" => {
+                    return self.error(String::from("This program is missing a 'main' label"))
+                }
+                None => return self.error(format!("Undefined name '{}'", name)),
+            };
+
+            match patch {
+                Patch::Address12 => {
+                    if target > 0xFFF {
+                        return self
+                            .error(format!("The address of '{}' does not fit in 12 bits", name));
+                    }
+                    let index = address - START_ADDRESS;
+                    self.rom[index] = (self.rom[index] & 0xF0) | (target >> 8) as u8;
+                    self.rom[index + 1] = target as u8;
+                }
+                Patch::Address16 => {
+                    self.write(address, (target >> 8) as u8)?;
+                    self.write(address + 1, target as u8)?;
+                }
+                Patch::Unpack(nibble) => {
+                    self.write(address + 1, (nibble << 4) | ((target >> 8) & 0xF) as u8)?;
+                    self.write(address + 3, target as u8)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+
+        self.register_from(&token).map_or_else(
+            || self.error(format!("Expected a register, got '{}'", token)),
+            Ok,
+        )
+    }
+
+    fn register_from(&self, token: &str) -> Option<u8> {
+        register_index(token).or_else(|| self.aliases.get(token).copied())
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        self.register_from(token).is_some()
+    }
+
+    // Numbers, constants, labels and { expressions }
+    fn value(&mut self) -> Result<f64, OctoError> {
+        let token = self.next()?;
+
+        if token == "{" {
+            return self.calc_block();
+        }
+
+        match self.known_value(&token) {
+            Some(value) => Ok(value),
+            None => self.error(format!("Undefined name '{}'", token)),
+        }
+    }
+
+    fn known_value(&self, token: &str) -> Option<f64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).copied())
+            .or_else(|| self.labels.get(token).map(|address| *address as f64))
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let value = self.value()?.floor() as i64;
+
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("The value {} does not fit in a byte", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        let value = self.value()?.floor() as i64;
+
+        if !(0..=15).contains(&value) {
+            return self.error(format!("The value {} does not fit in 4 bits", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    // Address operand, labels that are not defined yet are patched in at the end
+    fn address(&mut self, opcode: u16, patch: Patch) -> Result<(), OctoError> {
+        let token = self.next()?;
+
+        let value = if token == "{" {
+            Some(self.calc_block()?)
+        } else {
+            self.known_value(&token)
+        };
+
+        let address = self.here;
+
+        match (value, patch) {
+            (Some(value), Patch::Address16) => {
+                self.instruction(opcode)?;
+                self.instruction(value as u16)?;
+            }
+            (Some(value), _) => {
+                if !(0.0..=4095.0).contains(&value) {
+                    return self.error(format!("The address {} does not fit in 12 bits", value));
+                }
+                self.instruction(opcode | value as u16)?;
+            }
+            (None, _) => {
+                if parse_number(&token).is_none() && !self.is_register(&token) {
+                    self.patches.push((address, patch, token, self.line));
+                    self.instruction(opcode)?;
+                    if let Patch::Address16 = patch {
+                        self.instruction(0x0000)?;
+                    }
+                } else {
+                    return self.error(format!("Expected an address, got '{}'", token));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        let token = self.next()?;
+
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name, self.here)
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(name, self.here + 1)
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = if self.peek() == Some("{") {
+                    self.next()?;
+                    self.calc_block()? as u8 & 0xF
+                } else {
+                    self.register()?
+                };
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let token = self.next()?;
+                match self.known_value(&token) {
+                    Some(address) => {
+                        let address = address as usize;
+                        self.instruction(
+                            0x6000 | (nibble as u16) << 4 | (address >> 8) as u16 & 0xF,
+                        )?;
+                        self.instruction(0x6100 | address as u16 & 0xFF)
+                    }
+                    None => {
+                        self.patches
+                            .push((self.here, Patch::Unpack(nibble), token, self.line));
+                        self.instruction(0x6000)?;
+                        self.instruction(0x6100)
+                    }
+                }
+            }
+            ":org" => {
+                let address = self.value()? as usize;
+                if address < START_ADDRESS {
+                    return self.error(format!("Cannot :org to {:X}, below 0x200", address));
+                }
+                self.here = address;
+                Ok(())
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte)
+            }
+            ":pointer" => self.address(0x0000, Patch::Address16),
+            ":call" => self.address(0x2000, Patch::Address12),
+            ":macro" => self.define_macro(),
+            ":assert" => {
+                let message = if self.peek().is_some_and(|t| t.starts_with('"')) {
+                    self.next()?
+                } else {
+                    String::from("Assertion failed")
+                };
+                self.expect("{")?;
+                if self.calc_block()? == 0.0 {
+                    return self.error(message.trim_matches('"').to_string());
+                }
+                Ok(())
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+                Ok(())
+            }
+            ":breakpoint" => {
+                self.next()?;
+                Ok(())
+            }
+            ":stringmode" => self.error(String::from(":stringmode is not supported")),
+            ";" | "return" => self.instruction(0x00EE),
+            "clear" => self.instruction(0x00E0),
+            "hires" => self.instruction(0x00FF),
+            "lores" => self.instruction(0x00FE),
+            "exit" => self.instruction(0x00FD),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(0x00C0 | n as u16)
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(0x00D0 | n as u16)
+            }
+            "scroll-right" => self.instruction(0x00FB),
+            "scroll-left" => self.instruction(0x00FC),
+            "audio" => self.instruction(0xF002),
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(0xF001 | (n as u16) << 8)
+            }
+            "bcd" => self.register_instruction(0xF033),
+            "saveflags" => self.register_instruction(0xF075),
+            "loadflags" => self.register_instruction(0xF085),
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()? as u16;
+                    let base = if token == "save" { 0x5002 } else { 0x5003 };
+                    self.instruction(base | x << 8 | y << 4)
+                } else {
+                    let base = if token == "save" { 0xF055 } else { 0xF065 };
+                    self.instruction(base | x << 8)
+                }
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()? as u16;
+                self.instruction(0xD000 | x << 8 | y << 4 | n)
+            }
+            "jump" => self.address(0x1000, Patch::Address12),
+            "jump0" => self.address(0xB000, Patch::Address12),
+            "native" => self.address(0x0000, Patch::Address12),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let base = match token.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.instruction(base | x << 8)
+            }
+            "i" => self.index_statement(),
+            "if" => self.if_statement(),
+            "else" => {
+                let jump = match self.branches.pop() {
+                    Some(jump) => jump,
+                    None => return self.error(String::from("'else' without 'if ... begin'")),
+                };
+                self.branches.push(self.here);
+                self.instruction(0x1000)?;
+                self.patch_jump(jump, self.here)
+            }
+            "end" => match self.branches.pop() {
+                Some(jump) => self.patch_jump(jump, self.here),
+                None => self.error(String::from("'end' without 'if ... begin'")),
+            },
+            "loop" => {
+                self.loops.push(Loop {
+                    start: self.here,
+                    whiles: Vec::new(),
+                });
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return self.error(String::from("'while' outside of a loop"));
+                }
+                let condition = self.condition()?;
+                self.skip_unless(&Condition {
+                    comparison: condition.comparison.negate(),
+                    ..condition
+                })?;
+                let address = self.here;
+                self.loops.last_mut().unwrap().whiles.push(address);
+                self.instruction(0x1000)
+            }
+            "again" => {
+                let lp = match self.loops.pop() {
+                    Some(lp) => lp,
+                    None => return self.error(String::from("'again' without 'loop'")),
+                };
+                self.instruction(0x1000 | lp.start as u16)?;
+                for jump in lp.whiles {
+                    self.patch_jump(jump, self.here)?;
+                }
+                Ok(())
+            }
+            _ => self.other_statement(token),
+        }
+    }
+
+    fn other_statement(&mut self, token: String) -> Result<(), OctoError> {
+        if let Some(x) = self.register_from(&token) {
+            return self.register_statement(x);
+        }
+
+        if self.macros.contains_key(&token) {
+            return self.expand_macro(&token);
+        }
+
+        if let Some(value) = parse_number(&token).or_else(|| self.constants.get(&token).copied()) {
+            let value = value.floor() as i64;
+            if !(-128..=255).contains(&value) {
+                return self.error(format!("The value {} does not fit in a byte", value));
+            }
+            return self.emit(value as u8);
+        }
+
+        if token.starts_with(':') || token.starts_with('"') || token == "{" || token == "}" {
+            return self.error(format!("Unexpected '{}'", token));
+        }
+
+        // Anything else is a call to a subroutine
+        self.tokens.push_front(Token {
+            text: token,
+            line: self.line,
+        });
+        self.address(0x2000, Patch::Address12)
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), OctoError> {
+        let x = self.register()? as u16;
+        self.instruction(opcode | x << 8)
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), OctoError> {
+        let x = x as u16;
+        let op = self.next()?;
+
+        if op == ":=" {
+            match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let n = self.byte()? as u16;
+                    return self.instruction(0xC000 | x << 8 | n);
+                }
+                Some("key") => {
+                    self.next()?;
+                    return self.instruction(0xF00A | x << 8);
+                }
+                Some("delay") => {
+                    self.next()?;
+                    return self.instruction(0xF007 | x << 8);
+                }
+                _ => {}
+            }
+        }
+
+        let operand = self.peek().unwrap_or("").to_string();
+
+        if let Some(y) = self.register_from(&operand) {
+            self.next()?;
+            let y = y as u16;
+            let n = match op.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return self.error(format!("Unknown operator '{}'", op)),
+            };
+            return self.instruction(0x8000 | x << 8 | y << 4 | n);
+        }
+
+        match op.as_str() {
+            ":=" => {
+                let n = self.byte()? as u16;
+                self.instruction(0x6000 | x << 8 | n)
+            }
+            "+=" => {
+                let n = self.byte()? as u16;
+                self.instruction(0x7000 | x << 8 | n)
+            }
+            "-=" => {
+                let n = self.byte()?;
+                self.instruction(0x7000 | x << 8 | n.wrapping_neg() as u16)
+            }
+            _ => self.error(format!(
+                "The operator '{}' needs a register, got '{}'",
+                op, operand
+            )),
+        }
+    }
+
+    fn index_statement(&mut self) -> Result<(), OctoError> {
+        let op = self.next()?;
+
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.register_instruction(0xF029)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    self.register_instruction(0xF030)
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.address(0xF000, Patch::Address16)
+                }
+                _ => self.address(0xA000, Patch::Address12),
+            },
+            "+=" => self.register_instruction(0xF01E),
+            _ => self.error(format!("Unknown operator 'i {}'", op)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let register = self.register()?;
+        let op = self.next()?;
+
+        let comparison = match op.as_str() {
+            "key" => Comparison::Key,
+            "-key" => Comparison::NotKey,
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            ">=" => Comparison::GreaterEqual,
+            _ => return self.error(format!("Unknown comparison '{}'", op)),
+        };
+
+        let operand = match comparison {
+            Comparison::Key | Comparison::NotKey => Operand::None,
+            _ => {
+                let token = self.peek().unwrap_or("").to_string();
+                match self.register_from(&token) {
+                    Some(y) => {
+                        self.next()?;
+                        Operand::Register(y)
+                    }
+                    None => Operand::Byte(self.byte()?),
+                }
+            }
+        };
+
+        Ok(Condition {
+            register,
+            comparison,
+            operand,
+        })
+    }
+
+    // Emits the instructions that skip the next instruction unless the condition holds
+    fn skip_unless(&mut self, condition: &Condition) -> Result<(), OctoError> {
+        let x = condition.register as u16;
+
+        match (condition.comparison, &condition.operand) {
+            (Comparison::Key, _) => self.instruction(0xE0A1 | x << 8),
+            (Comparison::NotKey, _) => self.instruction(0xE09E | x << 8),
+            (Comparison::Equal, Operand::Byte(n)) => self.instruction(0x4000 | x << 8 | *n as u16),
+            (Comparison::Equal, Operand::Register(y)) => {
+                self.instruction(0x9000 | x << 8 | (*y as u16) << 4)
+            }
+            (Comparison::NotEqual, Operand::Byte(n)) => {
+                self.instruction(0x3000 | x << 8 | *n as u16)
+            }
+            (Comparison::NotEqual, Operand::Register(y)) => {
+                self.instruction(0x5000 | x << 8 | (*y as u16) << 4)
+            }
+            (comparison, operand) => {
+                // Subtract into vf, the borrow flag tells the result
+                let (subtract, true_when_flag_set) = match (comparison, operand) {
+                    (Comparison::Less, Operand::Register(_)) => (0x5, false),
+                    (Comparison::Greater, Operand::Register(_)) => (0x7, false),
+                    (Comparison::GreaterEqual, Operand::Register(_)) => (0x5, true),
+                    (Comparison::LessEqual, Operand::Register(_)) => (0x7, true),
+                    (Comparison::Less, _) => (0x7, false),
+                    (Comparison::Greater, _) => (0x5, false),
+                    (Comparison::GreaterEqual, _) => (0x7, true),
+                    _ => (0x5, true),
+                };
+
+                match operand {
+                    Operand::Register(y) => {
+                        self.instruction(0x8F00 | x << 4)?;
+                        self.instruction(0x8F00 | (*y as u16) << 4 | subtract)?;
+                    }
+                    Operand::Byte(n) => {
+                        self.instruction(0x6F00 | *n as u16)?;
+                        self.instruction(0x8F00 | x << 4 | subtract)?;
+                    }
+                    Operand::None => unreachable!(),
+                }
+
+                if true_when_flag_set {
+                    self.instruction(0x3F00)
+                } else {
+                    self.instruction(0x4F00)
+                }
+            }
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), OctoError> {
+        let condition = self.condition()?;
+        let keyword = self.next()?;
+
+        match keyword.as_str() {
+            "then" => self.skip_unless(&condition),
+            "begin" => {
+                self.skip_unless(&Condition {
+                    comparison: condition.comparison.negate(),
+                    ..condition
+                })?;
+                self.branches.push(self.here);
+                self.instruction(0x1000)
+            }
+            _ => self.error(format!("Expected 'then' or 'begin', got '{}'", keyword)),
+        }
+    }
+
+    fn patch_jump(&mut self, jump: usize, target: usize) -> Result<(), OctoError> {
+        if target > 0xFFF {
+            return self.error(format!("Jump target {:X} does not fit in 12 bits", target));
+        }
+
+        self.write(jump, 0x10 | (target >> 8) as u8)?;
+        self.write(jump + 1, target as u8)
+    }
+
+    // Tokens up to the matching }, the { is already consumed
+    fn block(&mut self) -> Result<Vec<Token>, OctoError> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return self.error(String::from("Missing '}'")),
+            };
+
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+
+            body.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+
+        let body = self.block()?;
+        self.macros.insert(name, Macro { args, body });
+
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), OctoError> {
+        let arg_count = self.macros[name].args.len();
+        let mut values = HashMap::new();
+
+        for i in 0..arg_count {
+            let value = self.next()?;
+            values.insert(self.macros[name].args[i].clone(), value);
+        }
+
+        self.macro_calls += 1;
+        self.constants
+            .insert(String::from("CALLS"), self.macro_calls as f64);
+
+        let line = self.line;
+        let expanded: Vec<Token> = self.macros[name]
+            .body
+            .iter()
+            .map(|token| Token {
+                text: values.get(&token.text).unwrap_or(&token.text).clone(),
+                line,
+            })
+            .collect();
+
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+
+        Ok(())
+    }
+
+    // :calc expressions, evaluated right to left without precedence like Octo does
+    fn calc_block(&mut self) -> Result<f64, OctoError> {
+        let tokens = self.block()?;
+        let mut position = 0;
+
+        let value = self.calc_expression(&tokens, &mut position)?;
+
+        if position != tokens.len() {
+            return self.error(format!(
+                "Unexpected '{}' in expression",
+                tokens[position].text
+            ));
+        }
+
+        Ok(value)
+    }
+
+    fn calc_expression(&self, tokens: &[Token], position: &mut usize) -> Result<f64, OctoError> {
+        let left = self.calc_term(tokens, position)?;
+
+        let op = match tokens.get(*position) {
+            Some(token) if token.text != ")" => token.text.clone(),
+            _ => return Ok(left),
+        };
+        *position += 1;
+
+        let right = self.calc_expression(tokens, position)?;
+
+        let value = match op.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => ((left as i64) & (right as i64)) as f64,
+            "|" => ((left as i64) | (right as i64)) as f64,
+            "^" => ((left as i64) ^ (right as i64)) as f64,
+            "<<" => ((left as i64) << (right as i64)) as f64,
+            ">>" => ((left as i64) >> (right as i64)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => return self.error(format!("Unknown operator '{}' in expression", op)),
+        };
+
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize) -> Result<f64, OctoError> {
+        let token = match tokens.get(*position) {
+            Some(token) => token.text.as_str(),
+            None => return self.error(String::from("Expression ended early")),
+        };
+        *position += 1;
+
+        let unary = |f: fn(f64) -> f64, position: &mut usize| -> Result<f64, OctoError> {
+            Ok(f(self.calc_term(tokens, position)?))
+        };
+
+        match token {
+            "(" => {
+                let value = self.calc_expression(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(token) if token.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => self.error(String::from("Missing ')' in expression")),
+                }
+            }
+            "-" => unary(|v| -v, position),
+            "~" => unary(|v| !(v as i64) as f64, position),
+            "!" => unary(|v| (v == 0.0) as u8 as f64, position),
+            "sin" => unary(f64::sin, position),
+            "cos" => unary(f64::cos, position),
+            "tan" => unary(f64::tan, position),
+            "exp" => unary(f64::exp, position),
+            "log" => unary(f64::ln, position),
+            "abs" => unary(f64::abs, position),
+            "sqrt" => unary(f64::sqrt, position),
+            "sign" => unary(f64::signum, position),
+            "ceil" => unary(f64::ceil, position),
+            "floor" => unary(f64::floor, position),
+            "@" => {
+                let address = self.calc_term(tokens, position)? as usize;
+                Ok(address
+                    .checked_sub(START_ADDRESS)
+                    .and_then(|index| self.rom.get(index))
+                    .copied()
+                    .unwrap_or(0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => match self.known_value(token) {
+                Some(value) => Ok(value),
+                None => self.error(format!("Undefined name '{}' in expression", token)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2)
+            .map(|pair| (pair[0] as u16) << 8 | *pair.get(1).unwrap_or(&0) as u16)
+            .collect()
+    }
+
+    fn assemble_words(source: &str) -> Vec<u16> {
+        words(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn test_main_and_basic_instructions() {
+        let rom = assemble_words(
+            ": main
+                clear
+                v0 := 5  v1 := v0  v2 += 3  v3 -= 1
+                v4 |= v5 v4 &= v5 v4 ^= v5 v4 += v5 v4 -= v5 v4 >>= v5 v4 =- v5 v4 <<= v5
+                i := 0x300  i += v1  i := hex v2
+                sprite v0 v1 5
+                bcd v3 save v3 load v3
+                delay := v1 buzzer := v1 v6 := delay v7 := key v8 := random 0x0F
+                jump0 0x400
+                return",
+        );
+
+        assert_eq!(
+            rom,
+            [
+                0x1202, 0x00E0, 0x6005, 0x8100, 0x7203, 0x73FF, 0x8451, 0x8452, 0x8453, 0x8454,
+                0x8455, 0x8456, 0x8457, 0x845E, 0xA300, 0xF11E, 0xF229, 0xD015, 0xF333, 0xF355,
+                0xF365, 0xF115, 0xF118, 0xF607, 0xF70A, 0xC80F, 0xB400, 0x00EE,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_labels_calls_and_data() {
+        let rom = assemble_words(
+            ": main
+                i := sprite-data
+                draw
+                jump main
+            : draw ;
+            : sprite-data 0xFF 0x81",
+        );
+
+        assert_eq!(rom, [0x1202, 0xA20A, 0x2208, 0x1202, 0x00EE, 0xFF81]);
+    }
+
+    #[test]
+    fn test_if_then_and_begin_else_end() {
+        let rom = assemble_words(
+            ": main
+                if v0 == 3 then v1 := 1
+                if v0 != v2 then v1 := 2
+                if v3 key then v1 := 3
+                if v0 == 4 begin v1 := 4 else v1 := 5 end",
+        );
+
+        assert_eq!(
+            rom,
+            [
+                0x1202, 0x4003, 0x6101, 0x5020, 0x6102, 0xE3A1, 0x6103,
+                // if v0 == 4 begin: skip the jump to else when the condition holds
+                0x3004, 0x1216, 0x6104, 0x1218, 0x6105,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loop_while_again() {
+        let rom = assemble_words(
+            ": main
+                loop
+                    v0 += 1
+                    while v0 != 10
+                again",
+        );
+
+        // Leave the loop when the condition fails
+        assert_eq!(rom, [0x1202, 0x7001, 0x400A, 0x120A, 0x1202]);
+    }
+
+    #[test]
+    fn test_comparisons_use_vf() {
+        let rom = assemble_words(
+            ": main
+                if v1 < v2 then ;
+                if v1 >= 5 then ;",
+        );
+
+        assert_eq!(
+            rom,
+            [0x1202, 0x8F10, 0x8F25, 0x4F00, 0x00EE, 0x6F05, 0x8F17, 0x3F00, 0x00EE]
+        );
+    }
+
+    #[test]
+    fn test_const_alias_calc_macro_unpack() {
+        let rom = assemble_words(
+            ":const SPEED 3
+            :alias px v4
+            :calc DOUBLE { SPEED * 2 }
+            :calc ORDER { 2 * 3 + 1 }
+            :macro move reg amount { reg += amount }
+            : main
+                px := SPEED
+                move px DOUBLE
+                v0 := ORDER
+                :unpack 0xA data
+            : data 1 2",
+        );
+
+        assert_eq!(
+            rom,
+            [0x1202, 0x6403, 0x7406, 0x6008, 0x60A2, 0x610C, 0x0102]
+        );
+    }
+
+    #[test]
+    fn test_org_and_byte_and_comments() {
+        let rom = assemble(
+            ": main ; # returns right away
+            :org 0x206
+            :byte 7 :byte { 1 << 4 }",
+        )
+        .unwrap();
+
+        assert_eq!(rom, [0x12, 0x02, 0x00, 0xEE, 0x00, 0x00, 0x07, 0x10]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("v0 := 1", "This program is missing a 'main' label"),
+            (": main jump nowhere", "Undefined name 'nowhere'"),
+            (": main : main", "The label 'main' has already been defined"),
+            (": main v0 := 256", "The value 256 does not fit in a byte"),
+            (": main loop v0 += 1", "The loop at 202 is missing 'again'"),
+            (": main v0 += v1 v1", "Unexpected end of program"),
+        ];
+
+        for (source, message) in cases {
+            let err = assemble(source).unwrap_err();
+            assert_eq!(err.message, message, "{}", source);
+        }
+
+        assert_eq!(assemble(": main\n\n  jump 0x1000").unwrap_err().line, 3);
+    }
+}
@@ -0,0 +1,189 @@
+// Phosphor persistence, smooths out the flicker of sprites that get erased and redrawn every frame.
+// Sits between the frame buffer and the display, it doesn't need a browser either.
+use super::frame_buffer::Chip8FrameBuffer;
+
+const PIXEL_COUNT: usize = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as usize
+    * Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PersistenceMode {
+    // Show every frame as it is
+    #[default]
+    Off,
+    // Show pixels that are on in this frame or the one before
+    Blend,
+    // Pixels that turn off fade out over this many frames
+    Decay {
+        frames: u8,
+    },
+}
+
+impl PersistenceMode {
+    pub const DEFAULT_DECAY_FRAMES: u8 = 4;
+
+    // "off", "blend" or "decay"
+    pub fn from_name(name: &str, decay_frames: u8) -> Option<PersistenceMode> {
+        let mode = match name {
+            "off" => PersistenceMode::Off,
+            "blend" => PersistenceMode::Blend,
+            "decay" => PersistenceMode::Decay {
+                frames: decay_frames.max(1),
+            },
+            _ => return None,
+        };
+
+        Some(mode)
+    }
+}
+
+pub struct Chip8Persistence {
+    mode: PersistenceMode,
+    // Last palette index every pixel was lit with
+    colors: [u8; PIXEL_COUNT],
+    intensity: [u8; PIXEL_COUNT],
+    previous: [u8; PIXEL_COUNT],
+    // Palette index and intensity for every pixel, what the display shows
+    texels: [u8; PIXEL_COUNT * 2],
+    // The next present changes the picture even if the frame doesn't
+    animating: bool,
+}
+
+impl Chip8Persistence {
+    pub fn new() -> Chip8Persistence {
+        Chip8Persistence {
+            mode: PersistenceMode::Off,
+            colors: [0; PIXEL_COUNT],
+            intensity: [0; PIXEL_COUNT],
+            previous: [0; PIXEL_COUNT],
+            texels: [0; PIXEL_COUNT * 2],
+            animating: false,
+        }
+    }
+
+    pub fn mode(&self) -> PersistenceMode {
+        self.mode
+    }
+
+    // Starts from the next frame as is, nothing of the old mode lingers
+    pub fn set_mode(&mut self, mode: PersistenceMode) {
+        self.mode = mode;
+        self.colors = [0; PIXEL_COUNT];
+        self.intensity = [0; PIXEL_COUNT];
+        self.previous = [0; PIXEL_COUNT];
+        self.animating = true;
+    }
+
+    // Blended frames or fading pixels still have to be presented when the screen doesn't change
+    pub fn is_animating(&self) -> bool {
+        self.animating
+    }
+
+    // Mix a new frame in, returns (palette index, intensity) pairs in video buffer order
+    pub fn present(&mut self, frame_buffer: &Chip8FrameBuffer) -> &[u8] {
+        let pixels = frame_buffer.video_buffer();
+        self.animating = false;
+
+        for (i, &pixel) in pixels.iter().enumerate() {
+            let (color, intensity) = match self.mode {
+                PersistenceMode::Off => (pixel, 0xFF),
+                PersistenceMode::Blend => {
+                    let color = pixel | self.previous[i];
+                    self.animating |= self.previous[i] != pixel;
+                    self.previous[i] = pixel;
+                    (color, 0xFF)
+                }
+                PersistenceMode::Decay { frames } => {
+                    if pixel != 0 {
+                        self.colors[i] = pixel;
+                        self.intensity[i] = 0xFF;
+                    } else {
+                        let step = (0xFF / frames.max(1) as u16) as u8;
+                        self.intensity[i] = self.intensity[i].saturating_sub(step.max(1));
+                        self.animating |= self.intensity[i] > 0;
+                    }
+                    (self.colors[i], self.intensity[i])
+                }
+            };
+
+            self.texels[i * 2] = color;
+            self.texels[i * 2 + 1] = intensity;
+        }
+
+        &self.texels
+    }
+}
+
+impl Default for Chip8Persistence {
+    fn default() -> Self {
+        Chip8Persistence::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(lit: bool) -> Chip8FrameBuffer {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+        if lit {
+            frame_buffer.xor_pixel(0, 31, Chip8FrameBuffer::PIXEL_ON);
+        }
+        frame_buffer
+    }
+
+    // Texel of the pixel `frame` lights, the first one in the buffer
+    fn first_texel(persistence: &mut Chip8Persistence, lit: bool) -> (u8, u8) {
+        let texels = persistence.present(&frame(lit));
+        (texels[0], texels[1])
+    }
+
+    #[test]
+    fn test_off_shows_frames_as_they_are() {
+        let mut persistence = Chip8Persistence::new();
+
+        assert_eq!(first_texel(&mut persistence, true), (1, 0xFF));
+        assert_eq!(first_texel(&mut persistence, false), (0, 0xFF));
+        assert!(!persistence.is_animating());
+    }
+
+    #[test]
+    fn test_blend_ors_the_last_two_frames() {
+        let mut persistence = Chip8Persistence::new();
+        persistence.set_mode(PersistenceMode::Blend);
+
+        assert_eq!(first_texel(&mut persistence, true), (1, 0xFF));
+        assert!(persistence.is_animating());
+        assert_eq!(first_texel(&mut persistence, false), (1, 0xFF));
+        assert!(persistence.is_animating());
+        assert_eq!(first_texel(&mut persistence, false), (0, 0xFF));
+        assert!(!persistence.is_animating());
+    }
+
+    #[test]
+    fn test_decay_fades_out() {
+        let mut persistence = Chip8Persistence::new();
+        persistence.set_mode(PersistenceMode::Decay { frames: 3 });
+
+        assert_eq!(first_texel(&mut persistence, true), (1, 0xFF));
+        assert_eq!(first_texel(&mut persistence, false), (1, 0xAA));
+        assert_eq!(first_texel(&mut persistence, false), (1, 0x55));
+        assert!(persistence.is_animating());
+        assert_eq!(first_texel(&mut persistence, false), (1, 0x00));
+        assert!(!persistence.is_animating());
+        // Lighting up again is instant
+        assert_eq!(first_texel(&mut persistence, true), (1, 0xFF));
+    }
+
+    #[test]
+    fn test_mode_from_name() {
+        assert_eq!(
+            PersistenceMode::from_name("decay", 0),
+            Some(PersistenceMode::Decay { frames: 1 })
+        );
+        assert_eq!(
+            PersistenceMode::from_name("blend", 4),
+            Some(PersistenceMode::Blend)
+        );
+        assert_eq!(PersistenceMode::from_name("glow", 4), None);
+    }
+}
@@ -0,0 +1,263 @@
+// Records the screen to an animated GIF. Frames are kept as palette indices and only encoded
+// when the recording is finished, so recording itself costs next to nothing.
+use super::config::Rgb;
+use super::display::full_palette;
+use super::frame_buffer::Chip8FrameBuffer;
+
+const WIDTH: usize = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as usize;
+const HEIGHT: usize = Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as usize;
+const FRAMES_PER_SECOND: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GifRecorderOptions {
+    // Every chip8 pixel becomes a scale x scale square
+    pub scale: u32,
+    // Frames skipped after every recorded one, 1 records at 30 fps
+    pub frame_skip: u32,
+    // Stop recording after this many seconds
+    pub max_seconds: f64,
+}
+
+impl GifRecorderOptions {
+    pub const MAX_SCALE: u32 = 16;
+}
+
+impl Default for GifRecorderOptions {
+    fn default() -> Self {
+        GifRecorderOptions {
+            scale: 4,
+            frame_skip: 1,
+            max_seconds: 30.0,
+        }
+    }
+}
+
+struct RecordedFrame {
+    // Palette index per pixel, top row first
+    pixels: Vec<u8>,
+    // How long it shows, in 60 Hz frames
+    ticks: u32,
+}
+
+pub struct Chip8GifRecorder {
+    palette: Vec<Rgb>,
+    options: GifRecorderOptions,
+    frames: Vec<RecordedFrame>,
+    // Frames seen since the recording started
+    ticks: u32,
+    max_ticks: u32,
+}
+
+impl Chip8GifRecorder {
+    pub fn new(palette: &[Rgb], options: GifRecorderOptions) -> Result<Chip8GifRecorder, String> {
+        if options.scale == 0 || options.scale > GifRecorderOptions::MAX_SCALE {
+            return Err(format!(
+                "The recording scale has to be between 1 and {}, got {}",
+                GifRecorderOptions::MAX_SCALE,
+                options.scale
+            ));
+        }
+
+        if options.max_seconds.is_nan() || options.max_seconds <= 0.0 {
+            return Err(format!(
+                "The recording has to be longer than 0 seconds, got {}",
+                options.max_seconds
+            ));
+        }
+
+        Ok(Chip8GifRecorder {
+            palette: palette.to_vec(),
+            options,
+            frames: Vec::new(),
+            ticks: 0,
+            max_ticks: (options.max_seconds * FRAMES_PER_SECOND as f64).ceil() as u32,
+        })
+    }
+
+    // The maximum duration is reached, further frames are ignored
+    pub fn is_full(&self) -> bool {
+        self.ticks >= self.max_ticks
+    }
+
+    // Call once per 60 Hz frame
+    pub fn record_frame(&mut self, frame_buffer: &Chip8FrameBuffer) {
+        if self.is_full() {
+            return;
+        }
+
+        let skipped = self.ticks % self.options.frame_skip.saturating_add(1) != 0;
+        self.ticks += 1;
+
+        if skipped {
+            if let Some(last) = self.frames.last_mut() {
+                last.ticks += 1;
+            }
+            return;
+        }
+
+        let pixels: Vec<u8> = frame_buffer
+            .video_buffer()
+            .chunks(WIDTH)
+            .rev()
+            .flatten()
+            .map(|pixel| pixel & 0x0F)
+            .collect();
+
+        match self.frames.last_mut() {
+            // Nothing moved, show the last frame for longer
+            Some(last) if last.pixels == pixels => last.ticks += 1,
+            _ => self.frames.push(RecordedFrame { pixels, ticks: 1 }),
+        }
+    }
+
+    pub fn duration_seconds(&self) -> f64 {
+        self.ticks as f64 / FRAMES_PER_SECOND as f64
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        if self.frames.is_empty() {
+            return Err(String::from("Nothing was recorded"));
+        }
+
+        let scale = self.options.scale as usize;
+        let palette: Vec<u8> = full_palette(&self.palette)
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+
+        let mut gif_bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(
+                &mut gif_bytes,
+                (WIDTH * scale) as u16,
+                (HEIGHT * scale) as u16,
+                &palette,
+            )
+            .map_err(|err| err.to_string())?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(|err| err.to_string())?;
+
+            // GIF delays are in 1/100 s, round the running time so the clip doesn't drift
+            let mut ticks = 0;
+            for frame in &self.frames {
+                let start = ticks * 100 / FRAMES_PER_SECOND;
+                ticks += frame.ticks;
+                let delay = ticks * 100 / FRAMES_PER_SECOND - start;
+
+                let pixels: Vec<u8> = frame
+                    .pixels
+                    .chunks(WIDTH)
+                    .flat_map(|row| {
+                        let row: Vec<u8> = row
+                            .iter()
+                            .flat_map(|pixel| std::iter::repeat(*pixel).take(scale))
+                            .collect();
+                        std::iter::repeat(row).take(scale).flatten()
+                    })
+                    .collect();
+
+                let mut gif_frame = gif::Frame::from_indexed_pixels(
+                    (WIDTH * scale) as u16,
+                    (HEIGHT * scale) as u16,
+                    pixels,
+                    None,
+                );
+                gif_frame.delay = delay as u16;
+
+                encoder
+                    .write_frame(&gif_frame)
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+
+        Ok(gif_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: [Rgb; 2] = [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF]];
+
+    // Frames and their delays
+    fn decode(gif_bytes: &[u8]) -> (u16, u16, Vec<(Vec<u8>, u16)>) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif_bytes).unwrap();
+        let (width, height) = (decoder.width(), decoder.height());
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.buffer.to_vec(), frame.delay));
+        }
+
+        (width, height, frames)
+    }
+
+    fn options(scale: u32, frame_skip: u32, max_seconds: f64) -> GifRecorderOptions {
+        GifRecorderOptions {
+            scale,
+            frame_skip,
+            max_seconds,
+        }
+    }
+
+    #[test]
+    fn test_records_changes_with_delays() {
+        let mut recorder = Chip8GifRecorder::new(&PALETTE, options(2, 0, 10.0)).unwrap();
+        let mut frame_buffer = Chip8FrameBuffer::new();
+
+        recorder.record_frame(&frame_buffer);
+        recorder.record_frame(&frame_buffer);
+        recorder.record_frame(&frame_buffer);
+        frame_buffer.xor_pixel(0, 0, Chip8FrameBuffer::PIXEL_ON);
+        recorder.record_frame(&frame_buffer);
+
+        let (width, height, frames) = decode(&recorder.finish().unwrap());
+
+        assert_eq!((width, height), (128, 64));
+        // The 3 identical frames are merged into one of 3/60 s
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].1, 5);
+        assert_eq!(frames[1].1, 1);
+        // Top left chip8 pixel is a 2x2 square
+        let pixels = &frames[1].0;
+        assert_eq!(&pixels[0..3], [1, 1, 0]);
+        assert_eq!(&pixels[128..131], [1, 1, 0]);
+        assert_eq!(pixels[256], 0);
+    }
+
+    #[test]
+    fn test_frame_skip_and_max_duration() {
+        // 0.1 s is 6 frames, every other one is recorded
+        let mut recorder = Chip8GifRecorder::new(&PALETTE, options(1, 1, 0.1)).unwrap();
+        let mut frame_buffer = Chip8FrameBuffer::new();
+
+        for i in 0..10 {
+            frame_buffer.xor_pixel(i, 0, Chip8FrameBuffer::PIXEL_ON);
+            recorder.record_frame(&frame_buffer);
+        }
+
+        assert!(recorder.is_full());
+        assert_eq!(recorder.duration_seconds(), 0.1);
+
+        let (_, _, frames) = decode(&recorder.finish().unwrap());
+        assert_eq!(frames.len(), 3);
+        // Pixels 0, 2 and 4 were recorded last
+        assert_eq!(&frames[2].0[0..6], [1, 1, 1, 1, 1, 0]);
+        let total: u16 = frames.iter().map(|frame| frame.1).sum();
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn test_invalid_options_and_empty_recording() {
+        assert!(Chip8GifRecorder::new(&PALETTE, options(0, 0, 1.0)).is_err());
+        assert!(Chip8GifRecorder::new(&PALETTE, options(1, 0, 0.0)).is_err());
+
+        let recorder = Chip8GifRecorder::new(&PALETTE, GifRecorderOptions::default()).unwrap();
+        assert_eq!(recorder.finish(), Err(String::from("Nothing was recorded")));
+    }
+}
@@ -0,0 +1,189 @@
+// Ways of getting ROM bytes, these only read the bytes, `Chip8::load_rom_bytes` puts them in memory.
+use std::{fmt, path::Path};
+
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{DataTransfer, File as WasmFile, Response};
+
+use super::config::Chip8Platform;
+use super::cpu::Chip8CPU;
+
+// Reasons a ROM is not loaded, memory is left untouched when one of these is returned.
+#[derive(Debug, PartialEq)]
+pub enum RomLoadError {
+    Empty,
+    TooLarge { size: usize, max_size: usize },
+    ReadFailed(String),
+    UnsupportedFormat(String),
+    InvalidCartridge(String),
+}
+
+impl fmt::Display for RomLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomLoadError::Empty => write!(f, "The ROM file is empty"),
+            RomLoadError::TooLarge { size, max_size } => write!(
+                f,
+                "The ROM is {} bytes, but at most {} bytes fit in memory ({} bytes too many)",
+                size,
+                max_size,
+                size - max_size
+            ),
+            RomLoadError::ReadFailed(reason) => write!(f, "Could not read the ROM: {}", reason),
+            RomLoadError::UnsupportedFormat(format) => {
+                write!(f, "This is not a CHIP-8 ROM but {}", format)
+            }
+            RomLoadError::InvalidCartridge(reason) => {
+                write!(f, "Could not load the Octo cartridge: {}", reason)
+            }
+        }
+    }
+}
+
+// Shows up as a regular `Error` on the JS side.
+impl From<RomLoadError> for JsValue {
+    fn from(err: RomLoadError) -> JsValue {
+        js_sys::Error::new(&err.to_string()).into()
+    }
+}
+
+// The ROM is loaded, but probably won't run as expected.
+#[derive(Debug, PartialEq)]
+pub enum RomLoadWarning {
+    OddSize(usize),
+    InvalidFirstOpcode(u16),
+    // Made for a platform whose extra instructions aren't emulated
+    UnsupportedPlatform(Chip8Platform),
+}
+
+impl fmt::Display for RomLoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomLoadWarning::OddSize(size) => write!(
+                f,
+                "The ROM is {} bytes, instructions are 2 bytes so the last byte is not a full instruction",
+                size
+            ),
+            RomLoadWarning::InvalidFirstOpcode(opcode) => write!(
+                f,
+                "The ROM starts with {:04X}, which is not a CHIP-8 instruction",
+                opcode
+            ),
+            RomLoadWarning::UnsupportedPlatform(platform) => write!(
+                f,
+                "The ROM is made for {}, it runs as plain CHIP-8 and may not work",
+                platform.id()
+            ),
+        }
+    }
+}
+
+// Check a ROM before it is copied to memory.
+pub fn validate(rom: &[u8], max_size: usize) -> Result<Vec<RomLoadWarning>, RomLoadError> {
+    if rom.is_empty() {
+        return Err(RomLoadError::Empty);
+    }
+
+    if let Some(format) = detect_other_format(rom) {
+        return Err(RomLoadError::UnsupportedFormat(format.to_string()));
+    }
+
+    if rom.len() > max_size {
+        return Err(RomLoadError::TooLarge {
+            size: rom.len(),
+            max_size,
+        });
+    }
+
+    let mut warnings = Vec::new();
+
+    if rom.len() % 2 != 0 {
+        warnings.push(RomLoadWarning::OddSize(rom.len()));
+    }
+
+    let first_opcode = if rom.len() >= 2 {
+        ((rom[0] as u16) << 8) | rom[1] as u16
+    } else {
+        (rom[0] as u16) << 8
+    };
+
+    if !Chip8CPU::is_valid_opcode(first_opcode) {
+        warnings.push(RomLoadWarning::InvalidFirstOpcode(first_opcode));
+    }
+
+    Ok(warnings)
+}
+
+// Files people commonly pick by accident instead of a ROM.
+fn detect_other_format(rom: &[u8]) -> Option<&'static str> {
+    if rom.starts_with(b"GIF87a") || rom.starts_with(b"GIF89a") {
+        Some("a GIF image (Octo cartridge?)")
+    } else if rom.starts_with(b"PK\x03\x04") {
+        Some("a ZIP archive, extract the ROM first")
+    } else if rom.starts_with(b"\x89PNG") {
+        Some("a PNG image")
+    } else if std::str::from_utf8(rom).is_ok_and(|text| text.contains(": main")) {
+        Some("Octo source code, compile it to a .ch8 first")
+    } else {
+        None
+    }
+}
+
+fn js_error_message(err: JsValue) -> String {
+    err.as_string()
+        .or_else(|| {
+            err.dyn_into::<js_sys::Error>()
+                .ok()
+                .map(|e| e.message().into())
+        })
+        .unwrap_or_else(|| String::from("unknown error"))
+}
+
+// Read a JS File (from a file input or a drop) as binary.
+pub async fn read_file(file: &WasmFile) -> Result<Vec<u8>, RomLoadError> {
+    let array = JsFuture::from(file.array_buffer())
+        .await
+        .map_err(|err| RomLoadError::ReadFailed(js_error_message(err)))?;
+
+    Ok(js_sys::Uint8Array::new(&array).to_vec())
+}
+
+// Fetch a ROM from a url, relative urls are resolved against the page.
+pub async fn fetch(url: &str) -> Result<Vec<u8>, RomLoadError> {
+    let window = web_sys::window()
+        .ok_or_else(|| RomLoadError::ReadFailed(String::from("no global `window` exists")))?;
+
+    let response: Response = JsFuture::from(window.fetch_with_str(url))
+        .await
+        .and_then(|response| response.dyn_into())
+        .map_err(|err| RomLoadError::ReadFailed(js_error_message(err)))?;
+
+    if !response.ok() {
+        return Err(RomLoadError::ReadFailed(format!(
+            "fetching {} failed with status {}",
+            url,
+            response.status()
+        )));
+    }
+
+    let array = match response.array_buffer() {
+        Ok(promise) => JsFuture::from(promise).await,
+        Err(err) => Err(err),
+    }
+    .map_err(|err| RomLoadError::ReadFailed(js_error_message(err)))?;
+
+    Ok(js_sys::Uint8Array::new(&array).to_vec())
+}
+
+// First file of a drag and drop, if anything but text got dropped.
+pub fn data_transfer_file(data_transfer: &DataTransfer) -> Option<WasmFile> {
+    data_transfer.files()?.item(0)
+}
+
+// Read a ROM from disk, for running outside of the browser.
+pub fn read_path<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, RomLoadError> {
+    let path = path.as_ref();
+
+    std::fs::read(path)
+        .map_err(|err| RomLoadError::ReadFailed(format!("{}: {}", path.display(), err)))
+}
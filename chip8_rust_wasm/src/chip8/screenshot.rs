@@ -0,0 +1,105 @@
+// Screenshots as PNG, drawn from the frame buffer instead of the canvas so they stay crisp.
+use super::config::Rgb;
+use super::display::texels_to_rgba;
+use super::frame_buffer::Chip8FrameBuffer;
+
+// 4096x2048, plenty for docs and bug reports
+pub const MAX_SCALE: u32 = 64;
+
+// PNG of the screen in the given palette, every chip8 pixel becomes a scale x scale square.
+pub fn encode_png(
+    frame_buffer: &Chip8FrameBuffer,
+    palette: &[Rgb],
+    scale: u32,
+) -> Result<Vec<u8>, String> {
+    if scale == 0 || scale > MAX_SCALE {
+        return Err(format!(
+            "The screenshot scale has to be between 1 and {}, got {}",
+            MAX_SCALE, scale
+        ));
+    }
+
+    let width = Chip8FrameBuffer::CHIP8_DISPLAY_WIDTH as u32;
+    let height = Chip8FrameBuffer::CHIP8_DISPLAY_HEIGHT as u32;
+
+    let texels: Vec<u8> = frame_buffer
+        .video_buffer()
+        .iter()
+        .flat_map(|pixel| [*pixel, 0xFF])
+        .collect();
+    let rgba = texels_to_rgba(&texels, palette);
+
+    let mut image = Vec::with_capacity((width * height * scale * scale * 3) as usize);
+    for row in rgba.chunks(width as usize * 4) {
+        let scaled_row: Vec<u8> = row
+            .chunks(4)
+            .flat_map(|pixel| {
+                std::iter::repeat(&pixel[..3])
+                    .take(scale as usize)
+                    .flatten()
+            })
+            .copied()
+            .collect();
+
+        for _ in 0..scale {
+            image.extend_from_slice(&scaled_row);
+        }
+    }
+
+    let mut png_bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_bytes, width * scale, height * scale);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+        writer
+            .write_image_data(&image)
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(png_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(png_bytes: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let decoder = png::Decoder::new(png_bytes);
+        let mut reader = decoder.read_info().unwrap();
+        let mut image = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut image).unwrap();
+
+        (info, image)
+    }
+
+    #[test]
+    fn test_encode_png_scales_with_palette() {
+        let mut frame_buffer = Chip8FrameBuffer::new();
+        frame_buffer.xor_pixel(0, 0, Chip8FrameBuffer::PIXEL_ON);
+        let palette = [[0x10, 0x20, 0x30], [0xF0, 0xE0, 0xD0]];
+
+        let (info, image) = decode(&encode_png(&frame_buffer, &palette, 3).unwrap());
+
+        assert_eq!((info.width, info.height), (64 * 3, 32 * 3));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+
+        let pixel = |x: usize, y: usize| &image[(y * 64 * 3 + x) * 3..(y * 64 * 3 + x) * 3 + 3];
+        // The top left chip8 pixel is a 3x3 square
+        assert_eq!(pixel(0, 0), [0xF0, 0xE0, 0xD0]);
+        assert_eq!(pixel(2, 2), [0xF0, 0xE0, 0xD0]);
+        assert_eq!(pixel(3, 0), [0x10, 0x20, 0x30]);
+        assert_eq!(pixel(0, 3), [0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn test_encode_png_rejects_bad_scales() {
+        let frame_buffer = Chip8FrameBuffer::new();
+        let palette = [[0, 0, 0], [0xFF, 0xFF, 0xFF]];
+
+        assert!(encode_png(&frame_buffer, &palette, 0).is_err());
+        assert!(encode_png(&frame_buffer, &palette, MAX_SCALE + 1).is_err());
+        assert!(encode_png(&frame_buffer, &palette, 1).is_ok());
+    }
+}
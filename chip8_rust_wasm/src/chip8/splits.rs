@@ -0,0 +1,514 @@
+// A speedrun timer that starts, splits and resets by itself on conditions over the machine,
+// see `conditions`. It counts emulated frames rather than wall time, so a run times the same
+// on every computer and pausing the emulator pauses the timer. Split files are JSON:
+//
+//   {
+//     "game": "Blinky",
+//     "category": "Any%",
+//     "start": [ { "left": "m:3A0", "cmp": "==", "right": "1" } ],
+//     "reset": [ { "left": "m:3A0", "cmp": "==", "right": "0" } ],
+//     "splits": [
+//       { "name": "Level 2", "conditions": [ { "left": "m:3A0", "cmp": ">", "right": "prev m:3A0" } ] },
+//       { "name": "Level 3", "conditions": [ { "left": "m:3A0", "cmp": "==", "right": "3" } ], "best": 5400 }
+//     ]
+//   }
+//
+// "reset" is optional. "best" is the time of the split in the best run, in frames from the
+// start. It is filled in when a run beats it, see `Chip8Splits::to_json`.
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use super::conditions::{self, Condition, ConditionDefinition, MachineSnapshot, PreviousFrame};
+
+// The timer runs at the 60 Hz of the timers
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SplitsFile {
+    #[serde(default)]
+    game: String,
+    #[serde(default)]
+    category: String,
+    start: Vec<ConditionDefinition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reset: Vec<ConditionDefinition>,
+    splits: Vec<SplitDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SplitDefinition {
+    name: String,
+    conditions: Vec<ConditionDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    best: Option<u32>,
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitEventKind {
+    Start,
+    Split,
+    // The last split
+    Finish,
+    Reset,
+}
+
+// What the page hears about
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitEvent {
+    kind: SplitEventKind,
+    // The split that was reached, for splits and the finish
+    split: Option<u32>,
+    name: String,
+    // Since the start
+    frames: u32,
+    // A new best run
+    best: bool,
+}
+
+#[wasm_bindgen]
+impl SplitEvent {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> SplitEventKind {
+        self.kind
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn split(&self) -> Option<u32> {
+        self.split
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn best(&self) -> bool {
+        self.best
+    }
+}
+
+impl SplitEvent {
+    fn new(kind: SplitEventKind, frames: u32) -> SplitEvent {
+        SplitEvent {
+            kind,
+            split: None,
+            name: String::new(),
+            frames,
+            best: false,
+        }
+    }
+}
+
+// A split with its time in this run, for showing the splits
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitInfo {
+    name: String,
+    frames: Option<u32>,
+    best: Option<u32>,
+}
+
+#[wasm_bindgen]
+impl SplitInfo {
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn frames(&self) -> Option<u32> {
+        self.frames
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn best(&self) -> Option<u32> {
+        self.best
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerState {
+    NotStarted,
+    Running,
+    Finished,
+}
+
+pub struct Chip8Splits {
+    file: SplitsFile,
+    start: Vec<Condition>,
+    reset: Vec<Condition>,
+    // One list per split
+    splits: Vec<Vec<Condition>>,
+    state: TimerState,
+    frames: u32,
+    // Of the splits reached in this run
+    times: Vec<u32>,
+    // The machine at the end of the last frame, for "prev" operands
+    previous: PreviousFrame,
+}
+
+impl Default for Chip8Splits {
+    fn default() -> Self {
+        Chip8Splits::new()
+    }
+}
+
+fn parse_conditions(definitions: &[ConditionDefinition]) -> Result<Vec<Condition>, String> {
+    definitions.iter().map(Condition::from_definition).collect()
+}
+
+impl Chip8Splits {
+    // No splits, the timer never starts
+    pub fn new() -> Chip8Splits {
+        Chip8Splits {
+            file: SplitsFile {
+                game: String::new(),
+                category: String::new(),
+                start: Vec::new(),
+                reset: Vec::new(),
+                splits: Vec::new(),
+            },
+            start: Vec::new(),
+            reset: Vec::new(),
+            splits: Vec::new(),
+            state: TimerState::NotStarted,
+            frames: 0,
+            times: Vec::new(),
+            previous: PreviousFrame::default(),
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Chip8Splits, String> {
+        let file: SplitsFile =
+            serde_json::from_str(json).map_err(|err| format!("Invalid splits: {}", err))?;
+
+        if file.start.is_empty() {
+            return Err(String::from("The splits have no start conditions"));
+        }
+        if file.splits.is_empty() {
+            return Err(String::from("There are no splits"));
+        }
+
+        let start = parse_conditions(&file.start).map_err(|err| format!("Start: {}", err))?;
+        let reset = parse_conditions(&file.reset).map_err(|err| format!("Reset: {}", err))?;
+        let mut splits = Vec::new();
+        for split in &file.splits {
+            if split.conditions.is_empty() {
+                return Err(format!("Split {} has no conditions", split.name));
+            }
+
+            splits.push(
+                parse_conditions(&split.conditions)
+                    .map_err(|err| format!("Split {}: {}", split.name, err))?,
+            );
+        }
+
+        let previous =
+            PreviousFrame::new(start.iter().chain(&reset).chain(splits.iter().flatten()));
+
+        Ok(Chip8Splits {
+            file,
+            start,
+            reset,
+            splits,
+            previous,
+            ..Chip8Splits::new()
+        })
+    }
+
+    // The split file, with the best times of a new best run
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.file).expect("should serialize splits OK")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.splits.is_empty()
+    }
+
+    pub fn state(&self) -> TimerState {
+        self.state
+    }
+
+    // Since the start, or the time of the finished run
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn list(&self) -> Vec<SplitInfo> {
+        self.file
+            .splits
+            .iter()
+            .enumerate()
+            .map(|(index, split)| SplitInfo {
+                name: split.name.clone(),
+                frames: self.times.get(index).copied(),
+                best: split.best,
+            })
+            .collect()
+    }
+
+    // Call at the end of every frame
+    pub fn evaluate(&mut self, now: &MachineSnapshot) -> Option<SplitEvent> {
+        if !self.previous.is_recorded() {
+            self.previous.record(now);
+        }
+        let before = std::mem::take(&mut self.previous);
+        let event = self.update(now, &before);
+        self.previous = before;
+        self.previous.record(now);
+
+        event
+    }
+
+    fn update(&mut self, now: &MachineSnapshot, before: &PreviousFrame) -> Option<SplitEvent> {
+        if self.is_empty() {
+            return None;
+        }
+
+        if self.state == TimerState::Running {
+            self.frames = self.frames.saturating_add(1);
+        }
+
+        if self.state != TimerState::NotStarted
+            && !self.reset.is_empty()
+            && conditions::update_all(&mut self.reset, now, before)
+        {
+            return self.reset_timer();
+        }
+
+        match self.state {
+            TimerState::NotStarted => {
+                if !conditions::update_all(&mut self.start, now, before) {
+                    return None;
+                }
+
+                self.state = TimerState::Running;
+                self.frames = 0;
+                for condition in &mut self.reset {
+                    condition.reset();
+                }
+
+                Some(SplitEvent::new(SplitEventKind::Start, 0))
+            }
+            TimerState::Running => {
+                let index = self.times.len();
+                if !conditions::update_all(&mut self.splits[index], now, before) {
+                    return None;
+                }
+
+                self.times.push(self.frames);
+                let last = self.times.len() == self.splits.len();
+                let mut event = SplitEvent {
+                    kind: SplitEventKind::Split,
+                    split: Some(index as u32),
+                    name: self.file.splits[index].name.clone(),
+                    frames: self.frames,
+                    best: false,
+                };
+
+                if last {
+                    self.state = TimerState::Finished;
+                    event.kind = SplitEventKind::Finish;
+                    event.best = self.save_if_best();
+                }
+
+                Some(event)
+            }
+            TimerState::Finished => None,
+        }
+    }
+
+    // Keeps the split times of a finished run that beat the best one
+    fn save_if_best(&mut self) -> bool {
+        let best = self.file.splits.last().and_then(|split| split.best);
+        if best.is_some_and(|best| best <= self.frames) {
+            return false;
+        }
+
+        for (split, time) in self.file.splits.iter_mut().zip(&self.times) {
+            split.best = Some(*time);
+        }
+
+        true
+    }
+
+    // Back to waiting for the start, a reset event if a run was going on
+    pub fn reset_timer(&mut self) -> Option<SplitEvent> {
+        let event = match self.state {
+            TimerState::NotStarted => None,
+            _ => Some(SplitEvent::new(SplitEventKind::Reset, self.frames)),
+        };
+
+        self.state = TimerState::NotStarted;
+        self.frames = 0;
+        self.times.clear();
+        for condition in self
+            .start
+            .iter_mut()
+            .chain(&mut self.reset)
+            .chain(self.splits.iter_mut().flatten())
+        {
+            condition.reset();
+        }
+
+        event
+    }
+
+    // The game starts over, so does the run
+    pub fn restart(&mut self) -> Option<SplitEvent> {
+        self.previous.forget();
+        self.reset_timer()
+    }
+}
+
+// Frames as h:mm:ss.cc, without the hours for runs under one
+pub fn format_frames(frames: u32) -> String {
+    let centiseconds = frames as u64 * 100 / FRAMES_PER_SECOND as u64;
+    let seconds = centiseconds / 100;
+    let (hours, minutes) = (seconds / 3600, seconds / 60 % 60);
+
+    match hours {
+        0 => format!("{}:{:02}.{:02}", minutes, seconds % 60, centiseconds % 100),
+        _ => format!(
+            "{}:{:02}:{:02}.{:02}",
+            hours,
+            minutes,
+            seconds % 60,
+            centiseconds % 100
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::conditions::tests::TestMachine;
+
+    const SPLITS: &str = r#"{
+        "game": "Test",
+        "start": [ { "left": "m:3A0", "cmp": "==", "right": "1" } ],
+        "reset": [ { "left": "m:3A0", "cmp": "==", "right": "0" } ],
+        "splits": [
+            { "name": "Level 2", "conditions": [ { "left": "m:3A0", "cmp": ">", "right": "prev m:3A0" } ] },
+            { "name": "Level 3", "conditions": [ { "left": "m:3A0", "cmp": "==", "right": "3" } ] }
+        ]
+    }"#;
+
+    fn level(level: u8) -> TestMachine {
+        let mut machine = TestMachine::new();
+        machine.memory[0x3A0] = level;
+        machine
+    }
+
+    fn run(splits: &mut Chip8Splits, levels: &[u8]) -> Vec<SplitEvent> {
+        levels
+            .iter()
+            .filter_map(|value| splits.evaluate(&level(*value).snapshot()))
+            .collect()
+    }
+
+    #[test]
+    fn test_a_run_in_frames() {
+        let mut splits = Chip8Splits::parse(SPLITS).unwrap();
+
+        let events = run(&mut splits, &[1, 1, 1, 2, 2, 3]);
+        let kinds: Vec<SplitEventKind> = events.iter().map(|event| event.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                SplitEventKind::Start,
+                SplitEventKind::Split,
+                SplitEventKind::Finish
+            ]
+        );
+        assert_eq!(events[1].name(), "Level 2");
+        assert_eq!(events[1].frames(), 3);
+        assert_eq!(events[2].split(), Some(1));
+        assert_eq!(events[2].frames(), 5);
+        assert!(events[2].best());
+        assert_eq!(splits.state(), TimerState::Finished);
+
+        // The timer stops at the finish
+        run(&mut splits, &[3, 3]);
+        assert_eq!(splits.frames(), 5);
+        assert_eq!(splits.list()[0].frames(), Some(3));
+        assert_eq!(splits.list()[1].best(), Some(5));
+    }
+
+    #[test]
+    fn test_resets_and_best_runs() {
+        let mut splits = Chip8Splits::parse(SPLITS).unwrap();
+
+        let events = run(&mut splits, &[1, 2, 0]);
+        assert_eq!(events.last().unwrap().kind(), SplitEventKind::Reset);
+        assert_eq!(splits.state(), TimerState::NotStarted);
+        assert_eq!(splits.list()[0].frames(), None);
+
+        // The first finished run is the best one, until a faster one
+        run(&mut splits, &[1, 2, 3]);
+        let saved = Chip8Splits::parse(&splits.to_json()).unwrap();
+        assert_eq!(saved.list()[1].best(), Some(2));
+
+        let mut splits = saved;
+        let events = run(&mut splits, &[1, 1, 2, 2, 3]);
+        assert!(!events.last().unwrap().best());
+        assert_eq!(splits.list()[1].best(), Some(2));
+
+        // Resetting the machine resets the run
+        splits.reset_timer();
+        run(&mut splits, &[1]);
+        assert_eq!(
+            splits.restart().map(|event| event.kind()),
+            Some(SplitEventKind::Reset)
+        );
+        assert_eq!(splits.restart(), None);
+    }
+
+    // The split timer only keeps the bytes its "prev" operands read, and forgets them when the
+    // game starts over
+    #[test]
+    fn test_prev_starts_over_with_the_game() {
+        let mut splits = Chip8Splits::parse(
+            r#"{ "start": [ { "left": "m:3A0", "cmp": "<", "right": "prev m:3A0" } ],
+                 "splits": [ { "name": "End", "conditions": [ { "left": "m:3A0", "cmp": "==", "right": "9" } ] } ] }"#,
+        )
+        .unwrap();
+
+        // Nothing before the first frame to compare with
+        assert!(run(&mut splits, &[6]).is_empty());
+        splits.restart();
+        // Nor does the frame before the restart count
+        assert!(run(&mut splits, &[2]).is_empty());
+        assert_eq!(run(&mut splits, &[1])[0].kind(), SplitEventKind::Start);
+        assert_eq!(run(&mut splits, &[9])[0].kind(), SplitEventKind::Finish);
+    }
+
+    #[test]
+    fn test_invalid_splits() {
+        assert!(Chip8Splits::parse(r#"{ "start": [], "splits": [] }"#).is_err());
+        let error = Chip8Splits::parse(
+            r#"{ "start": [ { "left": "i", "cmp": "==", "right": "0" } ],
+                 "splits": [ { "name": "End", "conditions": [] } ] }"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error, "Split End has no conditions");
+    }
+
+    #[test]
+    fn test_format_frames() {
+        assert_eq!(format_frames(0), "0:00.00");
+        assert_eq!(format_frames(90), "0:01.50");
+        assert_eq!(format_frames(60 * 61 + 1), "1:01.01");
+        assert_eq!(format_frames(60 * 3600 * 2 + 60 * 65), "2:01:05.00");
+    }
+}
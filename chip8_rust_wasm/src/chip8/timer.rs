@@ -1,5 +0,0 @@
-pub struct Chip8AudioTimer {}
-
-pub struct Chip8DelayTimer {}
-
-trait Timeable {}
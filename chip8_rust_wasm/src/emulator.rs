@@ -0,0 +1,979 @@
+// The emulator as JS sees it. Owns the machine and the animation frame loop that runs it,
+// so the page can keep controlling a running game. Every emulator has its own canvas, key
+// events and audio, so a page can run as many as it likes.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    console, AudioNode, Blob, BlobPropertyBag, EventTarget, HtmlCanvasElement, KeyboardEvent,
+};
+
+use crate::chip8::achievements::{AchievementInfo, Chip8Achievements};
+use crate::chip8::audio::Chip8Audio;
+use crate::chip8::cheat_finder::{Chip8CheatFinder, SearchFilter, SearchResult};
+use crate::chip8::cheats::Chip8Cheats;
+use crate::chip8::config::{Chip8Config, PalettePreset, Rgb, ScalingMode};
+use crate::chip8::database::{parse_color, sha1_hex, RomInfo};
+use crate::chip8::display::{self, Chip8NullDisplay, DisplayTarget};
+use crate::chip8::persistence::PersistenceMode;
+use crate::chip8::recorder::GifRecorderOptions;
+use crate::chip8::splits::{Chip8Splits, SplitEvent, SplitInfo};
+use crate::chip8::Chip8;
+use crate::{
+    cancel_animation_frame, request_animation_frame, store_achievement, stored_achievements,
+};
+
+// Settings picked on the page, they win over the rom's and stay when another rom is loaded
+#[derive(Default)]
+struct Settings {
+    palette: Option<Vec<Rgb>>,
+    persistence: Option<PersistenceMode>,
+    scaling: Option<ScalingMode>,
+    speed: Option<u32>,
+}
+
+impl Settings {
+    fn apply(&self, machine: &mut Chip8) {
+        if let Some(palette) = &self.palette {
+            machine.set_palette(palette.clone());
+        }
+
+        if let Some(mode) = self.persistence {
+            machine.set_persistence(mode);
+        }
+
+        if let Some(scaling) = self.scaling {
+            machine.set_scaling(scaling);
+        }
+
+        if let Some(speed) = self.speed {
+            machine.set_tick_rate(speed);
+        }
+    }
+}
+
+type FrameCallback = Closure<dyn FnMut()>;
+type KeyListener = (&'static str, Closure<dyn FnMut(KeyboardEvent)>);
+
+// Listen for key presses on `target` and write them into the chip8 keypad
+fn listen_for_key(
+    target: &EventTarget,
+    event_name: &'static str,
+    machine: Rc<RefCell<Chip8>>,
+    pressed: bool,
+) -> KeyListener {
+    let listener = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+        set_key(&machine.borrow(), &event.code(), pressed);
+    }) as Box<dyn FnMut(KeyboardEvent)>);
+
+    target
+        .add_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref())
+        .expect("should register key listener OK");
+
+    (event_name, listener)
+}
+
+// Returns false for keys that aren't on the keypad
+fn set_key(machine: &Chip8, code: &str, pressed: bool) -> bool {
+    let input = machine.input();
+    let key = input.borrow().key_from_code(code);
+
+    match key {
+        Some(key) => {
+            input.borrow_mut().set_key(key, pressed);
+            true
+        }
+        None => false,
+    }
+}
+
+// Where the beeps go
+pub(crate) enum Buzzer {
+    Audio(Chip8Audio),
+    // Workers have no Web Audio, `callback(playing)` lets the page play it
+    Callback {
+        playing: bool,
+        callback: js_sys::Function,
+    },
+}
+
+impl Buzzer {
+    fn set_playing(&mut self, playing: bool) {
+        match self {
+            Buzzer::Audio(audio) => audio.set_playing(playing),
+            Buzzer::Callback {
+                playing: was_playing,
+                callback,
+            } => {
+                if *was_playing != playing {
+                    *was_playing = playing;
+                    let _ = callback.call1(&JsValue::NULL, &JsValue::from_bool(playing));
+                }
+            }
+        }
+    }
+
+    fn resume(&self) {
+        if let Buzzer::Audio(audio) = self {
+            audio.resume();
+        }
+    }
+
+    fn set_volume(&self, volume: f32) {
+        if let Buzzer::Audio(audio) = self {
+            audio.set_volume(volume);
+        }
+    }
+}
+
+// The buzzer to the speakers or `output`, None if the browser has no Web Audio
+pub(crate) fn create_audio(output: Option<AudioNode>) -> Option<Chip8Audio> {
+    match Chip8Audio::new(output) {
+        Ok(audio) => Some(audio),
+        Err(err) => {
+            console::warn_2(&JsValue::from_str("Playing without sound:"), &err);
+            None
+        }
+    }
+}
+
+pub(crate) fn cheat_names(cheats: &Chip8Cheats) -> js_sys::Array {
+    cheats
+        .cheats()
+        .iter()
+        .map(|cheat| JsValue::from_str(&cheat.name))
+        .collect()
+}
+
+pub(crate) fn is_cheat_enabled(cheats: &Chip8Cheats, name: &str) -> bool {
+    cheats
+        .cheats()
+        .iter()
+        .any(|cheat| cheat.name == name && cheat.enabled)
+}
+
+pub(crate) fn unknown_cheat(name: &str) -> JsValue {
+    js_sys::Error::new(&format!("Unknown cheat: {}", name)).into()
+}
+
+pub(crate) fn png_blob(png_bytes: &[u8]) -> Result<Blob, JsValue> {
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(png_bytes));
+
+    let options = BlobPropertyBag::new();
+    options.set_type("image/png");
+
+    Blob::new_with_u8_array_sequence_and_options(&parts, &options)
+}
+
+// Pauses that happen without the page asking for them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PauseTrigger {
+    // The tab is in the background
+    Hidden,
+    // The canvas lost focus
+    Unfocused,
+}
+
+// Why the game is paused. Pauses that happen on their own (hidden tab, canvas lost focus) end
+// on their own too, a pause from the page stays until the page resumes.
+struct PauseState {
+    by_user: bool,
+    hidden: bool,
+    unfocused: bool,
+    auto_pause: bool,
+    // Off by default, clicking a button next to the canvas would pause the game
+    pause_on_blur: bool,
+    on_change: Option<js_sys::Function>,
+}
+
+impl PauseState {
+    fn is_paused(&self) -> bool {
+        self.by_user || (self.auto_pause && self.hidden) || (self.pause_on_blur && self.unfocused)
+    }
+
+    fn set_trigger(&mut self, trigger: PauseTrigger, active: bool) {
+        match trigger {
+            PauseTrigger::Hidden => self.hidden = active,
+            PauseTrigger::Unfocused => self.unfocused = active,
+        }
+    }
+}
+
+// Change why the game is paused, tells the machine and the page if that pauses or resumes it
+fn update_pause(
+    pause: &RefCell<PauseState>,
+    machine: &RefCell<Chip8>,
+    change: impl FnOnce(&mut PauseState),
+) {
+    let (paused, on_change) = {
+        let mut pause = pause.borrow_mut();
+        let was_paused = pause.is_paused();
+        change(&mut pause);
+
+        if pause.is_paused() == was_paused {
+            return;
+        }
+
+        (pause.is_paused(), pause.on_change.clone())
+    };
+
+    machine.borrow_mut().set_paused(paused);
+
+    if let Some(on_change) = on_change {
+        let _ = on_change.call1(&JsValue::NULL, &JsValue::from_bool(paused));
+    }
+}
+
+pub(crate) type PauseListener = (EventTarget, &'static str, Closure<dyn FnMut()>);
+
+// Calls `on_trigger(trigger, active)` when the tab is hidden or shown and, if given, when the
+// canvas loses or gets focus
+pub(crate) fn listen_for_pause_triggers(
+    canvas: Option<&HtmlCanvasElement>,
+    on_trigger: Rc<dyn Fn(PauseTrigger, bool)>,
+) -> Vec<PauseListener> {
+    let mut listeners = Vec::new();
+
+    let mut listen = |target: EventTarget,
+                      event_name: &'static str,
+                      trigger: PauseTrigger,
+                      is_active: Box<dyn Fn() -> bool>| {
+        let on_trigger = on_trigger.clone();
+        let listener = Closure::wrap(Box::new(move || {
+            on_trigger(trigger, is_active());
+        }) as Box<dyn FnMut()>);
+
+        let _ =
+            target.add_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref());
+        listeners.push((target, event_name, listener));
+    };
+
+    // Not there in a worker, the page has to pass them on then
+    if let Some(document) = web_sys::window().and_then(|window| window.document()) {
+        listen(
+            document.clone().into(),
+            "visibilitychange",
+            PauseTrigger::Hidden,
+            Box::new(move || document.hidden()),
+        );
+    }
+
+    if let Some(canvas) = canvas {
+        listen(
+            canvas.clone().into(),
+            "blur",
+            PauseTrigger::Unfocused,
+            Box::new(|| true),
+        );
+        listen(
+            canvas.clone().into(),
+            "focus",
+            PauseTrigger::Unfocused,
+            Box::new(|| false),
+        );
+    }
+
+    listeners
+}
+
+pub(crate) fn remove_listeners(listeners: &[PauseListener]) {
+    for (target, event_name, listener) in listeners {
+        let _ = target
+            .remove_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref());
+    }
+}
+
+pub(crate) type AchievementHook = Rc<dyn Fn(AchievementInfo)>;
+pub(crate) type SplitHook = Rc<dyn Fn(SplitEvent)>;
+
+// What the page hears about after a frame
+#[derive(Default)]
+struct FrameEvents {
+    on_achievement: Option<AchievementHook>,
+    on_split: Option<SplitHook>,
+    // Unlocked achievements are stored for this game
+    rom_hash: Option<String>,
+}
+
+impl FrameEvents {
+    fn after_frame(events: &RefCell<FrameEvents>, machine: &RefCell<Chip8>) {
+        let (unlocked, split_events) = {
+            let mut machine = machine.borrow_mut();
+            (
+                machine.take_unlocked_achievements(),
+                machine.take_split_events(),
+            )
+        };
+        if unlocked.is_empty() && split_events.is_empty() {
+            return;
+        }
+
+        // The hooks may well control the emulator
+        let (on_achievement, on_split, rom_hash) = {
+            let events = events.borrow();
+            (
+                events.on_achievement.clone(),
+                events.on_split.clone(),
+                events.rom_hash.clone(),
+            )
+        };
+
+        if let Some(on_split) = &on_split {
+            for event in split_events {
+                on_split(event);
+            }
+        }
+
+        for achievement in unlocked {
+            if let Some(rom_hash) = &rom_hash {
+                if let Err(err) = store_achievement(rom_hash, achievement.id_str()) {
+                    console::warn_2(&JsValue::from_str("Can't store the achievement:"), &err);
+                }
+            }
+
+            if let Some(on_achievement) = &on_achievement {
+                on_achievement(achievement);
+            }
+        }
+    }
+}
+
+// The animation frame callback and key listeners of a started emulator, dropping it stops both
+// and silences the buzzer
+struct FrameLoop {
+    callback: Rc<RefCell<Option<FrameCallback>>>,
+    request_id: Rc<Cell<i32>>,
+    key_target: EventTarget,
+    key_listeners: Vec<KeyListener>,
+    buzzer: Option<Rc<RefCell<Buzzer>>>,
+}
+
+impl FrameLoop {
+    fn start(
+        machine: Rc<RefCell<Chip8>>,
+        key_target: EventTarget,
+        buzzer: Option<Rc<RefCell<Buzzer>>>,
+        events: Rc<RefCell<FrameEvents>>,
+    ) -> FrameLoop {
+        let key_listeners = vec![
+            listen_for_key(&key_target, "keydown", machine.clone(), true),
+            listen_for_key(&key_target, "keyup", machine.clone(), false),
+        ];
+
+        let callback = Rc::new(RefCell::new(None::<FrameCallback>));
+        let request_id = Rc::new(Cell::new(0));
+
+        let next_frame = callback.clone();
+        let next_request_id = request_id.clone();
+        let frame_buzzer = buzzer.clone();
+        *callback.borrow_mut() = Some(Closure::wrap(Box::new(move || {
+            machine.borrow_mut().update();
+            FrameEvents::after_frame(&events, &machine);
+
+            if let Some(buzzer) = &frame_buzzer {
+                buzzer
+                    .borrow_mut()
+                    .set_playing(machine.borrow().is_beeping());
+            }
+
+            if let Some(callback) = &*next_frame.borrow() {
+                next_request_id.set(request_animation_frame(callback));
+            }
+        }) as Box<dyn FnMut()>));
+
+        request_id.set(request_animation_frame(callback.borrow().as_ref().unwrap()));
+
+        FrameLoop {
+            callback,
+            request_id,
+            key_target,
+            key_listeners,
+            buzzer,
+        }
+    }
+}
+
+impl Drop for FrameLoop {
+    fn drop(&mut self) {
+        cancel_animation_frame(self.request_id.get());
+
+        for (event_name, listener) in &self.key_listeners {
+            let _ = self
+                .key_target
+                .remove_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref());
+        }
+
+        if let Some(buzzer) = &self.buzzer {
+            buzzer.borrow_mut().set_playing(false);
+        }
+
+        // The callback holds on to itself to schedule the next frame
+        let _ = self.callback.borrow_mut().take();
+    }
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    machine: Rc<RefCell<Chip8>>,
+    // What was loaded last, `reset` starts it over
+    rom: Option<Vec<u8>>,
+    // The colours the rom comes with, `clear_palette` goes back to them
+    rom_palette: Vec<Rgb>,
+    settings: Settings,
+    // The memory search for finding cheats, if one was started
+    cheat_finder: Option<Chip8CheatFinder>,
+    events: Rc<RefCell<FrameEvents>>,
+    pause: Rc<RefCell<PauseState>>,
+    pause_listeners: Vec<PauseListener>,
+    frame_loop: Option<FrameLoop>,
+    key_target: EventTarget,
+    // None if there is no Web Audio
+    buzzer: Option<Rc<RefCell<Buzzer>>>,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    // Draws to `canvas`, a canvas element or an OffscreenCanvas. Keys are read from
+    // `key_target` (the whole window if not given, pass the canvas or a wrapper element to
+    // only play while it has focus) and the buzzer plays into `audio_output` (the speakers if
+    // not given). See `WorkerEmulator` to run it off the main thread.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        canvas: JsValue,
+        key_target: Option<EventTarget>,
+        audio_output: Option<AudioNode>,
+    ) -> Result<Emulator, JsValue> {
+        Emulator::with_buzzer(
+            canvas,
+            key_target,
+            create_audio(audio_output).map(Buzzer::Audio),
+        )
+    }
+
+    // Load a rom or Octo cartridge, replaces whatever ran before. A running emulator keeps
+    // running with the new rom. Returns the database entry of the rom, or undefined for an
+    // unknown one, and throws if the rom can't be loaded.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<Option<RomInfo>, JsValue> {
+        let mut machine = Chip8::new(Box::new(Chip8NullDisplay));
+
+        // Warnings go to the console, errors are handed back to JS
+        for warning in machine.load_rom_bytes(rom)? {
+            console::warn_1(&JsValue::from_str(&warning.to_string()));
+        }
+
+        self.rom_palette = machine.config().palette.clone();
+        self.settings.apply(&mut machine);
+        machine.set_paused(self.pause.borrow().is_paused());
+        let rom_info = machine.rom_info().cloned();
+
+        // The canvas was set up once in the constructor, the new machine draws with that display
+        let mut current = self.machine.borrow_mut();
+        machine.replace_display(current.replace_display(Box::new(Chip8NullDisplay)));
+        *current = machine;
+        drop(current);
+
+        self.rom = Some(rom.to_vec());
+        self.cheat_finder = None;
+        self.events.borrow_mut().rom_hash = None;
+
+        Ok(rom_info)
+    }
+
+    // Database entry of the loaded rom
+    pub fn rom_info(&self) -> Option<RomInfo> {
+        self.machine.borrow().rom_info().cloned()
+    }
+
+    // Achievements for the loaded rom, see `chip8::achievements` for the JSON. The ones
+    // unlocked before are read from local storage and new ones are stored there. Loading
+    // another rom drops them.
+    pub fn load_achievements(&mut self, json: &str) -> Result<(), JsValue> {
+        let rom_hash = self.rom_hash();
+        let unlocked = rom_hash
+            .as_deref()
+            .map(stored_achievements)
+            .unwrap_or_default();
+
+        self.set_achievements(json, &unlocked)?;
+        self.events.borrow_mut().rom_hash = rom_hash;
+
+        Ok(())
+    }
+
+    pub fn achievements(&self) -> Vec<AchievementInfo> {
+        self.machine.borrow().achievements().list()
+    }
+
+    // `callback(achievement)` is called for every achievement that unlocks
+    pub fn on_achievement(&self, callback: Option<js_sys::Function>) {
+        self.set_achievement_hook(callback.map(|callback| {
+            Rc::new(move |achievement: AchievementInfo| {
+                let _ = callback.call1(&JsValue::NULL, &achievement.into());
+            }) as AchievementHook
+        }));
+    }
+
+    // Speedrun splits, see `chip8::splits` for the JSON. The timer waits for the start
+    // conditions, loading another rom drops them.
+    pub fn load_splits(&mut self, json: &str) -> Result<(), JsValue> {
+        let splits = Chip8Splits::parse(json).map_err(|err| js_sys::Error::new(&err))?;
+        self.machine.borrow_mut().set_splits(splits);
+
+        Ok(())
+    }
+
+    // The splits with their times in this run, in frames
+    pub fn splits(&self) -> Vec<SplitInfo> {
+        self.machine.borrow().splits().list()
+    }
+
+    // The split file to save, with the times of the best run. Nothing without splits.
+    pub fn splits_json(&self) -> Option<String> {
+        let machine = self.machine.borrow();
+        let splits = machine.splits();
+
+        match splits.is_empty() {
+            true => None,
+            false => Some(splits.to_json()),
+        }
+    }
+
+    // Frames since the start of the run, `format_frames` turns them into a time
+    pub fn timer_frames(&self) -> u32 {
+        self.machine.borrow().splits().frames()
+    }
+
+    // Throw the run away, resetting the machine does this too
+    pub fn reset_split_timer(&mut self) {
+        self.machine.borrow_mut().reset_split_timer();
+    }
+
+    // `callback(event)` is called when the timer starts, splits, finishes or resets. Events
+    // come in after the frame they happened in.
+    pub fn on_split(&self, callback: Option<js_sys::Function>) {
+        self.set_split_hook(callback.map(|callback| {
+            Rc::new(move |event: SplitEvent| {
+                let _ = callback.call1(&JsValue::NULL, &event.into());
+            }) as SplitHook
+        }));
+    }
+
+    // Start a memory search over, every address is a candidate again. Returns how many there
+    // are.
+    pub fn start_search(&mut self) -> u32 {
+        let machine = self.machine.borrow();
+        let finder =
+            Chip8CheatFinder::new(&machine.memory().borrow(), machine.config().memory_size());
+        let count = finder.candidates().len() as u32;
+        self.cheat_finder = Some(finder);
+
+        count
+    }
+
+    // Keep the addresses whose value is "equal" to `value` now, or "changed", "unchanged",
+    // "increased" or "decreased" since the last search. Returns how many are left.
+    pub fn narrow_search(&mut self, filter: &str, value: Option<u8>) -> Result<u32, JsValue> {
+        let filter = SearchFilter::from_name(filter, value)
+            .ok_or_else(|| js_sys::Error::new(&format!("Unknown search: {}", filter)))?;
+        let finder = self
+            .cheat_finder
+            .as_mut()
+            .ok_or_else(|| js_sys::Error::new("Start a search first"))?;
+
+        Ok(finder.narrow(&self.machine.borrow().memory().borrow(), filter) as u32)
+    }
+
+    // The addresses that are left with their values now, the first 100 if no `limit` is given
+    pub fn search_results(&self, limit: Option<u32>) -> Vec<SearchResult> {
+        match &self.cheat_finder {
+            Some(finder) => finder.results(
+                &self.machine.borrow().memory().borrow(),
+                limit.unwrap_or(100) as usize,
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    // SHA-1 of the loaded rom file, to keep things like cheats per game
+    pub fn rom_hash(&self) -> Option<String> {
+        self.rom.as_deref().map(sha1_hex)
+    }
+
+    // Cheats in the text format of `chip8::cheats`, they replace the ones before. Loading
+    // another rom drops them, `stored_cheats` has the ones saved for a game.
+    pub fn load_cheats(&mut self, text: &str) -> Result<(), JsValue> {
+        self.machine
+            .borrow_mut()
+            .set_cheats(Chip8Cheats::parse(text)?);
+
+        Ok(())
+    }
+
+    // The cheats in the text format, with the ones that are on marked, for `store_cheats`
+    pub fn cheats_text(&self) -> String {
+        self.machine.borrow().cheats().to_string()
+    }
+
+    pub fn cheat_names(&self) -> js_sys::Array {
+        cheat_names(self.machine.borrow().cheats())
+    }
+
+    pub fn is_cheat_enabled(&self, name: &str) -> bool {
+        is_cheat_enabled(self.machine.borrow().cheats(), name)
+    }
+
+    pub fn set_cheat_enabled(&mut self, name: &str, enabled: bool) -> Result<(), JsValue> {
+        match self.machine.borrow_mut().set_cheat_enabled(name, enabled) {
+            true => Ok(()),
+            false => Err(unknown_cheat(name)),
+        }
+    }
+
+    // Run the loaded rom every animation frame until `stop`
+    pub fn start(&mut self) -> Result<(), JsValue> {
+        if self.rom.is_none() {
+            return Err(js_sys::Error::new("Load a rom first").into());
+        }
+
+        if self.frame_loop.is_none() {
+            if let Some(buzzer) = &self.buzzer {
+                buzzer.borrow().resume();
+            }
+
+            update_pause(&self.pause, &self.machine, |pause| pause.by_user = false);
+            self.frame_loop = Some(FrameLoop::start(
+                self.machine.clone(),
+                self.key_target.clone(),
+                self.buzzer.clone(),
+                self.events.clone(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Stop the loop and let go of the keyboard, `start` carries on where it stopped
+    pub fn stop(&mut self) {
+        self.frame_loop = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.frame_loop.is_some()
+    }
+
+    // Freeze the game, the loop keeps going so it can be resumed right away
+    pub fn pause(&self) {
+        update_pause(&self.pause, &self.machine, |pause| pause.by_user = true);
+    }
+
+    // Ends a pause from `pause`, a hidden tab or unfocused canvas still keeps it paused
+    pub fn resume(&self) {
+        update_pause(&self.pause, &self.machine, |pause| pause.by_user = false);
+    }
+
+    // Returns whether it is paused now
+    pub fn toggle_pause(&self) -> bool {
+        let paused = self.is_paused();
+        update_pause(&self.pause, &self.machine, |pause| pause.by_user = !paused);
+
+        self.is_paused()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.borrow().is_paused()
+    }
+
+    // Pause on its own while the tab is hidden, on by default
+    pub fn set_auto_pause(&self, enabled: bool) {
+        update_pause(&self.pause, &self.machine, |pause| {
+            pause.auto_pause = enabled
+        });
+    }
+
+    // Also pause while the canvas doesn't have focus, off by default. Focusing the canvas again
+    // resumes it.
+    pub fn set_pause_on_blur(&self, enabled: bool) {
+        update_pause(&self.pause, &self.machine, |pause| {
+            pause.pause_on_blur = enabled
+        });
+    }
+
+    // `callback(paused)` is called every time the game pauses or resumes, for whatever reason
+    pub fn on_pause_change(&self, callback: Option<js_sys::Function>) {
+        self.pause.borrow_mut().on_change = callback;
+    }
+
+    // Start the loaded rom over, settings picked on the page stay
+    pub fn reset(&mut self) -> Result<(), JsValue> {
+        self.loaded_machine()?.reset()?;
+
+        Ok(())
+    }
+
+    // Reset with RAM cleared, or filled with random bytes with `random_ram`
+    pub fn power_cycle(&mut self, random_ram: Option<bool>) -> Result<(), JsValue> {
+        self.loaded_machine()?
+            .power_cycle(random_ram.unwrap_or(false))?;
+
+        Ok(())
+    }
+
+    // Run a single instruction, for going through a paused or stopped game
+    pub fn step(&mut self) {
+        self.machine.borrow_mut().step();
+    }
+
+    // Instructions per 60 Hz frame, also used for roms loaded after this
+    pub fn set_speed(&mut self, instructions_per_frame: u32) -> Result<(), JsValue> {
+        if instructions_per_frame == 0 {
+            return Err(js_sys::Error::new("The speed has to be at least 1 instruction").into());
+        }
+
+        self.settings.speed = Some(instructions_per_frame);
+        self.machine
+            .borrow_mut()
+            .set_tick_rate(instructions_per_frame);
+
+        Ok(())
+    }
+
+    // Buzzer volume, 0 mutes and 1 is full volume
+    pub fn set_volume(&self, volume: f32) {
+        if let Some(buzzer) = &self.buzzer {
+            buzzer.borrow().set_volume(volume);
+        }
+    }
+
+    // Feed a key event from JS, e.g. from an on-screen keypad. `code` is a KeyboardEvent code,
+    // returns false for keys that aren't on the keypad.
+    pub fn key_down(&self, code: &str) -> bool {
+        set_key(&self.machine.borrow(), code, true)
+    }
+
+    pub fn key_up(&self, code: &str) -> bool {
+        set_key(&self.machine.borrow(), code, false)
+    }
+
+    // Use one of the named palettes, see `palette_presets`.
+    pub fn set_palette_preset(&mut self, name: &str) -> Result<(), JsValue> {
+        let preset = PalettePreset::from_name(name)
+            .ok_or_else(|| js_sys::Error::new(&format!("Unknown palette: {}", name)))?;
+
+        self.use_palette(preset.colors());
+
+        Ok(())
+    }
+
+    // Use custom colours, "#rrggbb" strings with the background first.
+    pub fn set_palette(&mut self, colors: js_sys::Array) -> Result<(), JsValue> {
+        let colors = colors
+            .iter()
+            .map(|color| {
+                let hex = color.as_string().unwrap_or_default();
+                parse_color(&hex)
+                    .ok_or_else(|| js_sys::Error::new(&format!("Invalid colour: {}", hex)))
+            })
+            .collect::<Result<Vec<Rgb>, _>>()?;
+
+        if colors.len() < 2 || colors.len() > Chip8Config::MAX_COLORS {
+            return Err(js_sys::Error::new(&format!(
+                "A palette needs 2 to {} colours, got {}",
+                Chip8Config::MAX_COLORS,
+                colors.len()
+            ))
+            .into());
+        }
+
+        self.use_palette(colors);
+
+        Ok(())
+    }
+
+    // Forget the palette picked with `set_palette_preset` or `set_palette`, the rom's own colours
+    // are used again.
+    pub fn clear_palette(&mut self) {
+        self.settings.palette = None;
+        self.machine
+            .borrow_mut()
+            .set_palette(self.rom_palette.clone());
+    }
+
+    // Anti-flicker mode: "off", "blend" (OR the last two frames) or "decay" (pixels fade out
+    // over `decay_frames` frames, 4 if not given).
+    pub fn set_persistence(&mut self, mode: &str, decay_frames: Option<u8>) -> Result<(), JsValue> {
+        let decay_frames = decay_frames.unwrap_or(PersistenceMode::DEFAULT_DECAY_FRAMES);
+        let mode = PersistenceMode::from_name(mode, decay_frames)
+            .ok_or_else(|| js_sys::Error::new(&format!("Unknown persistence mode: {}", mode)))?;
+
+        self.settings.persistence = Some(mode);
+        self.machine.borrow_mut().set_persistence(mode);
+
+        Ok(())
+    }
+
+    // How the image fills the canvas: "fit" (as large as fits) or "integer" (whole pixels
+    // only). Both keep the aspect ratio and letterbox the rest of the canvas.
+    pub fn set_scaling(&mut self, mode: &str) -> Result<(), JsValue> {
+        let scaling = ScalingMode::from_name(mode)
+            .ok_or_else(|| js_sys::Error::new(&format!("Unknown scaling mode: {}", mode)))?;
+
+        self.settings.scaling = Some(scaling);
+        self.machine.borrow_mut().set_scaling(scaling);
+
+        Ok(())
+    }
+
+    // PNG of the screen in the current palette, every chip8 pixel `scale` x `scale` pixels big.
+    pub fn screenshot_png(&self, scale: u32) -> Result<Vec<u8>, JsValue> {
+        self.machine
+            .borrow()
+            .screenshot_png(scale)
+            .map_err(|err| js_sys::Error::new(&err).into())
+    }
+
+    // The screenshot as an image/png Blob, ready for `URL.createObjectURL` and a download link.
+    pub fn screenshot_blob(&self, scale: u32) -> Result<Blob, JsValue> {
+        png_blob(&self.screenshot_png(scale)?)
+    }
+
+    // Start recording the screen to an animated GIF. Defaults: scale 4, skip every other frame
+    // (30 fps) and stop after 30 seconds.
+    pub fn start_recording(
+        &mut self,
+        scale: Option<u32>,
+        frame_skip: Option<u32>,
+        max_seconds: Option<f64>,
+    ) -> Result<(), JsValue> {
+        let defaults = GifRecorderOptions::default();
+        let options = GifRecorderOptions {
+            scale: scale.unwrap_or(defaults.scale),
+            frame_skip: frame_skip.unwrap_or(defaults.frame_skip),
+            max_seconds: max_seconds.unwrap_or(defaults.max_seconds),
+        };
+
+        self.machine
+            .borrow_mut()
+            .start_recording(options)
+            .map_err(|err| js_sys::Error::new(&err).into())
+    }
+
+    // Stop recording, returns the GIF.
+    pub fn stop_recording(&mut self) -> Result<Vec<u8>, JsValue> {
+        self.machine
+            .borrow_mut()
+            .stop_recording()
+            .map_err(|err| js_sys::Error::new(&err).into())
+    }
+
+    // Whether the screen changed in the last frame
+    pub fn frame_changed(&self) -> bool {
+        self.machine.borrow().frame_changed()
+    }
+}
+
+impl Emulator {
+    // Keys are read from the global scope if no `key_target` is given, in a worker nothing
+    // sends key events there and they have to come in through `key_down` and `key_up`
+    pub(crate) fn with_buzzer(
+        canvas: JsValue,
+        key_target: Option<EventTarget>,
+        buzzer: Option<Buzzer>,
+    ) -> Result<Emulator, JsValue> {
+        let target = DisplayTarget::from_js(canvas).map_err(|err| js_sys::Error::new(&err))?;
+        let machine = Chip8::new(display::create(&target).map_err(|err| js_sys::Error::new(&err))?);
+
+        let machine = Rc::new(RefCell::new(machine));
+        let pause = Rc::new(RefCell::new(PauseState {
+            by_user: false,
+            hidden: false,
+            unfocused: false,
+            auto_pause: true,
+            pause_on_blur: false,
+            on_change: None,
+        }));
+
+        let canvas = match &target {
+            DisplayTarget::Canvas(canvas) => Some(canvas),
+            DisplayTarget::Offscreen(_) => None,
+        };
+        let (trigger_pause, trigger_machine) = (pause.clone(), machine.clone());
+        let pause_listeners = listen_for_pause_triggers(
+            canvas,
+            Rc::new(move |trigger, active| {
+                update_pause(&trigger_pause, &trigger_machine, |pause| {
+                    pause.set_trigger(trigger, active)
+                });
+            }),
+        );
+
+        Ok(Emulator {
+            machine,
+            rom: None,
+            rom_palette: Chip8Config::new().palette,
+            settings: Settings::default(),
+            cheat_finder: None,
+            events: Rc::new(RefCell::new(FrameEvents::default())),
+            pause,
+            pause_listeners,
+            frame_loop: None,
+            key_target: key_target.unwrap_or_else(|| js_sys::global().unchecked_into()),
+            buzzer: buzzer.map(|buzzer| Rc::new(RefCell::new(buzzer))),
+        })
+    }
+
+    // Achievements with the ones in `unlocked` unlocked already, nothing is stored
+    pub(crate) fn set_achievements(
+        &mut self,
+        json: &str,
+        unlocked: &[String],
+    ) -> Result<(), JsValue> {
+        let mut achievements =
+            Chip8Achievements::parse(json).map_err(|err| js_sys::Error::new(&err))?;
+        for id in unlocked {
+            achievements.unlock(id);
+        }
+
+        self.machine.borrow_mut().set_achievements(achievements);
+        self.events.borrow_mut().rom_hash = None;
+
+        Ok(())
+    }
+
+    pub(crate) fn set_achievement_hook(&self, hook: Option<AchievementHook>) {
+        self.events.borrow_mut().on_achievement = hook;
+    }
+
+    pub(crate) fn set_split_hook(&self, hook: Option<SplitHook>) {
+        self.events.borrow_mut().on_split = hook;
+    }
+
+    // For pause triggers noticed somewhere else, e.g. on the page when running in a worker
+    pub(crate) fn set_pause_trigger(&self, trigger: PauseTrigger, active: bool) {
+        update_pause(&self.pause, &self.machine, |pause| {
+            pause.set_trigger(trigger, active)
+        });
+    }
+
+    fn loaded_machine(&self) -> Result<std::cell::RefMut<'_, Chip8>, JsValue> {
+        if self.rom.is_none() {
+            return Err(js_sys::Error::new("Load a rom first").into());
+        }
+
+        Ok(self.machine.borrow_mut())
+    }
+
+    fn use_palette(&mut self, palette: Vec<Rgb>) {
+        self.machine.borrow_mut().set_palette(palette.clone());
+        self.settings.palette = Some(palette);
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        remove_listeners(&self.pause_listeners);
+    }
+}
@@ -1,10 +1,13 @@
-use js_sys::Date;
-use std::cell::RefCell;
-use std::f64;
-use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{console, Document, Element, HtmlCanvasElement, Window};
+use web_sys::{console, DataTransfer, DedicatedWorkerGlobalScope, HtmlInputElement};
+
+use chip8::config::PalettePreset;
+use chip8::rom::{self, RomLoadError};
+use chip8::Chip8FileIOError;
+
+pub use emulator::Emulator;
+pub use worker::{run_emulator_worker, WorkerEmulator};
 
 // use futures::executor::block_on;
 
@@ -12,7 +15,11 @@ use web_sys::{console, Document, Element, HtmlCanvasElement, Window};
 
 // extern crate wasm_bindgen_test;
 
-mod chip8;
+pub mod chip8;
+mod emulator;
+#[cfg(feature = "libretro")]
+mod libretro;
+mod worker;
 
 // When the `wee_alloc` feature is enabled, this uses `wee_alloc` as the global
 // allocator.
@@ -31,9 +38,7 @@ pub fn main_js() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
 
     // Your code goes here!
-    unsafe {
-        console::log_1(&JsValue::from_str("Hello world!"));
-    }
+    console::log_1(&JsValue::from_str("Hello world!"));
 
     Ok(())
 }
@@ -42,118 +47,147 @@ fn window() -> web_sys::Window {
     web_sys::window().expect("no global `window` exists")
 }
 
-fn request_animation_frame(f: &Closure<dyn FnMut()>) {
-    window()
-        .request_animation_frame(f.as_ref().unchecked_ref())
-        .expect("should register `requestAnimationFrame` OK");
+// Works on the page and in a worker, which has its own requestAnimationFrame
+fn request_animation_frame(f: &Closure<dyn FnMut()>) -> i32 {
+    match web_sys::window() {
+        Some(window) => window.request_animation_frame(f.as_ref().unchecked_ref()),
+        None => js_sys::global()
+            .unchecked_into::<DedicatedWorkerGlobalScope>()
+            .request_animation_frame(f.as_ref().unchecked_ref()),
+    }
+    .expect("should register `requestAnimationFrame` OK")
 }
 
-fn set_timeout_with_callback(f: &Closure<dyn FnMut()>, delay: i32) -> i32 {
-    let res = window()
-        .set_interval_with_callback_and_timeout_and_arguments_0(f.as_ref().unchecked_ref(), delay)
-        .expect("should register `requestAnimationFrame` OK");
-
-    res
+fn cancel_animation_frame(request_id: i32) {
+    let _ = match web_sys::window() {
+        Some(window) => window.cancel_animation_frame(request_id),
+        None => js_sys::global()
+            .unchecked_into::<DedicatedWorkerGlobalScope>()
+            .cancel_animation_frame(request_id),
+    };
 }
 
 #[wasm_bindgen]
 pub fn test_comp() {
-    unsafe {
-        // console::log_1(&JsValue::from_str(format!("{}", a).as_str()));
-    }
+    // console::log_1(&JsValue::from_str(format!("{}", a).as_str()));
+}
+
+#[wasm_bindgen]
+pub fn palette_presets() -> js_sys::Array {
+    PalettePreset::ALL
+        .iter()
+        .map(|preset| JsValue::from_str(preset.name()))
+        .collect()
 }
 
-static mut BLOCK_START: bool = false;
-static mut STOP_PROGRAM: bool = false;
-static mut TIMEOUT: i32 = 0;
+// The ways of getting a rom to hand to `Emulator.load_rom`.
 
+// The rom selected in a file input, the one with the id "chip8-file-input" if none is given.
+// Nothing if no file is selected.
 #[wasm_bindgen]
-pub fn stop_program() {
-    unsafe {
-        STOP_PROGRAM = true;
-    }
+pub async fn read_selected_rom(
+    input: Option<HtmlInputElement>,
+) -> Result<Option<Vec<u8>>, JsValue> {
+    let file = match chip8::selected_rom_file(input) {
+        Ok(file) => file,
+        Err(Chip8FileIOError::NoFileSelected) => {
+            console::log_1(&JsValue::from_str("Please select a file!"));
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Some(rom::read_file(&file).await?))
 }
 
+// A rom fetched from a url, e.g. from a rom catalog.
 #[wasm_bindgen]
-pub async fn start() {
-    unsafe {
-        console::log_1(&JsValue::from_str("Hello"));
-    }
+pub async fn fetch_rom(url: String) -> Result<Vec<u8>, JsValue> {
+    Ok(rom::fetch(&url).await?)
+}
 
-    unsafe {
-        if BLOCK_START {
-            console::log_1(&JsValue::from_str("Cannot start"));
-            return;
-        }
-        BLOCK_START = true;
-    }
+// A rom file dropped on the page, pass `event.dataTransfer` of the drop event.
+#[wasm_bindgen]
+pub async fn read_dropped_rom(data_transfer: DataTransfer) -> Result<Vec<u8>, JsValue> {
+    let file = rom::data_transfer_file(&data_transfer)
+        .ok_or_else(|| RomLoadError::ReadFailed(String::from("no file was dropped")))?;
 
-    let mut chip8_emulator = chip8::Chip8::new();
-
-    // chip8_emulator.test();
-
-    // return;
-
-    match chip8_emulator.set_rom() {
-        Ok(()) => {}
-        Err(err) => unsafe {
-            match err {
-                chip8::Chip8FileIOError::NoFileSelected => {
-                    console::log_1(&JsValue::from_str("Please select a file!"));
-                    unsafe {
-                        BLOCK_START = false;
-                    }
-                    return;
-                }
-            }
-        },
-    };
+    Ok(rom::read_file(&file).await?)
+}
 
-    chip8_emulator.load_rom().await;
+// Cheats and achievements are kept per game in the browser's local storage, under
+// `Emulator.rom_hash`.
 
-    let f: Rc<RefCell<Option<Closure<dyn FnMut()>>>> = Rc::new(RefCell::new(None));
-    let g = f.clone();
+// Workers have none
+fn local_storage() -> Result<web_sys::Storage, JsValue> {
+    web_sys::window()
+        .map(|window| window.local_storage())
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| js_sys::Error::new("Local storage is not available").into())
+}
 
-    let delay1: i32 = 100;
-    let delay2: i32 = 16;
-    let mut last_cycle_time: f64 = Date::now();
+fn cheats_key(rom_hash: &str) -> String {
+    format!("chip8-cheats-{}", rom_hash)
+}
 
-    let mut i: f32 = 0.0;
+// Save `Emulator.cheats_text` for a game, no cheats removes them.
+#[wasm_bindgen]
+pub fn store_cheats(rom_hash: &str, cheats: &str) -> Result<(), JsValue> {
+    let storage = local_storage()?;
 
-    *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-        // let current_time: f64 = Date::now();
-        // let delta_time = current_time - last_cycle_time;
+    match cheats.trim().is_empty() {
+        true => storage.remove_item(&cheats_key(rom_hash)),
+        false => storage.set_item(&cheats_key(rom_hash), cheats),
+    }
+}
 
-        // if delta_time > delay2 as f64 {
-        //     last_cycle_time = current_time;
+// The cheats saved for a game, ready for `Emulator.load_cheats`.
+#[wasm_bindgen]
+pub fn stored_cheats(rom_hash: &str) -> Result<Option<String>, JsValue> {
+    local_storage()?.get_item(&cheats_key(rom_hash))
+}
 
-        chip8_emulator.update(i);
-        //     i += 1.0;
-        // }
+fn achievements_key(rom_hash: &str) -> String {
+    format!("chip8-achievements-{}", rom_hash)
+}
 
-        unsafe {
-            if STOP_PROGRAM {
-                STOP_PROGRAM = false;
-                BLOCK_START = false;
-                // window().clear_timeout_with_handle(TIMEOUT);
-                let _ = f.borrow_mut().take();
-                return;
-            }
-        }
+// Ids of the achievements unlocked for a game, none if nothing is stored
+fn stored_achievements(rom_hash: &str) -> Vec<String> {
+    local_storage()
+        .and_then(|storage| storage.get_item(&achievements_key(rom_hash)))
+        .ok()
+        .flatten()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
 
-        // set_timeout_with_callback(f.borrow().as_ref().unwrap(), delay1);
+fn store_achievement(rom_hash: &str, id: &str) -> Result<(), JsValue> {
+    let mut unlocked = stored_achievements(rom_hash);
+    if unlocked.iter().any(|unlocked_id| unlocked_id == id) {
+        return Ok(());
+    }
 
-        request_animation_frame(f.borrow().as_ref().unwrap());
-    }) as Box<dyn FnMut()>));
+    unlocked.push(String::from(id));
+    let json = serde_json::to_string(&unlocked).expect("should serialize achievement ids OK");
 
-    // set_timeout_with_callback(g.borrow().as_ref().unwrap(), delay1);
+    local_storage()?.set_item(&achievements_key(rom_hash), &json)
+}
 
-    request_animation_frame(g.borrow().as_ref().unwrap());
+// Forget the achievements unlocked for a game, they show up locked after the next
+// `Emulator.load_achievements`.
+#[wasm_bindgen]
+pub fn clear_achievements(rom_hash: &str) -> Result<(), JsValue> {
+    local_storage()?.remove_item(&achievements_key(rom_hash))
+}
+
+// A speedrun time in frames as h:mm:ss.cc, see `Emulator.timer_frames`
+#[wasm_bindgen]
+pub fn format_frames(frames: u32) -> String {
+    chip8::splits::format_frames(frames)
 }
 
 #[wasm_bindgen]
 pub fn greet() {
-    unsafe {
-        console::log_1(&JsValue::from_str("Some greeting :D"));
-    }
+    console::log_1(&JsValue::from_str("Some greeting :D"));
 }
@@ -0,0 +1,826 @@
+// The emulator as a libretro core, for RetroArch and other libretro frontends. Build it with
+//
+//   cargo build --release --features libretro
+//
+// and load target/release/libchip8_rust_wasm.so (.dll, .dylib) as a core. It runs the same
+// `Chip8` as the page, drawing to a frame buffer instead of a canvas:
+// - video is 64x32 XRGB8888, in the palette picked in the core options
+// - audio is a 440 Hz square wave while the sound timer runs
+// - the joypad is the hex keypad, see `JOYPAD_KEYS`
+// - save states are `Chip8::save_state`
+// - the core options pick the platform (quirks), speed and palette
+//
+// Frontends call in from one thread, so the core lives in a thread local.
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use crate::chip8::audio::Chip8Audio;
+use crate::chip8::config::{Chip8Config, Chip8Platform, PalettePreset, Rgb, ScalingMode};
+use crate::chip8::display::{texels_to_rgba, Chip8Display};
+use crate::chip8::Chip8;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_ENVIRONMENT_SET_MESSAGE: c_uint = 6;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+const FPS: f64 = 60.0;
+const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+const BEEP_VOLUME: i16 = 4000;
+
+// The hex key for every joypad button, in the order of the RETRO_DEVICE_ID_JOYPAD ids: B, Y,
+// Select, Start, Up, Down, Left, Right, A, X, L, R, L2, R2, L3, R3. Most games move with 2, 4,
+// 6 and 8 and act with 5.
+const JOYPAD_KEYS: [u8; 16] = [
+    0x0, 0x7, 0xA, 0xB, 0x2, 0x8, 0x4, 0x6, 0x5, 0x9, 0x1, 0x3, 0xC, 0xD, 0xE, 0xF,
+];
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct RetroMessage {
+    msg: *const c_char,
+    frames: c_uint,
+}
+
+#[repr(C)]
+struct RetroVariable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+// Keys and "Description; default|other values", the first value is the default
+const OPTION_PLATFORM: &[u8] = b"chip8_platform\0";
+const OPTION_SPEED: &[u8] = b"chip8_speed\0";
+const OPTION_PALETTE: &[u8] = b"chip8_palette\0";
+const OPTIONS: [(&[u8], &[u8]); 3] = [
+    (
+        OPTION_PLATFORM,
+        b"Platform (quirks); auto|originalChip8|hybridVIP|modernChip8|chip48|superchip1|superchip|xochip\0",
+    ),
+    (
+        OPTION_SPEED,
+        b"Instructions per frame; auto|7|10|15|20|30|50|100|200|500|1000\0",
+    ),
+    (
+        OPTION_PALETTE,
+        b"Palette; auto|classic|amber|lcd|high-contrast\0",
+    ),
+];
+
+// What the core options say, `None` keeps what the database has for the rom
+#[derive(Debug, Clone, PartialEq, Default)]
+struct CoreOptions {
+    platform: Option<Chip8Platform>,
+    tick_rate: Option<u32>,
+    palette: Option<PalettePreset>,
+}
+
+impl CoreOptions {
+    fn set(&mut self, key: &[u8], value: &str) {
+        match key {
+            OPTION_PLATFORM => self.platform = Chip8Platform::from_id(value),
+            OPTION_SPEED => self.tick_rate = value.parse().ok(),
+            OPTION_PALETTE => self.palette = PalettePreset::from_name(value),
+            _ => {}
+        }
+    }
+
+    // The rom's settings with the options on top
+    fn apply(&self, config: &Chip8Config) -> Chip8Config {
+        let mut config = config.clone();
+        if let Some(platform) = self.platform {
+            config.platform = Some(platform);
+            config.quirks = platform.quirks();
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(palette) = self.palette {
+            config.palette = palette.colors();
+        }
+
+        config
+    }
+}
+
+// Draws into the picture handed to the frontend every frame
+struct FrameDisplay {
+    frame: Rc<RefCell<Vec<u32>>>,
+    palette: Vec<Rgb>,
+    stale: bool,
+}
+
+impl Chip8Display for FrameDisplay {
+    fn set_palette(&mut self, palette: &[Rgb]) {
+        self.palette = palette.to_vec();
+        self.stale = true;
+    }
+
+    // The frontend scales
+    fn set_scaling(&mut self, _scaling: ScalingMode) {}
+
+    fn draw(&mut self, texels: &[u8]) {
+        let rgba = texels_to_rgba(texels, &self.palette);
+        for (pixel, color) in self.frame.borrow_mut().iter_mut().zip(rgba.chunks(4)) {
+            *pixel = u32::from_be_bytes([0, color[0], color[1], color[2]]);
+        }
+        self.stale = false;
+    }
+
+    fn needs_redraw(&self) -> bool {
+        self.stale
+    }
+
+    fn name(&self) -> &'static str {
+        "libretro"
+    }
+}
+
+struct Core {
+    machine: Chip8,
+    frame: Rc<RefCell<Vec<u32>>>,
+    // The settings of the rom, before the core options
+    rom_config: Chip8Config,
+    // Where the square wave is, in samples
+    beep_phase: u32,
+    audio: Vec<i16>,
+}
+
+#[derive(Default)]
+struct Callbacks {
+    environment: Cell<Option<EnvironmentFn>>,
+    video_refresh: Cell<Option<VideoRefreshFn>>,
+    audio_sample_batch: Cell<Option<AudioSampleBatchFn>>,
+    input_poll: Cell<Option<InputPollFn>>,
+    input_state: Cell<Option<InputStateFn>>,
+}
+
+thread_local! {
+    static CALLBACKS: Callbacks = Callbacks::default();
+    static CORE: RefCell<Option<Core>> = const { RefCell::new(None) };
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match CALLBACKS.with(|callbacks| callbacks.environment.get()) {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+// Shown on screen for a few seconds
+fn show_message(text: &str) {
+    let text = CString::new(text).unwrap_or_default();
+    let mut message = RetroMessage {
+        msg: text.as_ptr(),
+        frames: 180,
+    };
+
+    environment(
+        RETRO_ENVIRONMENT_SET_MESSAGE,
+        &mut message as *mut RetroMessage as *mut c_void,
+    );
+}
+
+fn read_options() -> CoreOptions {
+    let mut options = CoreOptions::default();
+
+    for (key, _) in OPTIONS {
+        let mut variable = RetroVariable {
+            key: key.as_ptr() as *const c_char,
+            value: std::ptr::null(),
+        };
+        let found = environment(
+            RETRO_ENVIRONMENT_GET_VARIABLE,
+            &mut variable as *mut RetroVariable as *mut c_void,
+        );
+
+        if found && !variable.value.is_null() {
+            let value = unsafe { CStr::from_ptr(variable.value) };
+            options.set(key, &value.to_string_lossy());
+        }
+    }
+
+    options
+}
+
+impl Core {
+    fn new(rom: &[u8]) -> Result<Core, String> {
+        let frame = Rc::new(RefCell::new(vec![0; WIDTH * HEIGHT]));
+        let mut machine = Chip8::new(Box::new(FrameDisplay {
+            frame: frame.clone(),
+            palette: Vec::new(),
+            stale: true,
+        }));
+        machine.load_rom_bytes(rom).map_err(|err| err.to_string())?;
+        let rom_config = machine.config().clone();
+
+        let mut core = Core {
+            machine,
+            frame,
+            rom_config,
+            beep_phase: 0,
+            audio: Vec::with_capacity(SAMPLES_PER_FRAME * 2),
+        };
+        core.apply_options(&read_options());
+
+        Ok(core)
+    }
+
+    fn apply_options(&mut self, options: &CoreOptions) {
+        self.machine.apply_config(options.apply(&self.rom_config));
+    }
+
+    fn run_frame(&mut self, input_state: Option<InputStateFn>) {
+        if let Some(input_state) = input_state {
+            let input = self.machine.input();
+            let mut input = input.borrow_mut();
+            for (id, key) in JOYPAD_KEYS.iter().enumerate() {
+                let pressed = unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id as c_uint) };
+                input.set_key(*key, pressed != 0);
+            }
+        }
+
+        self.machine.update();
+        self.beep();
+    }
+
+    // One frame of stereo samples
+    fn beep(&mut self) {
+        let beeping = self.machine.is_beeping();
+        let half_period = (SAMPLE_RATE as f32 / Chip8Audio::FREQUENCY / 2.0) as u32;
+
+        self.audio.clear();
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = match (beeping, self.beep_phase / half_period % 2) {
+                (false, _) => 0,
+                (true, 0) => BEEP_VOLUME,
+                (true, _) => -BEEP_VOLUME,
+            };
+            self.audio.extend_from_slice(&[sample, sample]);
+            self.beep_phase = self.beep_phase.wrapping_add(1);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    CORE.with(|core| core.borrow_mut().take());
+}
+
+/// # Safety
+/// `info` points to a `retro_system_info` the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: b"CHIP-8 (Rust)\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"ch8|c8|sc8|xo8|gif\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` points to a `retro_system_av_info` the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: 2.0,
+        },
+        timing: RetroSystemTiming {
+            fps: FPS,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    CALLBACKS.with(|callbacks| callbacks.environment.set(Some(callback)));
+
+    let mut variables: Vec<RetroVariable> = OPTIONS
+        .iter()
+        .map(|(key, value)| RetroVariable {
+            key: key.as_ptr() as *const c_char,
+            value: value.as_ptr() as *const c_char,
+        })
+        .collect();
+    variables.push(RetroVariable {
+        key: std::ptr::null(),
+        value: std::ptr::null(),
+    });
+    environment(
+        RETRO_ENVIRONMENT_SET_VARIABLES,
+        variables.as_mut_ptr() as *mut c_void,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    CALLBACKS.with(|callbacks| callbacks.video_refresh.set(Some(callback)));
+}
+
+// Frames of audio go in one batch
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    CALLBACKS.with(|callbacks| callbacks.audio_sample_batch.set(Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    CALLBACKS.with(|callbacks| callbacks.input_poll.set(Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    CALLBACKS.with(|callbacks| callbacks.input_state.set(Some(callback)));
+}
+
+// Every port is a joypad
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    CORE.with(|core| {
+        if let Some(core) = core.borrow_mut().as_mut() {
+            // The game keeps running as it was
+            if let Err(err) = core.machine.reset() {
+                show_message(&format!("Reset failed: {}", err));
+            }
+        }
+    });
+}
+
+// A panic can't unwind into the frontend, it takes the game down instead of RetroArch. The
+// core is unloaded after one, its state can't be trusted anymore.
+fn catch_panic<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        CORE.with(|core| {
+            if let Ok(mut core) = core.try_borrow_mut() {
+                core.take();
+            }
+        });
+
+        failed
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    catch_panic((), run_frame);
+}
+
+fn run_frame() {
+    let mut options_changed = false;
+    environment(
+        RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE,
+        &mut options_changed as *mut bool as *mut c_void,
+    );
+    let options = options_changed.then(read_options);
+
+    CALLBACKS.with(|callbacks| {
+        if let Some(input_poll) = callbacks.input_poll.get() {
+            unsafe { input_poll() };
+        }
+
+        CORE.with(|core| {
+            let mut core = core.borrow_mut();
+            let core = match core.as_mut() {
+                Some(core) => core,
+                None => return,
+            };
+
+            if let Some(options) = &options {
+                core.apply_options(options);
+            }
+            core.run_frame(callbacks.input_state.get());
+
+            if let Some(video_refresh) = callbacks.video_refresh.get() {
+                let frame = core.frame.borrow();
+                unsafe {
+                    video_refresh(
+                        frame.as_ptr() as *const c_void,
+                        WIDTH as c_uint,
+                        HEIGHT as c_uint,
+                        WIDTH * 4,
+                    )
+                };
+            }
+            if let Some(audio_sample_batch) = callbacks.audio_sample_batch.get() {
+                unsafe { audio_sample_batch(core.audio.as_ptr(), core.audio.len() / 2) };
+            }
+        });
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    Chip8::SAVE_STATE_SIZE
+}
+
+/// # Safety
+/// `data` points to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    CORE.with(|core| match core.borrow().as_ref() {
+        Some(core) if !data.is_null() && size >= Chip8::SAVE_STATE_SIZE => {
+            let state = core.machine.save_state();
+            std::ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+            true
+        }
+        _ => false,
+    })
+}
+
+/// # Safety
+/// `data` points to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = std::slice::from_raw_parts(data as *const u8, size);
+
+    catch_panic(false, || {
+        CORE.with(|core| match core.borrow_mut().as_mut() {
+            Some(core) => core.machine.load_state(state).is_ok(),
+            None => false,
+        })
+    })
+}
+
+// RetroArch's cheats poke the memory from `retro_get_memory_data`
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` points to a `retro_game_info` with the rom in `data`, or a path to it.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    let game = match game.as_ref() {
+        Some(game) => game,
+        None => return false,
+    };
+
+    let rom = if !game.data.is_null() {
+        std::slice::from_raw_parts(game.data as *const u8, game.size).to_vec()
+    } else if !game.path.is_null() {
+        match std::fs::read(CStr::from_ptr(game.path).to_string_lossy().as_ref()) {
+            Ok(rom) => rom,
+            Err(_) => return false,
+        }
+    } else {
+        return false;
+    };
+
+    let mut pixel_format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut pixel_format as *mut c_uint as *mut c_void,
+    ) {
+        return false;
+    }
+
+    catch_panic(false, || match Core::new(&rom) {
+        Ok(new_core) => {
+            CORE.with(|core| *core.borrow_mut() = Some(new_core));
+            true
+        }
+        Err(_) => false,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const RetroGameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    CORE.with(|core| core.borrow_mut().take());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+// The RAM of the platform, for RetroArch's cheats and achievements
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    CORE.with(|core| match (core.borrow().as_ref(), id) {
+        (Some(core), RETRO_MEMORY_SYSTEM_RAM) => {
+            core.machine.memory().borrow_mut().as_mut_ptr() as *mut c_void
+        }
+        _ => std::ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    CORE.with(|core| match (core.borrow().as_ref(), id) {
+        (Some(core), RETRO_MEMORY_SYSTEM_RAM) => core.machine.config().memory_size(),
+        _ => 0,
+    })
+}
+
+// A small frontend that drives the core the way RetroArch does
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Frontend {
+        options: Vec<(String, String)>,
+        options_changed: bool,
+        pixel_format: Option<c_uint>,
+        frame: Vec<u32>,
+        audio_frames: usize,
+        loud_samples: usize,
+        buttons: Vec<c_uint>,
+    }
+
+    thread_local! {
+        static FRONTEND: RefCell<Frontend> = RefCell::new(Frontend::default());
+    }
+
+    unsafe extern "C" fn test_environment(cmd: c_uint, data: *mut c_void) -> bool {
+        FRONTEND.with(|frontend| {
+            let mut frontend = frontend.borrow_mut();
+            match cmd {
+                RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                    frontend.pixel_format = Some(*(data as *const c_uint));
+                    true
+                }
+                RETRO_ENVIRONMENT_SET_VARIABLES => true,
+                RETRO_ENVIRONMENT_GET_VARIABLE => {
+                    let variable = &mut *(data as *mut RetroVariable);
+                    let key = CStr::from_ptr(variable.key).to_string_lossy();
+                    match frontend.options.iter().find(|(name, _)| *name == key) {
+                        // Leaked, the frontend owns option values
+                        Some((_, value)) => {
+                            let value = std::ffi::CString::new(value.as_str()).unwrap();
+                            variable.value = value.into_raw();
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE => {
+                    *(data as *mut bool) = std::mem::take(&mut frontend.options_changed);
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    unsafe extern "C" fn test_video_refresh(
+        data: *const c_void,
+        width: c_uint,
+        height: c_uint,
+        pitch: usize,
+    ) {
+        assert_eq!(pitch, width as usize * 4);
+        let pixels = std::slice::from_raw_parts(data as *const u32, (width * height) as usize);
+        FRONTEND.with(|frontend| frontend.borrow_mut().frame = pixels.to_vec());
+    }
+
+    unsafe extern "C" fn test_audio_sample_batch(data: *const i16, frames: usize) -> usize {
+        let samples = std::slice::from_raw_parts(data, frames * 2);
+        FRONTEND.with(|frontend| {
+            let mut frontend = frontend.borrow_mut();
+            frontend.audio_frames += frames;
+            frontend.loud_samples += samples.iter().filter(|sample| **sample != 0).count();
+        });
+
+        frames
+    }
+
+    unsafe extern "C" fn test_input_poll() {}
+
+    unsafe extern "C" fn test_input_state(
+        port: c_uint,
+        device: c_uint,
+        _index: c_uint,
+        id: c_uint,
+    ) -> i16 {
+        FRONTEND.with(|frontend| {
+            let pressed = port == 0
+                && device == RETRO_DEVICE_JOYPAD
+                && frontend.borrow().buttons.contains(&id);
+            pressed as i16
+        })
+    }
+
+    // Waits for key 5, then beeps and draws a 0 at (5, 5)
+    const WAIT_AND_DRAW: [u8; 14] = [
+        0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0xF0, 0x18, 0xA0, 0x50, 0xD0, 0x05, 0x12, 0x0C,
+    ];
+    const JOYPAD_A: c_uint = 8;
+
+    fn start(rom: &[u8], options: &[(&str, &str)]) {
+        FRONTEND.with(|frontend| {
+            frontend.borrow_mut().options = options
+                .iter()
+                .map(|(key, value)| (String::from(*key), String::from(*value)))
+                .collect()
+        });
+
+        retro_set_environment(test_environment);
+        retro_set_video_refresh(test_video_refresh);
+        retro_set_audio_sample_batch(test_audio_sample_batch);
+        retro_set_input_poll(test_input_poll);
+        retro_set_input_state(test_input_state);
+        retro_init();
+
+        let game = RetroGameInfo {
+            path: std::ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: std::ptr::null(),
+        };
+        assert!(unsafe { retro_load_game(&game) });
+    }
+
+    fn pixel(x: usize, y: usize) -> u32 {
+        FRONTEND.with(|frontend| frontend.borrow().frame[y * WIDTH + x])
+    }
+
+    fn rgb(color: Rgb) -> u32 {
+        u32::from_be_bytes([0, color[0], color[1], color[2]])
+    }
+
+    #[test]
+    fn test_video_audio_and_joypad() {
+        start(&WAIT_AND_DRAW, &[]);
+        let palette = PalettePreset::Classic.colors();
+
+        retro_run();
+        assert_eq!(
+            FRONTEND.with(|frontend| frontend.borrow().pixel_format),
+            Some(RETRO_PIXEL_FORMAT_XRGB8888)
+        );
+        assert_eq!(pixel(5, 5), rgb(palette[0]));
+        FRONTEND.with(|frontend| {
+            let frontend = frontend.borrow();
+            assert_eq!(frontend.audio_frames, SAMPLES_PER_FRAME);
+            assert_eq!(frontend.loud_samples, 0);
+        });
+
+        FRONTEND.with(|frontend| frontend.borrow_mut().buttons.push(JOYPAD_A));
+        retro_run();
+        assert_eq!(pixel(5, 5), rgb(palette[1]));
+        assert_eq!(pixel(9, 5), rgb(palette[0]));
+        assert!(FRONTEND.with(|frontend| frontend.borrow().loud_samples) > 0);
+
+        retro_unload_game();
+        retro_deinit();
+    }
+
+    #[test]
+    fn test_save_states_and_memory() {
+        start(&WAIT_AND_DRAW, &[]);
+        FRONTEND.with(|frontend| frontend.borrow_mut().buttons.push(JOYPAD_A));
+        retro_run();
+
+        let mut state = vec![0u8; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+
+        retro_reset();
+        let memory = retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *mut u8;
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 4096);
+        assert_eq!(unsafe { *memory.add(0x200) }, 0x60);
+        // A cheat pokes the program, the save state brings it back
+        unsafe { *memory.add(0x201) = 0x07 };
+
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+        assert_eq!(unsafe { *memory.add(0x201) }, 0x05);
+        assert!(!unsafe { retro_unserialize(state.as_ptr() as *const c_void, 10) });
+        assert!(!unsafe { retro_unserialize(std::ptr::null(), state.len()) });
+        assert!(!unsafe { retro_serialize(std::ptr::null_mut(), state.len()) });
+
+        retro_unload_game();
+    }
+
+    #[test]
+    fn test_core_options() {
+        start(
+            &WAIT_AND_DRAW,
+            &[("chip8_palette", "amber"), ("chip8_speed", "1")],
+        );
+
+        // One instruction a frame, the key check is the second one
+        retro_run();
+        retro_run();
+        FRONTEND.with(|frontend| frontend.borrow_mut().buttons.push(JOYPAD_A));
+        retro_run();
+        assert_eq!(pixel(5, 5), rgb(PalettePreset::Amber.colors()[0]));
+
+        // Changed while running
+        FRONTEND.with(|frontend| {
+            let mut frontend = frontend.borrow_mut();
+            frontend.options = vec![(String::from("chip8_palette"), String::from("lcd"))];
+            frontend.options_changed = true;
+        });
+        retro_run();
+        assert_eq!(pixel(0, 0), rgb(PalettePreset::Lcd.colors()[0]));
+
+        retro_unload_game();
+    }
+
+    #[test]
+    fn test_panics_unload_the_game() {
+        start(&WAIT_AND_DRAW, &[]);
+
+        assert_eq!(catch_panic("failed", || panic!("bad frame")), "failed");
+        assert!(CORE.with(|core| core.borrow().is_none()));
+        // Nothing to run anymore, but nothing breaks either
+        retro_run();
+        assert!(retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM).is_null());
+    }
+}
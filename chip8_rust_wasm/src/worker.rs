@@ -0,0 +1,1257 @@
+// Runs the emulator in a dedicated worker, so a busy page can't make the game stutter. The
+// page keeps a `WorkerEmulator`, which hands its canvas over to the worker as an
+// OffscreenCanvas and passes commands, keys and pause triggers on as messages. The worker runs
+// a normal `Emulator` and answers with events, the beeps are played on the page.
+//
+// Messages are [json, payload] arrays, the payload carries what doesn't fit in JSON: the
+// canvas, roms, screenshots and GIFs.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    console, AudioNode, DedicatedWorkerGlobalScope, ErrorEvent, EventTarget, HtmlCanvasElement,
+    KeyboardEvent, MessageEvent, Worker,
+};
+
+use crate::chip8::achievements::{AchievementInfo, Chip8Achievements};
+use crate::chip8::audio::Chip8Audio;
+use crate::chip8::cheat_finder::SearchResult;
+use crate::chip8::cheats::Chip8Cheats;
+use crate::chip8::database::{sha1_hex, Chip8Database, RomInfo};
+use crate::chip8::splits::{Chip8Splits, SplitEvent, SplitInfo};
+use crate::emulator::{self, Buzzer, Emulator, PauseListener, PauseTrigger};
+
+// A command for the worker. Commands with an id get an answer, the others only report failures.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Envelope {
+    id: Option<u32>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+// The `Emulator` methods, with the same names
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    // The payload is the OffscreenCanvas
+    Init,
+    // The payload is the rom as a Uint8Array
+    LoadRom,
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    TogglePause,
+    SetAutoPause {
+        enabled: bool,
+    },
+    SetPauseOnBlur {
+        enabled: bool,
+    },
+    SetPauseTrigger {
+        trigger: PauseTrigger,
+        active: bool,
+    },
+    Reset,
+    PowerCycle {
+        random_ram: Option<bool>,
+    },
+    Step,
+    SetSpeed {
+        instructions_per_frame: u32,
+    },
+    KeyDown {
+        code: String,
+    },
+    KeyUp {
+        code: String,
+    },
+    SetPalettePreset {
+        name: String,
+    },
+    SetPalette {
+        colors: Vec<String>,
+    },
+    ClearPalette,
+    SetPersistence {
+        mode: String,
+        decay_frames: Option<u8>,
+    },
+    SetScaling {
+        mode: String,
+    },
+    ScreenshotPng {
+        scale: u32,
+    },
+    StartRecording {
+        scale: Option<u32>,
+        frame_skip: Option<u32>,
+        max_seconds: Option<f64>,
+    },
+    StopRecording,
+    LoadCheats {
+        text: String,
+    },
+    SetCheatEnabled {
+        name: String,
+        enabled: bool,
+    },
+    StartSearch,
+    NarrowSearch {
+        filter: String,
+        value: Option<u8>,
+    },
+    SearchResults {
+        limit: Option<u32>,
+    },
+    // The page reads and stores the unlocked ones, workers have no local storage
+    LoadAchievements {
+        json: String,
+        unlocked: Vec<String>,
+    },
+    LoadSplits {
+        json: String,
+    },
+    Splits,
+    SplitsJson,
+    TimerFrames,
+    ResetSplitTimer,
+}
+
+// What a command came back with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+enum Reply {
+    Nothing,
+    Flag {
+        value: bool,
+    },
+    // The page looks the rom up in its own copy of the database
+    Loaded {
+        sha1: Option<String>,
+        rom_hash: String,
+    },
+    Running {
+        running: bool,
+    },
+    Count {
+        count: u32,
+    },
+    SearchResults {
+        results: Vec<SearchResult>,
+    },
+    Splits {
+        splits: Vec<SplitInfo>,
+    },
+    Text {
+        text: Option<String>,
+    },
+    // The payload is a Uint8Array
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    // The worker listens for commands now
+    Ready,
+    Done { id: u32, reply: Reply },
+    Failed { id: Option<u32>, error: String },
+    Paused { paused: bool },
+    Beep { playing: bool },
+    Achievement { achievement: AchievementInfo },
+    Split { split: SplitEvent },
+}
+
+fn message<T: Serialize>(body: &T, payload: Option<JsValue>) -> js_sys::Array {
+    let json = serde_json::to_string(body).expect("should serialize worker messages OK");
+
+    js_sys::Array::of2(
+        &JsValue::from_str(&json),
+        &payload.unwrap_or(JsValue::UNDEFINED),
+    )
+}
+
+fn read_message<T: DeserializeOwned>(data: &JsValue) -> Result<(T, JsValue), String> {
+    let parts = data
+        .dyn_ref::<js_sys::Array>()
+        .ok_or_else(|| String::from("Worker messages have to be arrays"))?;
+    let json = parts
+        .get(0)
+        .as_string()
+        .ok_or_else(|| String::from("Worker messages have to start with JSON"))?;
+    let body = serde_json::from_str(&json).map_err(|err| err.to_string())?;
+
+    Ok((body, parts.get(1)))
+}
+
+fn error_message(err: &JsValue) -> String {
+    match err.dyn_ref::<js_sys::Error>() {
+        Some(err) => String::from(err.message()),
+        None => err.as_string().unwrap_or_else(|| format!("{:?}", err)),
+    }
+}
+
+// Bytes are handed over instead of copied
+fn bytes_payload(bytes: &[u8]) -> (JsValue, js_sys::Array) {
+    let bytes = js_sys::Uint8Array::from(bytes);
+    let transfer = js_sys::Array::of1(&bytes.buffer());
+
+    (bytes.into(), transfer)
+}
+
+// The worker side
+
+fn post_event(
+    scope: &DedicatedWorkerGlobalScope,
+    event: &Event,
+    payload: Option<(JsValue, js_sys::Array)>,
+) {
+    let (payload, transfer) = match payload {
+        Some((payload, transfer)) => (Some(payload), transfer),
+        None => (None, js_sys::Array::new()),
+    };
+
+    if let Err(err) = scope.post_message_with_transfer(&message(event, payload), &transfer) {
+        console::error_2(&JsValue::from_str("Can't answer the page:"), &err);
+    }
+}
+
+// A Rust closure as a JS function, for the emulator's callbacks
+fn event_callback(
+    scope: &DedicatedWorkerGlobalScope,
+    event: fn(bool) -> Event,
+) -> js_sys::Function {
+    let scope = scope.clone();
+
+    Closure::wrap(
+        Box::new(move |value: bool| post_event(&scope, &event(value), None))
+            as Box<dyn FnMut(bool)>,
+    )
+    .into_js_value()
+    .unchecked_into()
+}
+
+// Call this in the worker script once the module is initialised:
+//   await init();
+//   run_emulator_worker();
+// and hand the worker to `new WorkerEmulator(worker, canvas)` on the page.
+#[wasm_bindgen]
+pub fn run_emulator_worker() -> Result<(), JsValue> {
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().dyn_into()?;
+    let emulator = RefCell::new(None::<Emulator>);
+
+    let reply_scope = scope.clone();
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let (envelope, payload) = match read_message::<Envelope>(&event.data()) {
+            Ok(message) => message,
+            Err(error) => {
+                post_event(&reply_scope, &Event::Failed { id: None, error }, None);
+                return;
+            }
+        };
+
+        match run_command(&reply_scope, &emulator, envelope.command, payload) {
+            Ok((reply, payload)) => {
+                if let Some(id) = envelope.id {
+                    post_event(&reply_scope, &Event::Done { id, reply }, payload);
+                }
+            }
+            Err(err) => {
+                let error = error_message(&err);
+                post_event(
+                    &reply_scope,
+                    &Event::Failed {
+                        id: envelope.id,
+                        error,
+                    },
+                    None,
+                );
+            }
+        }
+    }) as Box<dyn FnMut(MessageEvent)>);
+
+    scope.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    // Listens for as long as the worker lives
+    on_message.forget();
+
+    post_event(&scope, &Event::Ready, None);
+
+    Ok(())
+}
+
+type CommandResult = Result<(Reply, Option<(JsValue, js_sys::Array)>), JsValue>;
+
+fn run_command(
+    scope: &DedicatedWorkerGlobalScope,
+    emulator: &RefCell<Option<Emulator>>,
+    command: Command,
+    payload: JsValue,
+) -> CommandResult {
+    if command == Command::Init {
+        let beep = event_callback(scope, |playing| Event::Beep { playing });
+        let new_emulator = Emulator::with_buzzer(
+            payload,
+            None,
+            Some(Buzzer::Callback {
+                playing: false,
+                callback: beep,
+            }),
+        )?;
+        let split_scope = scope.clone();
+        new_emulator.set_split_hook(Some(Rc::new(move |split| {
+            post_event(&split_scope, &Event::Split { split }, None);
+        })));
+        let achievement_scope = scope.clone();
+        new_emulator.set_achievement_hook(Some(Rc::new(move |achievement| {
+            post_event(
+                &achievement_scope,
+                &Event::Achievement { achievement },
+                None,
+            );
+        })));
+        new_emulator.on_pause_change(Some(event_callback(scope, |paused| Event::Paused {
+            paused,
+        })));
+
+        *emulator.borrow_mut() = Some(new_emulator);
+
+        return Ok((Reply::Nothing, None));
+    }
+
+    match emulator.borrow_mut().as_mut() {
+        Some(emulator) => control(emulator, command, payload),
+        None => Err(js_sys::Error::new("The worker didn't get a canvas").into()),
+    }
+}
+
+fn control(emulator: &mut Emulator, command: Command, payload: JsValue) -> CommandResult {
+    let reply = match command {
+        Command::Init => return Err(js_sys::Error::new("The worker already has a canvas").into()),
+        Command::LoadRom => {
+            let rom = payload
+                .dyn_into::<js_sys::Uint8Array>()
+                .map_err(|_| js_sys::Error::new("The rom has to be a Uint8Array"))?;
+            let rom = rom.to_vec();
+            let rom_info = emulator.load_rom(&rom)?;
+
+            Reply::Loaded {
+                sha1: rom_info.map(|info| info.sha1()),
+                rom_hash: sha1_hex(&rom),
+            }
+        }
+        Command::Start => {
+            emulator.start()?;
+            Reply::Running { running: true }
+        }
+        Command::Stop => {
+            emulator.stop();
+            Reply::Running { running: false }
+        }
+        Command::Pause => {
+            emulator.pause();
+            Reply::Nothing
+        }
+        Command::Resume => {
+            emulator.resume();
+            Reply::Nothing
+        }
+        Command::TogglePause => Reply::Flag {
+            value: emulator.toggle_pause(),
+        },
+        Command::SetAutoPause { enabled } => {
+            emulator.set_auto_pause(enabled);
+            Reply::Nothing
+        }
+        Command::SetPauseOnBlur { enabled } => {
+            emulator.set_pause_on_blur(enabled);
+            Reply::Nothing
+        }
+        Command::SetPauseTrigger { trigger, active } => {
+            emulator.set_pause_trigger(trigger, active);
+            Reply::Nothing
+        }
+        Command::Reset => {
+            emulator.reset()?;
+            Reply::Nothing
+        }
+        Command::PowerCycle { random_ram } => {
+            emulator.power_cycle(random_ram)?;
+            Reply::Nothing
+        }
+        Command::Step => {
+            emulator.step();
+            Reply::Nothing
+        }
+        Command::SetSpeed {
+            instructions_per_frame,
+        } => {
+            emulator.set_speed(instructions_per_frame)?;
+            Reply::Nothing
+        }
+        Command::KeyDown { code } => Reply::Flag {
+            value: emulator.key_down(&code),
+        },
+        Command::KeyUp { code } => Reply::Flag {
+            value: emulator.key_up(&code),
+        },
+        Command::SetPalettePreset { name } => {
+            emulator.set_palette_preset(&name)?;
+            Reply::Nothing
+        }
+        Command::SetPalette { colors } => {
+            emulator.set_palette(
+                colors
+                    .iter()
+                    .map(|color| JsValue::from_str(color))
+                    .collect(),
+            )?;
+            Reply::Nothing
+        }
+        Command::ClearPalette => {
+            emulator.clear_palette();
+            Reply::Nothing
+        }
+        Command::SetPersistence { mode, decay_frames } => {
+            emulator.set_persistence(&mode, decay_frames)?;
+            Reply::Nothing
+        }
+        Command::SetScaling { mode } => {
+            emulator.set_scaling(&mode)?;
+            Reply::Nothing
+        }
+        Command::ScreenshotPng { scale } => {
+            let png_bytes = emulator.screenshot_png(scale)?;
+            return Ok((Reply::Bytes, Some(bytes_payload(&png_bytes))));
+        }
+        Command::StartRecording {
+            scale,
+            frame_skip,
+            max_seconds,
+        } => {
+            emulator.start_recording(scale, frame_skip, max_seconds)?;
+            Reply::Nothing
+        }
+        Command::StopRecording => {
+            let gif_bytes = emulator.stop_recording()?;
+            return Ok((Reply::Bytes, Some(bytes_payload(&gif_bytes))));
+        }
+        Command::LoadCheats { text } => {
+            emulator.load_cheats(&text)?;
+            Reply::Nothing
+        }
+        Command::SetCheatEnabled { name, enabled } => {
+            emulator.set_cheat_enabled(&name, enabled)?;
+            Reply::Nothing
+        }
+        Command::StartSearch => Reply::Count {
+            count: emulator.start_search(),
+        },
+        Command::NarrowSearch { filter, value } => Reply::Count {
+            count: emulator.narrow_search(&filter, value)?,
+        },
+        Command::SearchResults { limit } => Reply::SearchResults {
+            results: emulator.search_results(limit),
+        },
+        Command::LoadAchievements { json, unlocked } => {
+            emulator.set_achievements(&json, &unlocked)?;
+            Reply::Nothing
+        }
+        Command::LoadSplits { json } => {
+            emulator.load_splits(&json)?;
+            Reply::Nothing
+        }
+        Command::Splits => Reply::Splits {
+            splits: emulator.splits(),
+        },
+        Command::SplitsJson => Reply::Text {
+            text: emulator.splits_json(),
+        },
+        Command::TimerFrames => Reply::Count {
+            count: emulator.timer_frames(),
+        },
+        Command::ResetSplitTimer => {
+            emulator.reset_split_timer();
+            Reply::Nothing
+        }
+    };
+
+    Ok((reply, None))
+}
+
+// The page side
+
+struct PendingReply {
+    resolve: js_sys::Function,
+    reject: js_sys::Function,
+}
+
+// What the page knows about the worker, kept up to date by its events
+struct WorkerState {
+    ready: bool,
+    // Posted once the worker listens
+    queued: Vec<(js_sys::Array, js_sys::Array)>,
+    next_id: u32,
+    pending: HashMap<u32, PendingReply>,
+    running: bool,
+    paused: bool,
+    rom_info: Option<RomInfo>,
+    rom_hash: Option<String>,
+    // The request id and hash of a rom the worker is still loading
+    loading: Option<(u32, String)>,
+    // What the worker's cheats are, the page can parse them too
+    cheats: Chip8Cheats,
+    achievements: Vec<AchievementInfo>,
+    on_achievement: Option<js_sys::Function>,
+    on_split: Option<js_sys::Function>,
+    on_pause_change: Option<js_sys::Function>,
+    audio: Option<Chip8Audio>,
+    // Why the worker stopped, requests fail right away once it did
+    dead: Option<String>,
+}
+
+fn post_command(
+    worker: &Worker,
+    state: &RefCell<WorkerState>,
+    envelope: &Envelope,
+    payload: Option<JsValue>,
+    transfer: js_sys::Array,
+) {
+    let message = message(envelope, payload);
+    let mut state = state.borrow_mut();
+
+    if !state.ready {
+        state.queued.push((message, transfer));
+    } else if let Err(err) = worker.post_message_with_transfer(&message, &transfer) {
+        console::error_2(&JsValue::from_str("Can't reach the emulator worker:"), &err);
+    }
+}
+
+// Fail every request that is still waiting, the answers are lost. A dead worker fails the
+// requests after them too.
+fn reject_pending(state: &RefCell<WorkerState>, reason: &str, dead: bool) {
+    let pending: Vec<PendingReply> = {
+        let mut state = state.borrow_mut();
+        if dead {
+            state.dead = Some(String::from(reason));
+            state.queued.clear();
+        }
+
+        state.pending.drain().map(|(_, pending)| pending).collect()
+    };
+
+    let error = js_sys::Error::new(reason);
+    for pending in pending {
+        let _ = pending.reject.call1(&JsValue::NULL, &error);
+    }
+}
+
+// The load got its answer, from now on rom_hash is right again
+fn forget_loading(state: &mut WorkerState, id: Option<u32>) {
+    if state.loading.as_ref().map(|(loading, _)| Some(*loading)) == Some(id) {
+        state.loading = None;
+    }
+}
+
+fn handle_event(worker: &Worker, state: &RefCell<WorkerState>, data: &JsValue) {
+    let (event, payload) = match read_message::<Event>(data) {
+        Ok(message) => message,
+        Err(error) => {
+            console::error_1(&JsValue::from_str(&error));
+            return;
+        }
+    };
+
+    // Callbacks are called once the state is let go of, they may well control the emulator
+    let (callback, value) = {
+        let mut state = state.borrow_mut();
+
+        match event {
+            Event::Ready => {
+                state.ready = true;
+                for (message, transfer) in state.queued.drain(..) {
+                    let _ = worker.post_message_with_transfer(&message, &transfer);
+                }
+                return;
+            }
+            Event::Done { id, reply } => {
+                forget_loading(&mut state, Some(id));
+                let value = match reply {
+                    Reply::Nothing => JsValue::UNDEFINED,
+                    Reply::Flag { value } => JsValue::from_bool(value),
+                    Reply::Loaded { sha1, rom_hash } => {
+                        // A new machine, without cheats
+                        state.rom_hash = Some(rom_hash);
+                        state.cheats = Chip8Cheats::new();
+                        state.achievements.clear();
+                        state.rom_info = sha1
+                            .and_then(|sha1| Chip8Database::bundled().lookup_hash(&sha1))
+                            .map(|(info, _)| info);
+                        state
+                            .rom_info
+                            .clone()
+                            .map(JsValue::from)
+                            .unwrap_or(JsValue::UNDEFINED)
+                    }
+                    Reply::Running { running } => {
+                        state.running = running;
+                        JsValue::UNDEFINED
+                    }
+                    Reply::Count { count } => JsValue::from(count),
+                    Reply::SearchResults { results } => results
+                        .into_iter()
+                        .map(JsValue::from)
+                        .collect::<js_sys::Array>()
+                        .into(),
+                    Reply::Splits { splits } => splits
+                        .into_iter()
+                        .map(JsValue::from)
+                        .collect::<js_sys::Array>()
+                        .into(),
+                    Reply::Text { text } => text
+                        .map(|text| JsValue::from_str(&text))
+                        .unwrap_or(JsValue::UNDEFINED),
+                    Reply::Bytes => payload,
+                };
+
+                match state.pending.remove(&id) {
+                    Some(pending) => (pending.resolve, value),
+                    None => return,
+                }
+            }
+            Event::Failed { id, error } => {
+                forget_loading(&mut state, id);
+                let pending = id.and_then(|id| state.pending.remove(&id));
+
+                match pending {
+                    Some(pending) => (pending.reject, js_sys::Error::new(&error).into()),
+                    None => {
+                        console::error_1(&JsValue::from_str(&error));
+                        return;
+                    }
+                }
+            }
+            Event::Paused { paused } => {
+                state.paused = paused;
+
+                match state.on_pause_change.clone() {
+                    Some(callback) => (callback, JsValue::from_bool(paused)),
+                    None => return,
+                }
+            }
+            Event::Achievement { achievement } => {
+                if let Some(info) = state
+                    .achievements
+                    .iter_mut()
+                    .find(|info| info.id_str() == achievement.id_str())
+                {
+                    info.set_unlocked(true);
+                }
+
+                if let Some(rom_hash) = &state.rom_hash {
+                    if let Err(err) = crate::store_achievement(rom_hash, achievement.id_str()) {
+                        console::warn_2(&JsValue::from_str("Can't store the achievement:"), &err);
+                    }
+                }
+
+                match state.on_achievement.clone() {
+                    Some(callback) => (callback, achievement.into()),
+                    None => return,
+                }
+            }
+            Event::Split { split } => match state.on_split.clone() {
+                Some(callback) => (callback, split.into()),
+                None => return,
+            },
+            Event::Beep { playing } => {
+                if let Some(audio) = &mut state.audio {
+                    audio.set_playing(playing);
+                }
+                return;
+            }
+        }
+    };
+
+    let _ = callback.call1(&JsValue::NULL, &value);
+}
+
+type KeyListener = (&'static str, Closure<dyn FnMut(KeyboardEvent)>);
+
+// An `Emulator` that runs in `worker`, a worker that called `run_emulator_worker`. It has the
+// same methods, the ones that need an answer from the worker return promises. `is_running`,
+// `is_paused` and `rom_info` answer right away with what the worker last said.
+#[wasm_bindgen]
+pub struct WorkerEmulator {
+    worker: Worker,
+    state: Rc<RefCell<WorkerState>>,
+    // The worker calls these, they have to stay around
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(ErrorEvent)>,
+    _on_message_error: Closure<dyn FnMut(MessageEvent)>,
+    key_target: EventTarget,
+    key_listeners: Vec<KeyListener>,
+    pause_listeners: Vec<PauseListener>,
+}
+
+#[wasm_bindgen]
+impl WorkerEmulator {
+    // Draws to `canvas`, which can't be drawn to on the page after this. Keys are read from
+    // `key_target` (the whole window if not given) and the buzzer plays into `audio_output`
+    // (the speakers if not given), like `Emulator`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        worker: Worker,
+        canvas: HtmlCanvasElement,
+        key_target: Option<EventTarget>,
+        audio_output: Option<AudioNode>,
+    ) -> Result<WorkerEmulator, JsValue> {
+        let offscreen = canvas.transfer_control_to_offscreen()?;
+
+        let state = Rc::new(RefCell::new(WorkerState {
+            ready: false,
+            queued: Vec::new(),
+            next_id: 0,
+            pending: HashMap::new(),
+            running: false,
+            paused: false,
+            rom_info: None,
+            rom_hash: None,
+            loading: None,
+            cheats: Chip8Cheats::new(),
+            achievements: Vec::new(),
+            on_achievement: None,
+            on_split: None,
+            on_pause_change: None,
+            audio: emulator::create_audio(audio_output),
+            dead: None,
+        }));
+
+        let (event_worker, event_state) = (worker.clone(), state.clone());
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            handle_event(&event_worker, &event_state, &event.data());
+        }) as Box<dyn FnMut(MessageEvent)>);
+        worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        // A panic or a script that failed to load, nothing will answer anymore
+        let error_state = state.clone();
+        let on_error = Closure::wrap(Box::new(move |event: ErrorEvent| {
+            let reason = format!("The emulator worker failed: {}", event.message());
+            console::error_1(&JsValue::from_str(&reason));
+            reject_pending(&error_state, &reason, true);
+        }) as Box<dyn FnMut(ErrorEvent)>);
+        worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        // An answer that couldn't be read, there is no telling which one
+        let message_error_state = state.clone();
+        let on_message_error = Closure::wrap(Box::new(move |_: MessageEvent| {
+            reject_pending(
+                &message_error_state,
+                "Could not read an answer of the emulator worker",
+                false,
+            );
+        }) as Box<dyn FnMut(MessageEvent)>);
+        worker.set_onmessageerror(Some(on_message_error.as_ref().unchecked_ref()));
+
+        post_command(
+            &worker,
+            &state,
+            &Envelope {
+                id: None,
+                command: Command::Init,
+            },
+            Some(offscreen.clone().into()),
+            js_sys::Array::of1(&offscreen),
+        );
+
+        // Every key goes to the worker, it knows the rom's key map
+        let key_target = key_target.unwrap_or_else(|| crate::window().into());
+        let mut key_listeners = Vec::new();
+        for (event_name, pressed) in [("keydown", true), ("keyup", false)] {
+            let (key_worker, key_state) = (worker.clone(), state.clone());
+            let listener = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+                let code = event.code();
+                let command = match pressed {
+                    true => Command::KeyDown { code },
+                    false => Command::KeyUp { code },
+                };
+                let envelope = Envelope { id: None, command };
+
+                post_command(
+                    &key_worker,
+                    &key_state,
+                    &envelope,
+                    None,
+                    js_sys::Array::new(),
+                );
+            }) as Box<dyn FnMut(KeyboardEvent)>);
+
+            key_target
+                .add_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref())
+                .expect("should register key listener OK");
+            key_listeners.push((event_name, listener));
+        }
+
+        // The worker can't see the page, tell it when the tab is hidden or the canvas unfocused
+        let (trigger_worker, trigger_state) = (worker.clone(), state.clone());
+        let pause_listeners = emulator::listen_for_pause_triggers(
+            Some(&canvas),
+            Rc::new(move |trigger, active| {
+                let envelope = Envelope {
+                    id: None,
+                    command: Command::SetPauseTrigger { trigger, active },
+                };
+
+                post_command(
+                    &trigger_worker,
+                    &trigger_state,
+                    &envelope,
+                    None,
+                    js_sys::Array::new(),
+                );
+            }),
+        );
+
+        Ok(WorkerEmulator {
+            worker,
+            state,
+            _on_message: on_message,
+            _on_error: on_error,
+            _on_message_error: on_message_error,
+            key_target,
+            key_listeners,
+            pause_listeners,
+        })
+    }
+
+    // Resolves to the database entry of the rom, if it is a known one
+    pub fn load_rom(&self, rom: &[u8]) -> js_sys::Promise {
+        let id = self.state.borrow().next_id;
+        let promise = self.request(Command::LoadRom, Some(js_sys::Uint8Array::from(rom).into()));
+
+        // The worker hashes the same bytes, achievements loaded before it answers are for this rom
+        let mut state = self.state.borrow_mut();
+        if state.pending.contains_key(&id) {
+            state.loading = Some((id, sha1_hex(rom)));
+        }
+
+        promise
+    }
+
+    pub fn rom_info(&self) -> Option<RomInfo> {
+        self.state.borrow().rom_info.clone()
+    }
+
+    pub fn rom_hash(&self) -> Option<String> {
+        self.state.borrow().rom_hash.clone()
+    }
+
+    // Checked on the page, so mistakes are thrown right away
+    pub fn load_cheats(&self, text: &str) -> Result<(), JsValue> {
+        self.state.borrow_mut().cheats = Chip8Cheats::parse(text)?;
+        self.send(Command::LoadCheats {
+            text: String::from(text),
+        });
+
+        Ok(())
+    }
+
+    pub fn cheats_text(&self) -> String {
+        self.state.borrow().cheats.to_string()
+    }
+
+    pub fn cheat_names(&self) -> js_sys::Array {
+        emulator::cheat_names(&self.state.borrow().cheats)
+    }
+
+    pub fn is_cheat_enabled(&self, name: &str) -> bool {
+        emulator::is_cheat_enabled(&self.state.borrow().cheats, name)
+    }
+
+    pub fn set_cheat_enabled(&self, name: &str, enabled: bool) -> Result<(), JsValue> {
+        if !self.state.borrow_mut().cheats.set_enabled(name, enabled) {
+            return Err(emulator::unknown_cheat(name));
+        }
+
+        self.send(Command::SetCheatEnabled {
+            name: String::from(name),
+            enabled,
+        });
+
+        Ok(())
+    }
+
+    pub fn start(&self) -> js_sys::Promise {
+        // Has to happen on the page, in the click that started it
+        if let Some(audio) = &self.state.borrow().audio {
+            audio.resume();
+        }
+
+        self.request(Command::Start, None)
+    }
+
+    pub fn stop(&self) -> js_sys::Promise {
+        self.request(Command::Stop, None)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.borrow().running
+    }
+
+    pub fn pause(&self) -> js_sys::Promise {
+        self.request(Command::Pause, None)
+    }
+
+    pub fn resume(&self) -> js_sys::Promise {
+        self.request(Command::Resume, None)
+    }
+
+    // Resolves to whether it is paused now
+    pub fn toggle_pause(&self) -> js_sys::Promise {
+        self.request(Command::TogglePause, None)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    pub fn set_auto_pause(&self, enabled: bool) -> js_sys::Promise {
+        self.request(Command::SetAutoPause { enabled }, None)
+    }
+
+    pub fn set_pause_on_blur(&self, enabled: bool) -> js_sys::Promise {
+        self.request(Command::SetPauseOnBlur { enabled }, None)
+    }
+
+    pub fn on_pause_change(&self, callback: Option<js_sys::Function>) {
+        self.state.borrow_mut().on_pause_change = callback;
+    }
+
+    pub fn reset(&self) -> js_sys::Promise {
+        self.request(Command::Reset, None)
+    }
+
+    pub fn power_cycle(&self, random_ram: Option<bool>) -> js_sys::Promise {
+        self.request(Command::PowerCycle { random_ram }, None)
+    }
+
+    pub fn step(&self) -> js_sys::Promise {
+        self.request(Command::Step, None)
+    }
+
+    pub fn set_speed(&self, instructions_per_frame: u32) -> js_sys::Promise {
+        self.request(
+            Command::SetSpeed {
+                instructions_per_frame,
+            },
+            None,
+        )
+    }
+
+    // The buzzer plays on the page, so this doesn't need the worker
+    pub fn set_volume(&self, volume: f32) {
+        if let Some(audio) = &self.state.borrow().audio {
+            audio.set_volume(volume);
+        }
+    }
+
+    // Resolves to false for keys that aren't on the keypad
+    pub fn key_down(&self, code: String) -> js_sys::Promise {
+        self.request(Command::KeyDown { code }, None)
+    }
+
+    pub fn key_up(&self, code: String) -> js_sys::Promise {
+        self.request(Command::KeyUp { code }, None)
+    }
+
+    pub fn set_palette_preset(&self, name: String) -> js_sys::Promise {
+        self.request(Command::SetPalettePreset { name }, None)
+    }
+
+    pub fn set_palette(&self, colors: js_sys::Array) -> js_sys::Promise {
+        let colors = colors
+            .iter()
+            .map(|color| color.as_string().unwrap_or_default())
+            .collect();
+
+        self.request(Command::SetPalette { colors }, None)
+    }
+
+    pub fn clear_palette(&self) -> js_sys::Promise {
+        self.request(Command::ClearPalette, None)
+    }
+
+    pub fn set_persistence(&self, mode: String, decay_frames: Option<u8>) -> js_sys::Promise {
+        self.request(Command::SetPersistence { mode, decay_frames }, None)
+    }
+
+    pub fn set_scaling(&self, mode: String) -> js_sys::Promise {
+        self.request(Command::SetScaling { mode }, None)
+    }
+
+    // Checked on the page, so mistakes are thrown right away
+    pub fn load_achievements(&self, json: &str) -> Result<(), JsValue> {
+        let mut achievements =
+            Chip8Achievements::parse(json).map_err(|err| js_sys::Error::new(&err))?;
+
+        let mut state = self.state.borrow_mut();
+        let unlocked = state
+            .loading
+            .as_ref()
+            .map(|(_, hash)| hash)
+            .or(state.rom_hash.as_ref())
+            .map(|hash| crate::stored_achievements(hash))
+            .unwrap_or_default();
+        for id in &unlocked {
+            achievements.unlock(id);
+        }
+        state.achievements = achievements.list();
+        drop(state);
+
+        self.send(Command::LoadAchievements {
+            json: String::from(json),
+            unlocked,
+        });
+
+        Ok(())
+    }
+
+    pub fn achievements(&self) -> Vec<AchievementInfo> {
+        self.state.borrow().achievements.clone()
+    }
+
+    pub fn on_achievement(&self, callback: Option<js_sys::Function>) {
+        self.state.borrow_mut().on_achievement = callback;
+    }
+
+    // Checked on the page, so mistakes are thrown right away
+    pub fn load_splits(&self, json: &str) -> Result<(), JsValue> {
+        Chip8Splits::parse(json).map_err(|err| js_sys::Error::new(&err))?;
+        self.send(Command::LoadSplits {
+            json: String::from(json),
+        });
+
+        Ok(())
+    }
+
+    // Resolves to the splits with their times in this run
+    pub fn splits(&self) -> js_sys::Promise {
+        self.request(Command::Splits, None)
+    }
+
+    // Resolves to the split file with the best times, undefined without splits
+    pub fn splits_json(&self) -> js_sys::Promise {
+        self.request(Command::SplitsJson, None)
+    }
+
+    // Resolves to the frames since the start of the run
+    pub fn timer_frames(&self) -> js_sys::Promise {
+        self.request(Command::TimerFrames, None)
+    }
+
+    pub fn reset_split_timer(&self) {
+        self.send(Command::ResetSplitTimer);
+    }
+
+    pub fn on_split(&self, callback: Option<js_sys::Function>) {
+        self.state.borrow_mut().on_split = callback;
+    }
+
+    // Resolves to the number of candidates
+    pub fn start_search(&self) -> js_sys::Promise {
+        self.request(Command::StartSearch, None)
+    }
+
+    // Resolves to how many are left
+    pub fn narrow_search(&self, filter: String, value: Option<u8>) -> js_sys::Promise {
+        self.request(Command::NarrowSearch { filter, value }, None)
+    }
+
+    // Resolves to the addresses that are left with their values
+    pub fn search_results(&self, limit: Option<u32>) -> js_sys::Promise {
+        self.request(Command::SearchResults { limit }, None)
+    }
+
+    // Resolves to the PNG bytes
+    pub fn screenshot_png(&self, scale: u32) -> js_sys::Promise {
+        self.request(Command::ScreenshotPng { scale }, None)
+    }
+
+    // Resolves to an image/png Blob
+    pub fn screenshot_blob(&self, scale: u32) -> js_sys::Promise {
+        let png_bytes = JsFuture::from(self.screenshot_png(scale));
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            let png_bytes = js_sys::Uint8Array::new(&png_bytes.await?);
+            Ok(emulator::png_blob(&png_bytes.to_vec())?.into())
+        })
+    }
+
+    pub fn start_recording(
+        &self,
+        scale: Option<u32>,
+        frame_skip: Option<u32>,
+        max_seconds: Option<f64>,
+    ) -> js_sys::Promise {
+        self.request(
+            Command::StartRecording {
+                scale,
+                frame_skip,
+                max_seconds,
+            },
+            None,
+        )
+    }
+
+    // Resolves to the GIF bytes
+    pub fn stop_recording(&self) -> js_sys::Promise {
+        self.request(Command::StopRecording, None)
+    }
+}
+
+impl WorkerEmulator {
+    // Send `command` without waiting for an answer, failures end up in the console
+    fn send(&self, command: Command) {
+        let envelope = Envelope { id: None, command };
+        post_command(
+            &self.worker,
+            &self.state,
+            &envelope,
+            None,
+            js_sys::Array::new(),
+        );
+    }
+
+    // Send `command` and settle the promise with the worker's answer
+    fn request(&self, command: Command, payload: Option<JsValue>) -> js_sys::Promise {
+        if let Some(reason) = &self.state.borrow().dead {
+            return js_sys::Promise::reject(&js_sys::Error::new(reason));
+        }
+
+        let mut pending = None;
+        let promise = js_sys::Promise::new(&mut |resolve, reject| {
+            pending = Some(PendingReply { resolve, reject });
+        });
+
+        let id = {
+            let mut state = self.state.borrow_mut();
+            let id = state.next_id;
+            state.next_id = state.next_id.wrapping_add(1);
+            state
+                .pending
+                .insert(id, pending.expect("promises run their executor right away"));
+            id
+        };
+
+        let envelope = Envelope {
+            id: Some(id),
+            command,
+        };
+        post_command(
+            &self.worker,
+            &self.state,
+            &envelope,
+            payload,
+            js_sys::Array::new(),
+        );
+
+        promise
+    }
+}
+
+impl Drop for WorkerEmulator {
+    fn drop(&mut self) {
+        for (event_name, listener) in &self.key_listeners {
+            let _ = self
+                .key_target
+                .remove_event_listener_with_callback(event_name, listener.as_ref().unchecked_ref());
+        }
+        emulator::remove_listeners(&self.pause_listeners);
+
+        self.worker.set_onmessage(None);
+        self.worker.set_onerror(None);
+        self.worker.set_onmessageerror(None);
+        self.worker.terminate();
+
+        if let Some(audio) = &mut self.state.borrow_mut().audio {
+            audio.set_playing(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_json() {
+        let envelope = Envelope {
+            id: Some(3),
+            command: Command::SetPauseTrigger {
+                trigger: PauseTrigger::Hidden,
+                active: true,
+            },
+        };
+        let json = serde_json::to_string(&envelope).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"id":3,"command":"set_pause_trigger","trigger":"hidden","active":true}"#
+        );
+        assert_eq!(serde_json::from_str::<Envelope>(&json).unwrap(), envelope);
+
+        // Keys don't need an answer
+        let key: Envelope =
+            serde_json::from_str(r#"{"command":"key_down","code":"KeyW"}"#).unwrap();
+        assert_eq!(key.id, None);
+        assert_eq!(
+            key.command,
+            Command::KeyDown {
+                code: String::from("KeyW")
+            }
+        );
+
+        let load: Envelope = serde_json::from_str(
+            r#"{"id":4,"command":"load_achievements","json":"{}","unlocked":["level-5"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            load.command,
+            Command::LoadAchievements {
+                json: String::from("{}"),
+                unlocked: vec![String::from("level-5")]
+            }
+        );
+    }
+
+    #[test]
+    fn test_event_json() {
+        let done = Event::Done {
+            id: 1,
+            reply: Reply::Loaded {
+                sha1: Some(String::from("abc")),
+                rom_hash: String::from("abc"),
+            },
+        };
+        let json = serde_json::to_string(&done).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"event":"done","id":1,"reply":{"reply":"loaded","sha1":"abc","rom_hash":"abc"}}"#
+        );
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), done);
+        assert_eq!(
+            serde_json::from_str::<Event>(r#"{"event":"beep","playing":true}"#).unwrap(),
+            Event::Beep { playing: true }
+        );
+        assert_eq!(
+            serde_json::to_string(&Reply::Text { text: None }).unwrap(),
+            r#"{"reply":"text","text":null}"#
+        );
+    }
+}
@@ -0,0 +1,5 @@
+// Runs the emulator off the main thread, see `WorkerEmulator`
+import init, { run_emulator_worker } from "chip8_rust_wasm";
+
+await init();
+run_emulator_worker();